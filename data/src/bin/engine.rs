@@ -0,0 +1,52 @@
+#![allow(clippy::needless_return)]
+
+use std::io;
+use clap::Parser;
+
+use data::{engine::{ModelPredictor, RandomPredictor, UciEngine}, model::Transformer, sampler::{Sampler, SamplingConfig}, tokenizer::Tokenizer};
+
+/// UCI engine playing the moves of a chessGPT model, for chess GUIs and cutechess
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+
+    #[arg(long, help="Encoder json written by the builder, e.g. assets/vocab.json")]
+    vocab: String,
+
+    #[arg(long, help="Model weights exported by export.py, random legal moves are played without")]
+    model: Option<String>,
+
+    #[arg(long, default_value_t = 0.0, help="Sampling temperature, 0 always plays the most likely move")]
+    temperature: f32,
+
+    #[arg(long, default_value_t = 0, help="Only sample from the k most likely tokens, 0 keeps all of them")]
+    top_k: usize,
+
+    #[arg(long, default_value_t = 1.0, help="Only sample from the most likely tokens whose probabilities add up to this")]
+    top_p: f32,
+
+    #[arg(long, default_value_t = 0, help="Seed of the sampler / random moves")]
+    seed: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let tokenizer = Tokenizer::load(&args.vocab).expect("Unable to load the vocabulary");
+    let (input, output) = (io::stdin().lock(), io::stdout().lock());
+    let result = match &args.model {
+        Some(path) => {
+            let model = Transformer::load(path).expect("Unable to load the model");
+            if let Err(e) = model.check_tokenizer(&tokenizer) {
+                panic!("Model does not match the vocabulary: {}", e);
+            }
+            let config = SamplingConfig { temperature: args.temperature, top_k: args.top_k, top_p: args.top_p };
+            let sampler = Sampler::new(config, args.seed).expect("Invalid sampling options");
+            UciEngine::new(tokenizer, ModelPredictor::new(model, sampler)).run(input, output)
+        },
+        None => UciEngine::new(tokenizer, RandomPredictor::new(args.seed)).run(input, output),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+    }
+}
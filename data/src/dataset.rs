@@ -0,0 +1,284 @@
+use std::{fs, io::{Read, Seek, SeekFrom, Write}};
+
+use anyhow::{anyhow, Error};
+
+use crate::tokenizer::{Token, Tokenizer};
+
+/*
+
+Every file written by the builder starts with a fixed 32 byte header
+
+ 0..8   - magic bytes "CHESSGPT"
+ 8..10  - format version (u16, little endian)
+10      - file kind (0 - tokens, 1 - game index, 2 - legal token masks)
+11      - element width in bytes (2 for tokens, 8 for index offsets, 1 for masks)
+12      - endianness of the elements (0 - big, 1 - little)
+13      - reserved
+14..18  - vocab size of the tokenizer (u32, little endian)
+18..26  - tokenizer fingerprint (u64, little endian), see Tokenizer::fingerprint
+26..32  - reserved
+
+The header fields are always little endian, the elements that follow use the
+endianness recorded in the header. Index offsets are byte offsets into the
+token data, i.e. relative to the end of the token file header.
+
+A mask file holds one packed mask of Tokenizer::mask_size bytes per token of
+the matching token file, the legal values of that token given the ones before
+it (see Tokenizer::legal_next_tokens and Tokenizer::pack_mask). Tokens that are
+not part of a move or <end> get a mask with every bit set. The masks of a game
+start at (index offset / 2) * mask_size.
+
+*/
+
+pub const MAGIC: &[u8; 8] = b"CHESSGPT";
+pub const FORMAT_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    Tokens = 0,
+    Index = 1,
+    Mask = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endianness {
+    Big = 0,
+    Little = 1,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetHeader {
+    pub version: u16,
+    pub kind: FileKind,
+    pub width: u8,
+    pub endianness: Endianness,
+    pub vocab_size: u32,
+    pub fingerprint: u64,
+}
+
+impl DatasetHeader {
+    pub fn new(kind: FileKind, tokenizer: &Tokenizer) -> DatasetHeader {
+        return DatasetHeader {
+            version: FORMAT_VERSION,
+            kind,
+            width: match kind {
+                FileKind::Tokens => 2,
+                FileKind::Index => 8,
+                FileKind::Mask => 1,
+            },
+            endianness: Endianness::Big,
+            vocab_size: tokenizer.vocab_size() as u32,
+            fingerprint: tokenizer.fingerprint(),
+        };
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0..8].copy_from_slice(MAGIC);
+        bytes[8..10].copy_from_slice(&self.version.to_le_bytes());
+        bytes[10] = self.kind as u8;
+        bytes[11] = self.width;
+        bytes[12] = self.endianness as u8;
+        bytes[14..18].copy_from_slice(&self.vocab_size.to_le_bytes());
+        bytes[18..26].copy_from_slice(&self.fingerprint.to_le_bytes());
+        return bytes;
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DatasetHeader, Error> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..8] != MAGIC {
+            return Err(anyhow!("Missing dataset header"));
+        }
+
+        let version = u16::from_le_bytes([bytes[8], bytes[9]]);
+        if version != FORMAT_VERSION {
+            return Err(anyhow!("Unsupported dataset format version {}", version));
+        }
+
+        return Ok(DatasetHeader {
+            version,
+            kind: match bytes[10] {
+                0 => FileKind::Tokens,
+                1 => FileKind::Index,
+                2 => FileKind::Mask,
+                v => return Err(anyhow!("Invalid file kind {}", v)),
+            },
+            width: bytes[11],
+            endianness: match bytes[12] {
+                0 => Endianness::Big,
+                1 => Endianness::Little,
+                v => return Err(anyhow!("Invalid endianness {}", v)),
+            },
+            vocab_size: u32::from_le_bytes(bytes[14..18].try_into()?),
+            fingerprint: u64::from_le_bytes(bytes[18..26].try_into()?),
+        });
+    }
+
+    pub fn read<R: Read>(reader: &mut R) -> Result<DatasetHeader, Error> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        return DatasetHeader::from_bytes(&bytes);
+    }
+
+    /// Checks that the file was produced by `tokenizer` and holds the expected kind of data
+    pub fn validate(&self, kind: FileKind, tokenizer: &Tokenizer) -> Result<(), Error> {
+        let expected = DatasetHeader::new(kind, tokenizer);
+        if self.kind != expected.kind {
+            return Err(anyhow!("Expected a {:?} file, found a {:?} file", expected.kind, self.kind));
+        }
+        if self.width != expected.width || self.endianness != expected.endianness {
+            return Err(anyhow!("Unsupported element layout ({} bytes, {:?} endian)", self.width, self.endianness));
+        }
+        if self.vocab_size != expected.vocab_size {
+            return Err(anyhow!("Vocab size mismatch, dataset has {} tokens, tokenizer has {}", self.vocab_size, expected.vocab_size));
+        }
+        if self.fingerprint != expected.fingerprint {
+            return Err(anyhow!("Tokenizer fingerprint mismatch ({:016x} != {:016x})", self.fingerprint, expected.fingerprint));
+        }
+        return Ok(());
+    }
+}
+
+/*
+
+Opens a dataset file for appending. A new (or empty) file gets a fresh header,
+an existing file must carry a header matching `tokenizer`. Returns the file
+and the length of the data already stored after the header.
+
+*/
+pub fn open_for_append(path: &str, kind: FileKind, tokenizer: &Tokenizer) -> Result<(fs::File, u64), Error> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+
+    let len = file.metadata()?.len();
+    if len == 0 {
+        file.write_all(&DatasetHeader::new(kind, tokenizer).to_bytes())?;
+        return Ok((file, 0));
+    }
+
+    file.seek(SeekFrom::Start(0))?;
+    DatasetHeader::read(&mut file)?.validate(kind, tokenizer)?;
+    return Ok((file, len - HEADER_SIZE as u64));
+}
+
+/// Drops everything past `data_len` bytes of data, used to cut off the torn tail of an interrupted build
+pub fn truncate_data(file: &fs::File, data_len: u64) -> Result<(), Error> {
+    file.set_len(HEADER_SIZE as u64 + data_len)?;
+    return Ok(());
+}
+
+fn read_data(path: &str, kind: FileKind, tokenizer: &Tokenizer) -> Result<Vec<u8>, Error> {
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    DatasetHeader::read(&mut file)?.validate(kind, tokenizer)?;
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    return Ok(bytes);
+}
+
+pub fn read_tokens(path: &str, tokenizer: &Tokenizer) -> Result<Vec<Token>, Error> {
+    let bytes = read_data(path, FileKind::Tokens, tokenizer)?;
+    if !bytes.len().is_multiple_of(2) {
+        return Err(anyhow!("Token file {} is truncated", path));
+    }
+    return Ok(bytes.chunks_exact(2).map(|b| Token { value: u16::from_be_bytes([b[0], b[1]]) }).collect());
+}
+
+pub fn read_index(path: &str, tokenizer: &Tokenizer) -> Result<Vec<u64>, Error> {
+    let bytes = read_data(path, FileKind::Index, tokenizer)?;
+    if !bytes.len().is_multiple_of(8) {
+        return Err(anyhow!("Index file {} is truncated", path));
+    }
+    return Ok(bytes.chunks_exact(8).map(|b| u64::from_be_bytes(b.try_into().unwrap())).collect());
+}
+
+/// The packed masks of a mask file, one per token
+pub fn read_masks(path: &str, tokenizer: &Tokenizer) -> Result<Vec<Vec<u8>>, Error> {
+    let bytes = read_data(path, FileKind::Mask, tokenizer)?;
+    if !bytes.len().is_multiple_of(tokenizer.mask_size()) {
+        return Err(anyhow!("Mask file {} is truncated", path));
+    }
+    return Ok(bytes.chunks_exact(tokenizer.mask_size()).map(Vec::from).collect());
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        return tokenizer;
+    }
+
+    #[test]
+    fn header() {
+        let tokenizer = tokenizer();
+        let header = DatasetHeader::new(FileKind::Tokens, &tokenizer);
+        let bytes = header.to_bytes();
+        assert_eq!(&bytes[0..8], MAGIC);
+        assert_eq!(DatasetHeader::from_bytes(&bytes).unwrap(), header);
+        assert!(header.validate(FileKind::Tokens, &tokenizer).is_ok());
+        assert!(header.validate(FileKind::Index, &tokenizer).is_err());
+        assert!(header.validate(FileKind::Tokens, &Tokenizer::new()).is_err());
+        assert!(DatasetHeader::from_bytes(&[0u8; HEADER_SIZE]).is_err());
+    }
+
+    #[test]
+    fn append() {
+        let tokenizer = tokenizer();
+        let dir = TempDir::new("dataset").unwrap();
+        let tokens_path = dir.path().join("out.games");
+        let tokens_path = tokens_path.to_str().unwrap();
+        let index_path = dir.path().join("out.index");
+        let index_path = index_path.to_str().unwrap();
+
+        let (mut file, len) = open_for_append(tokens_path, FileKind::Tokens, &tokenizer).unwrap();
+        assert_eq!(len, 0);
+        file.write_all(&[0, 0, 0, 3]).unwrap();
+        let (mut index_file, _) = open_for_append(index_path, FileKind::Index, &tokenizer).unwrap();
+        index_file.write_all(&0u64.to_be_bytes()).unwrap();
+
+        let (mut file, len) = open_for_append(tokens_path, FileKind::Tokens, &tokenizer).unwrap();
+        assert_eq!(len, 4);
+        file.write_all(&[0, 2, 0, 3]).unwrap();
+        let (mut index_file, _) = open_for_append(index_path, FileKind::Index, &tokenizer).unwrap();
+        index_file.write_all(&len.to_be_bytes()).unwrap();
+
+        let tokens = read_tokens(tokens_path, &tokenizer).unwrap();
+        assert_eq!(tokens, [Token { value: 0 }, Token { value: 3 }, Token { value: 2 }, Token { value: 3 }]);
+        assert_eq!(read_index(index_path, &tokenizer).unwrap(), [0, 4]);
+        assert!(read_tokens(index_path, &tokenizer).is_err());
+        assert!(open_for_append(tokens_path, FileKind::Tokens, &Tokenizer::new()).is_err());
+
+        truncate_data(&file, 4).unwrap();
+        truncate_data(&index_file, 8).unwrap();
+        assert_eq!(read_tokens(tokens_path, &tokenizer).unwrap().len(), 2);
+        assert_eq!(read_index(index_path, &tokenizer).unwrap(), [0]);
+    }
+
+    #[test]
+    fn masks() {
+        let tokenizer = tokenizer();
+        let dir = TempDir::new("dataset").unwrap();
+        let mask_path = dir.path().join("out.masks");
+        let mask_path = mask_path.to_str().unwrap();
+
+        let (mut file, _) = open_for_append(mask_path, FileKind::Mask, &tokenizer).unwrap();
+        let mask = tokenizer.pack_mask(&[Token { value: 0 }, Token { value: 9 }]);
+        file.write_all(&mask).unwrap();
+        file.write_all(&vec![0xff; tokenizer.mask_size()]).unwrap();
+
+        let masks = read_masks(mask_path, &tokenizer).unwrap();
+        assert_eq!(masks.len(), 2);
+        assert_eq!(masks[0][..2], [1, 2]);
+        assert!(read_tokens(mask_path, &tokenizer).is_err());
+        file.write_all(&[0]).unwrap();
+        assert!(read_masks(mask_path, &tokenizer).is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use anyhow::{anyhow, Error};
+use shakmaty::{san::SanPlus, uci::Uci, Color, Move, Outcome};
+
+use crate::{dataset, tokenizer::{EloBucket, Token, Tokenizer}, variant::GameVariant};
+
+/*
+
+A decoded game is rebuilt by replaying every move token on a shakmaty board, so
+anything returned from here is guaranteed to be a legal move sequence.
+
+A game without a trailing <end> token (e.g. a model sample that was cut off) is
+still decoded, `complete` is set to false in that case. A game starting with
+<start:UNKNOWN> has no outcome and the result "*".
+
+Elo tokens following the start token are reported as the rating buckets of the
+players (None when absent or unknown) and written as WhiteEloBucket /
+BlackEloBucket tags, the ratings themselves are not known. A setup prefix sets
+the start position of the game, written as SetUp / FEN tags. A variant start
+token selects the rules the moves are replayed with, written as a Variant tag.
+
+*/
+
+pub struct DecodedGame {
+    pub variant: GameVariant,
+    pub outcome: Option<Outcome>,
+    pub moves: Vec<Move>,
+    pub san: Vec<SanPlus>,
+    pub complete: bool,
+    pub white_elo: Option<EloBucket>,
+    pub black_elo: Option<EloBucket>,
+    pub fen: Option<String>,
+}
+
+impl DecodedGame {
+    pub fn uci(&self) -> Vec<Uci> {
+        return self.moves.iter().map(|m| m.to_uci(self.variant.castling_mode())).collect();
+    }
+
+    pub fn result(&self) -> &'static str {
+        return match self.outcome {
+            Some(Outcome::Decisive { winner: Color::White }) => "1-0",
+            Some(Outcome::Decisive { winner: Color::Black }) => "0-1",
+            Some(Outcome::Draw) => "1/2-1/2",
+            None => "*",
+        };
+    }
+
+    pub fn pgn(&self) -> String {
+        let mut pgn = String::new();
+        for (tag, value) in [("Event", "?"), ("Site", "?"), ("Date", "????.??.??"), ("Round", "?"), ("White", "?"), ("Black", "?"), ("Result", self.result())] {
+            pgn.push_str(&format!("[{} \"{}\"]\n", tag, value));
+        }
+        if self.variant != GameVariant::Standard {
+            pgn.push_str(&format!("[Variant \"{}\"]\n", self.variant.tag()));
+        }
+        if let Some(fen) = &self.fen {
+            pgn.push_str(&format!("[SetUp \"1\"]\n[FEN \"{}\"]\n", fen));
+        }
+        for (tag, bucket) in [("WhiteEloBucket", self.white_elo), ("BlackEloBucket", self.black_elo)] {
+            if let Some(bucket) = bucket {
+                pgn.push_str(&format!("[{} \"{}\"]\n", tag, bucket));
+            }
+        }
+        pgn.push('\n');
+
+        // Games set up with black to move start with "1... "
+        let offset = match &self.fen {
+            Some(fen) if fen.split(' ').nth(1) == Some("b") => 1,
+            _ => 0,
+        };
+        let mut movetext: Vec<String> = Vec::new();
+        for (ply, san) in self.san.iter().enumerate() {
+            if ply == 0 && offset == 1 {
+                movetext.push(format!("1... {}", san));
+            } else if (ply + offset) % 2 == 0 {
+                movetext.push(format!("{}. {}", (ply + offset) / 2 + 1, san));
+            } else {
+                movetext.push(san.to_string());
+            }
+        }
+        movetext.push(String::from(self.result()));
+
+        let mut line_length = 0;
+        for (i, item) in movetext.iter().enumerate() {
+            if i > 0 {
+                if line_length + 1 + item.len() > 79 {
+                    pgn.push('\n');
+                    line_length = 0;
+                } else {
+                    pgn.push(' ');
+                    line_length += 1;
+                }
+            }
+            pgn.push_str(item);
+            line_length += item.len();
+        }
+        pgn.push('\n');
+
+        return pgn;
+    }
+}
+
+pub fn tokens_from_bytes(bytes: &[u8]) -> Result<Vec<Token>, Error> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(anyhow!("Token stream has an odd number of bytes ({})", bytes.len()));
+    }
+
+    return Ok(bytes.chunks_exact(2).map(|b| Token { value: u16::from_be_bytes([b[0], b[1]]) }).collect());
+}
+
+/// Reads the tokens of a builder output file, the header must match `tokenizer`
+pub fn read_tokens_from_file(path: &str, tokenizer: &Tokenizer) -> Result<Vec<Token>, Error> {
+    return dataset::read_tokens(path, tokenizer);
+}
+
+pub fn decode_game(tokens: &[Token], tokenizer: &Tokenizer) -> Result<DecodedGame, Error> {
+    let (start, mut rest) = match tokens.split_first() {
+        Some(v) => v,
+        None => return Err(anyhow!("Empty token sequence")),
+    };
+
+    let outcome = tokenizer.token_to_game_outcome(start)?;
+    let variant = tokenizer.token_to_variant(start)?;
+    let mut pos = variant.start_position(None)?;
+    let mut game = DecodedGame { variant, outcome, moves: Vec::new(), san: Vec::new(), complete: false, white_elo: None, black_elo: None, fen: None };
+
+    while let Some((token, tail)) = rest.split_first() {
+        if !tokenizer.is_elo_token(token) {
+            break;
+        }
+        match tokenizer.token_to_elo(token)? {
+            (Color::White, bucket) => game.white_elo = bucket,
+            (Color::Black, bucket) => game.black_elo = bucket,
+        }
+        rest = tail;
+    }
+
+    if let Some((setup, fen, n_tokens)) = tokenizer.decode_setup(rest, variant)? {
+        pos = setup;
+        game.fen = Some(fen);
+        rest = &rest[n_tokens..];
+    }
+
+    let mut i = 0;
+    while i < rest.len() {
+        if tokenizer.is_game_end_token(&rest[i]) {
+            if i + 1 != rest.len() {
+                return Err(anyhow!("Unexpected tokens after <end>"));
+            }
+            game.complete = true;
+            break;
+        }
+
+        let (_move, n_tokens) = match tokenizer.decode_move(&pos, &rest[i..]) {
+            Ok(v) => v,
+            Err(e) => return Err(anyhow!("Invalid move at ply {}: {}", game.moves.len() + 1, e)),
+        };
+        game.san.push(SanPlus::from_move_and_play_unchecked(&mut pos, &_move));
+        game.moves.push(_move);
+        i += n_tokens;
+    }
+
+    return Ok(game);
+}
+
+pub fn decode_games(tokens: &[Token], tokenizer: &Tokenizer) -> Result<Vec<DecodedGame>, Error> {
+    let mut games = Vec::new();
+    let mut game_start: Option<usize> = None;
+
+    for (i, token) in tokens.iter().enumerate() {
+        if tokenizer.is_game_start_token(token) {
+            if let Some(start) = game_start {
+                games.push(decode_game(&tokens[start..i], tokenizer)?);
+            }
+            game_start = Some(i);
+        } else if game_start.is_none() {
+            return Err(anyhow!("Token stream does not begin with a game start token"));
+        }
+    }
+
+    if let Some(start) = game_start {
+        games.push(decode_game(&tokens[start..], tokenizer)?);
+    }
+
+    return Ok(games);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use std::io::Write;
+
+    use shakmaty::Position;
+    use tempdir::TempDir;
+
+    use crate::{dataset::FileKind, scheme::SchemeKind};
+
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        return tokenizer;
+    }
+
+    fn encode(tokenizer: &Tokenizer, outcome: Option<Outcome>, moves: &[&str], end: bool) -> Vec<Token> {
+        let mut tokens = vec![tokenizer.game_start_token(outcome).unwrap()];
+        for uci in moves {
+            tokens.push(tokenizer.uci_to_token(Uci::from_str(uci).unwrap()).unwrap());
+        }
+        if end {
+            tokens.push(tokenizer.game_end_token());
+        }
+        return tokens;
+    }
+
+    #[test]
+    fn decode_game() {
+        let tokenizer = tokenizer();
+        let tokens = encode(&tokenizer, Some(Outcome::Decisive { winner: Color::White }), &["e2e4", "e7e5", "f1c4", "b8c6", "d1h5", "g8f6", "h5f7"], true);
+
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert!(game.complete);
+        assert_eq!(game.result(), "1-0");
+        assert_eq!(game.uci().iter().map(|uci| uci.to_string()).collect::<Vec<String>>(), ["e2e4", "e7e5", "f1c4", "b8c6", "d1h5", "g8f6", "h5f7"]);
+        assert_eq!(game.san.iter().map(|san| san.to_string()).collect::<Vec<String>>(), ["e4", "e5", "Bc4", "Nc6", "Qh5", "Nf6", "Qxf7#"]);
+        assert!(game.pgn().ends_with("\n\n1. e4 e5 2. Bc4 Nc6 3. Qh5 Nf6 4. Qxf7# 1-0\n"));
+        assert!(game.pgn().contains("[Result \"1-0\"]"));
+    }
+
+    #[test]
+    fn decode_factored_game() {
+        let mut tokenizer = Tokenizer::with_scheme(SchemeKind::Factored.create());
+        tokenizer.create_token_map();
+
+        let mut pos = GameVariant::Standard.start_position(None).unwrap();
+        let mut tokens = vec![tokenizer.game_start_token(Some(Outcome::Draw)).unwrap()];
+        for uci in ["e2e4", "e7e5", "g1f3"] {
+            let _move = Uci::from_str(uci).unwrap().to_move(&pos).unwrap();
+            tokens.extend(tokenizer.encode_move(&pos, &_move).unwrap());
+            pos.play_unchecked(&_move);
+        }
+        assert_eq!(tokens.len(), 7);
+
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert!(!game.complete);
+        assert_eq!(game.san.iter().map(|san| san.to_string()).collect::<Vec<String>>(), ["e4", "e5", "Nf3"]);
+        assert!(super::decode_game(&tokens[..6], &tokenizer).is_err());
+    }
+
+    #[test]
+    fn decode_elo_tokens() {
+        let mut tokenizer = tokenizer();
+        tokenizer.set_elo_buckets(&[1800, 2000]).unwrap();
+        let mut tokens = encode(&tokenizer, Some(Outcome::Draw), &["e2e4", "e7e5"], true);
+        tokens.insert(1, tokenizer.elo_token(Color::White, Some(1900)).unwrap());
+        tokens.insert(2, tokenizer.elo_token(Color::Black, None).unwrap());
+
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert_eq!(game.moves.len(), 2);
+        assert_eq!(game.white_elo, Some(EloBucket { min: 1800, max: Some(2000) }));
+        assert_eq!(game.black_elo, None);
+        assert!(game.pgn().contains("[WhiteEloBucket \"1800-2000\"]\n\n"));
+    }
+
+    #[test]
+    fn decode_setup() {
+        let mut tokenizer = tokenizer();
+        tokenizer.set_setup_tokens(true);
+        let start = GameVariant::Standard.start_position(Some("4k3/8/8/8/8/8/4P3/4K3 b - - 0 1")).unwrap();
+        let mut tokens = encode(&tokenizer, Some(Outcome::Draw), &["e8d7", "e2e4", "d7d6"], true);
+        for (i, token) in tokenizer.setup_tokens(&start).unwrap().into_iter().enumerate() {
+            tokens.insert(1 + i, token);
+        }
+
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert_eq!(game.fen.as_deref(), Some("4k3/8/8/8/8/8/4P3/4K3 b - - 0 1"));
+        assert!(game.pgn().contains("[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 1\"]\n"));
+        assert!(game.pgn().ends_with("\n\n1... Kd7 2. e4 Kd6 1/2-1/2\n"));
+        assert!(super::decode_game(&encode(&tokenizer, Some(Outcome::Draw), &["e8d7"], true), &tokenizer).is_err());
+    }
+
+    #[test]
+    fn decode_variant_game() {
+        let mut tokenizer = tokenizer();
+        tokenizer.set_setup_tokens(true);
+        tokenizer.set_variants(&[GameVariant::Chess960, GameVariant::Crazyhouse]);
+
+        let start = GameVariant::Chess960.start_position(Some("rk2r3/8/8/8/8/8/8/RK2R3 w KQkq - 0 1")).unwrap();
+        let mut tokens = vec![tokenizer.variant_start_token(GameVariant::Chess960, Some(Outcome::Draw)).unwrap()];
+        tokens.extend(tokenizer.setup_tokens(&start).unwrap());
+        for uci in ["b1a1", "b8a8"] {
+            tokens.push(tokenizer.uci_to_token(Uci::from_str(uci).unwrap()).unwrap());
+        }
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert_eq!(game.variant, GameVariant::Chess960);
+        assert_eq!(game.san.iter().map(|san| san.to_string()).collect::<Vec<String>>(), ["O-O-O", "O-O-O"]);
+        assert_eq!(game.uci().iter().map(|uci| uci.to_string()).collect::<Vec<String>>(), ["b1a1", "b8a8"]);
+        assert!(game.pgn().contains("[Variant \"Chess960\"]\n[SetUp \"1\"]\n"));
+
+        let tokens = [
+            vec![tokenizer.variant_start_token(GameVariant::Crazyhouse, Some(Outcome::Decisive { winner: Color::White })).unwrap()],
+            ["e2e4", "d7d5", "e4d5", "d8d5", "P@e4"].iter().map(|uci| tokenizer.uci_to_token(Uci::from_str(uci).unwrap()).unwrap()).collect(),
+            vec![tokenizer.game_end_token()],
+        ].concat();
+        let game = super::decode_game(&tokens, &tokenizer).unwrap();
+        assert_eq!(game.variant, GameVariant::Crazyhouse);
+        assert_eq!(game.fen, None);
+        assert!(game.pgn().ends_with("\n\n1. e4 d5 2. exd5 Qxd5 3. @e4 1-0\n"));
+    }
+
+    #[test]
+    fn decode_illegal_game() {
+        let tokenizer = tokenizer();
+        let tokens = encode(&tokenizer, Some(Outcome::Draw), &["e2e4", "e2e4"], true);
+        assert!(super::decode_game(&tokens, &tokenizer).is_err());
+    }
+
+    #[test]
+    fn decode_games() {
+        let mut tokenizer = tokenizer();
+        tokenizer.set_unknown_outcome_token(true);
+        let mut tokens = encode(&tokenizer, Some(Outcome::Draw), &["e2e4", "e7e5"], true);
+        tokens.extend(encode(&tokenizer, None, &["g1f3"], true));
+        tokens.extend(encode(&tokenizer, Some(Outcome::Decisive { winner: Color::Black }), &["d2d4"], false));
+
+        let mut bytes: Vec<u8> = Vec::new();
+        for tok in &tokens {
+            bytes.extend(tok.value.to_be_bytes());
+        }
+
+        let games = super::decode_games(&tokens_from_bytes(&bytes).unwrap(), &tokenizer).unwrap();
+        assert_eq!(games.len(), 3);
+        assert_eq!(games[0].result(), "1/2-1/2");
+        assert!(games[0].complete);
+        assert_eq!(games[1].outcome, None);
+        assert_eq!(games[1].result(), "*");
+        assert!(games[1].pgn().ends_with("1. Nf3 *\n"));
+        assert_eq!(games[2].result(), "0-1");
+        assert!(!games[2].complete);
+        assert!(tokens_from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn read_tokens_from_file() {
+        let tokenizer = tokenizer();
+        let dir = TempDir::new("decoder").unwrap();
+        let path = dir.path().join("out.games");
+        let path = path.to_str().unwrap();
+
+        let tokens = encode(&tokenizer, Some(Outcome::Draw), &["e2e4", "e7e5"], true);
+        let (mut file, _) = dataset::open_for_append(path, FileKind::Tokens, &tokenizer).unwrap();
+        for tok in &tokens {
+            file.write_all(&tok.value.to_be_bytes()).unwrap();
+        }
+
+        let read = super::read_tokens_from_file(path, &tokenizer).unwrap();
+        assert_eq!(read, tokens);
+        let games = super::decode_games(&read, &tokenizer).unwrap();
+        assert_eq!(games.len(), 1);
+        assert_eq!(games[0].result(), "1/2-1/2");
+        assert!(super::read_tokens_from_file(path, &Tokenizer::new()).is_err());
+    }
+}
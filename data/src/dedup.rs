@@ -0,0 +1,434 @@
+use std::{cmp::Reverse, collections::{BinaryHeap, HashSet}, fs, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::Mutex};
+
+use anyhow::{anyhow, Error};
+use tempdir::TempDir;
+
+use crate::manifest::write_atomically;
+
+/*
+
+Duplicate detection for the builder. Every written game is identified by a
+128 bit fingerprint, the first half of the sha256 of its tokens (2 bytes big
+endian per token), so a game costs 16 bytes instead of a 64 byte hex string.
+
+The fingerprints live in a DedupStore, split into shards by their top bits
+with one lock per shard, so workers only contend when they hit the same shard.
+With a memory limit, a shard that grows past its share of the limit is sorted
+and spilled to a run file in the spill directory. Lookups then also binary
+search the runs of the shard, through a sparse in-memory index that narrows a
+lookup down to a single block read per run. Runs are merged once a shard has
+MAX_RUNS of them. Removed fingerprints that were already spilled are kept as
+tombstones until the next merge.
+
+Fingerprints are persisted in an append-only dedup file:
+
+"CGPTDDP1" magic
+fingerprints, 16 bytes little endian each
+
+The builder appends the fingerprints of a source once it is completely written
+and records the resulting length in the build manifest, a longer file is cut
+back to it on --resume.
+
+*/
+
+pub type Fingerprint = u128;
+
+const MAGIC: &[u8; 8] = b"CGPTDDP1";
+const FINGERPRINT_SIZE: u64 = 16;
+// Fingerprints per block of a run, the sparse index keeps the first key of every block
+const RUN_BLOCK: usize = 256;
+const MAX_RUNS: usize = 8;
+
+pub fn fingerprint(bytes: &[u8]) -> Fingerprint {
+    let digest = sha256::digest(bytes);
+    return Fingerprint::from_str_radix(&digest[..32], 16).expect("sha256 digest is not hex");
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupConfig {
+    pub shards: usize,
+    /// Fingerprints kept in memory before spilling to disk, None keeps everything in memory
+    pub memory_limit: Option<usize>,
+    /// Directory of the spilled runs, a temporary directory by default
+    pub spill_dir: Option<String>,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        return DedupConfig { shards: 64, memory_limit: None, spill_dir: None };
+    }
+}
+
+// A sorted run of spilled fingerprints, deleted with the store
+struct Run {
+    path: PathBuf,
+    file: fs::File,
+    len: usize,
+    sparse: Vec<Fingerprint>,
+}
+
+impl Run {
+    fn write(path: PathBuf, fingerprints: impl Iterator<Item = Result<Fingerprint, Error>>) -> Result<Run, Error> {
+        let mut writer = BufWriter::new(fs::File::create(&path)?);
+        let mut len = 0;
+        let mut sparse = Vec::new();
+        for fingerprint in fingerprints {
+            let fingerprint = fingerprint?;
+            if len % RUN_BLOCK == 0 {
+                sparse.push(fingerprint);
+            }
+            writer.write_all(&fingerprint.to_le_bytes())?;
+            len += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+        return Ok(Run { file: fs::File::open(&path)?, path, len, sparse });
+    }
+
+    fn contains(&mut self, fingerprint: Fingerprint) -> Result<bool, Error> {
+        let block = match self.sparse.partition_point(|first| *first <= fingerprint) {
+            0 => return Ok(false),
+            n => n - 1,
+        };
+        let start = block * RUN_BLOCK;
+        let count = RUN_BLOCK.min(self.len - start);
+        let mut bytes = vec![0u8; count * FINGERPRINT_SIZE as usize];
+        self.file.seek(SeekFrom::Start(start as u64 * FINGERPRINT_SIZE))?;
+        self.file.read_exact(&mut bytes)?;
+        let keys: Vec<Fingerprint> = bytes.chunks_exact(FINGERPRINT_SIZE as usize).map(|b| Fingerprint::from_le_bytes(b.try_into().unwrap())).collect();
+        return Ok(keys.binary_search(&fingerprint).is_ok());
+    }
+
+    fn reader(&self) -> Result<impl Iterator<Item = Result<Fingerprint, Error>>, Error> {
+        let mut reader = BufReader::new(fs::File::open(&self.path)?);
+        return Ok((0..self.len).map(move |_| {
+            let mut bytes = [0u8; FINGERPRINT_SIZE as usize];
+            reader.read_exact(&mut bytes)?;
+            return Ok(Fingerprint::from_le_bytes(bytes));
+        }));
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    memory: HashSet<Fingerprint>,
+    runs: Vec<Run>,
+    // Spilled fingerprints that were removed since
+    removed: HashSet<Fingerprint>,
+    len: usize,
+    next_run: usize,
+}
+
+pub struct DedupStore {
+    shards: Vec<Mutex<Shard>>,
+    shard_memory_limit: Option<usize>,
+    spill_dir: PathBuf,
+    // Removed on drop, when no spill directory was given
+    _tmp_dir: Option<TempDir>,
+}
+
+impl DedupStore {
+    pub fn new(config: &DedupConfig) -> Result<DedupStore, Error> {
+        if !config.shards.is_power_of_two() {
+            return Err(anyhow!("The number of dedup shards must be a power of two, got {}", config.shards));
+        }
+        let (spill_dir, tmp_dir) = match (&config.memory_limit, &config.spill_dir) {
+            (Some(_), Some(dir)) => {
+                fs::create_dir_all(dir)?;
+                (PathBuf::from(dir), None)
+            },
+            (Some(_), None) => {
+                let tmp_dir = TempDir::new("dedup")?;
+                (tmp_dir.path().to_path_buf(), Some(tmp_dir))
+            },
+            (None, _) => (PathBuf::new(), None),
+        };
+        return Ok(DedupStore {
+            shards: (0..config.shards).map(|_| Mutex::new(Shard::default())).collect(),
+            shard_memory_limit: config.memory_limit.map(|limit| (limit / config.shards).max(1)),
+            spill_dir,
+            _tmp_dir: tmp_dir,
+        });
+    }
+
+    fn shard(&self, fingerprint: Fingerprint) -> usize {
+        // Top bits, the bottom ones pick the HashSet buckets. A single shard would shift by 128
+        return fingerprint.checked_shr(128 - self.shards.len().trailing_zeros()).unwrap_or(0) as usize & (self.shards.len() - 1);
+    }
+
+    fn lock(&self, idx: usize) -> Result<std::sync::MutexGuard<'_, Shard>, Error> {
+        return self.shards[idx].lock().map_err(|e| anyhow!("Unable to lock dedup shard {}: {}", idx, e));
+    }
+
+    fn spilled_contains(shard: &mut Shard, fingerprint: Fingerprint) -> Result<bool, Error> {
+        if shard.removed.contains(&fingerprint) {
+            return Ok(false);
+        }
+        for run in shard.runs.iter_mut() {
+            if run.contains(fingerprint)? {
+                return Ok(true);
+            }
+        }
+        return Ok(false);
+    }
+
+    /// Adds `fingerprint`, returns false when it was already present
+    pub fn insert(&self, fingerprint: Fingerprint) -> Result<bool, Error> {
+        let idx = self.shard(fingerprint);
+        let mut shard = self.lock(idx)?;
+        if shard.memory.contains(&fingerprint) || DedupStore::spilled_contains(&mut shard, fingerprint)? {
+            return Ok(false);
+        }
+        // A removed spilled fingerprint comes back by dropping its tombstone
+        if !shard.removed.remove(&fingerprint) {
+            shard.memory.insert(fingerprint);
+        }
+        shard.len += 1;
+
+        if self.shard_memory_limit.is_some_and(|limit| shard.memory.len() >= limit) {
+            self.spill(idx, &mut shard)?;
+        }
+        return Ok(true);
+    }
+
+    pub fn contains(&self, fingerprint: Fingerprint) -> Result<bool, Error> {
+        let mut shard = self.lock(self.shard(fingerprint))?;
+        return Ok(shard.memory.contains(&fingerprint) || DedupStore::spilled_contains(&mut shard, fingerprint)?);
+    }
+
+    /// Removes `fingerprint`, used to roll back the games of a failed source
+    pub fn remove(&self, fingerprint: Fingerprint) -> Result<(), Error> {
+        let mut shard = self.lock(self.shard(fingerprint))?;
+        if shard.memory.remove(&fingerprint) {
+            shard.len -= 1;
+        } else if DedupStore::spilled_contains(&mut shard, fingerprint)? {
+            shard.removed.insert(fingerprint);
+            shard.len -= 1;
+        }
+        return Ok(());
+    }
+
+    pub fn len(&self) -> usize {
+        return self.shards.iter().map(|shard| shard.lock().map(|shard| shard.len).unwrap_or(0)).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    /// Number of run files currently spilled to disk
+    pub fn spilled_runs(&self) -> usize {
+        return self.shards.iter().map(|shard| shard.lock().map(|shard| shard.runs.len()).unwrap_or(0)).sum();
+    }
+
+    fn spill(&self, idx: usize, shard: &mut Shard) -> Result<(), Error> {
+        let mut fingerprints: Vec<Fingerprint> = shard.memory.drain().collect();
+        fingerprints.sort_unstable();
+        let path = self.spill_dir.join(format!("dedup-{}-{}.run", idx, shard.next_run));
+        shard.next_run += 1;
+        shard.runs.push(Run::write(path, fingerprints.into_iter().map(Ok))?);
+
+        if shard.runs.len() >= MAX_RUNS {
+            // k-way merge of the sorted runs, dropping the tombstones
+            let mut readers = Vec::new();
+            for run in &shard.runs {
+                readers.push(run.reader()?);
+            }
+            let mut heap = BinaryHeap::new();
+            for (i, reader) in readers.iter_mut().enumerate() {
+                if let Some(fingerprint) = reader.next() {
+                    heap.push(Reverse((fingerprint?, i)));
+                }
+            }
+            let removed = std::mem::take(&mut shard.removed);
+            let merged = std::iter::from_fn(|| {
+                while let Some(Reverse((fingerprint, i))) = heap.pop() {
+                    match readers[i].next() {
+                        Some(Ok(next)) => heap.push(Reverse((next, i))),
+                        Some(Err(e)) => return Some(Err(e)),
+                        None => (),
+                    }
+                    if !removed.contains(&fingerprint) {
+                        return Some(Ok(fingerprint));
+                    }
+                }
+                return None;
+            });
+            let path = self.spill_dir.join(format!("dedup-{}-{}.run", idx, shard.next_run));
+            shard.next_run += 1;
+            let merged = Run::write(path, merged)?;
+            shard.runs = Vec::from([merged]);
+        }
+        return Ok(());
+    }
+
+    /// Adds the first `limit` (all by default) fingerprints of a dedup file, e.g. the one of an earlier dataset.
+    /// A missing file adds nothing. Returns the number of fingerprints read
+    pub fn load(&self, path: &str, limit: Option<u64>) -> Result<u64, Error> {
+        let mut count = 0;
+        read_dedup_file(path, limit, |fingerprint| {
+            self.insert(fingerprint)?;
+            count += 1;
+            return Ok(());
+        })?;
+        return Ok(count);
+    }
+}
+
+fn check_magic(file: &mut fs::File, path: &str) -> Result<(), Error> {
+    let mut magic = [0u8; 8];
+    if file.read_exact(&mut magic).is_err() || magic != *MAGIC {
+        return Err(anyhow!("{} is not a dedup file", path));
+    }
+    return Ok(());
+}
+
+// Calls `f` with the first `limit` fingerprints of a dedup file
+fn read_dedup_file(path: &str, limit: Option<u64>, mut f: impl FnMut(Fingerprint) -> Result<(), Error>) -> Result<(), Error> {
+    if !Path::new(path).exists() {
+        return Ok(());
+    }
+    let mut file = fs::File::open(path)?;
+    check_magic(&mut file, path)?;
+    let mut reader = BufReader::new(file);
+    let mut bytes = [0u8; FINGERPRINT_SIZE as usize];
+    let mut count = 0;
+    while count < limit.unwrap_or(u64::MAX) {
+        match reader.read_exact(&mut bytes) {
+            Ok(_) => f(Fingerprint::from_le_bytes(bytes))?,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        count += 1;
+    }
+    return Ok(());
+}
+
+/// Append-only writer of a dedup file
+pub struct DedupLog {
+    file: fs::File,
+    len: u64,
+}
+
+impl DedupLog {
+    /// Opens the dedup file at `path`, keeping its first `length` fingerprints (all when None).
+    /// A missing file is created
+    pub fn open(path: &str, length: Option<u64>) -> Result<DedupLog, Error> {
+        if !Path::new(path).exists() {
+            write_atomically(path, MAGIC)?;
+        }
+
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        check_magic(&mut file, path)?;
+        let available = (file.metadata()?.len() - MAGIC.len() as u64) / FINGERPRINT_SIZE;
+        let len = match length {
+            Some(length) if length > available => return Err(anyhow!("{} holds {} fingerprints, expected at least {}", path, available, length)),
+            Some(length) => length,
+            None => available,
+        };
+        // Drops a torn tail, and anything written after the last committed source
+        file.set_len(MAGIC.len() as u64 + len * FINGERPRINT_SIZE)?;
+
+        return Ok(DedupLog { file, len });
+    }
+
+    pub fn append(&mut self, fingerprints: &[Fingerprint]) -> Result<(), Error> {
+        let bytes: Vec<u8> = fingerprints.iter().flat_map(|fingerprint| fingerprint.to_le_bytes()).collect();
+        self.file.seek(SeekFrom::Start(MAGIC.len() as u64 + self.len * FINGERPRINT_SIZE))?;
+        self.file.write_all(&bytes)?;
+        self.file.sync_data()?;
+        self.len += fingerprints.len() as u64;
+        return Ok(());
+    }
+
+    /// Number of fingerprints in the file
+    pub fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprints(n: usize) -> Vec<Fingerprint> {
+        return (0..n).map(|i| fingerprint(&i.to_be_bytes())).collect();
+    }
+
+    #[test]
+    fn store() {
+        let dir = TempDir::new("dedup").unwrap();
+        for config in [
+            DedupConfig::default(),
+            DedupConfig { shards: 1, ..Default::default() },
+            DedupConfig { shards: 4, memory_limit: Some(40), spill_dir: Some(dir.path().to_str().unwrap().to_string()) },
+        ] {
+            let store = DedupStore::new(&config).unwrap();
+            let values = fingerprints(1000);
+            for value in &values {
+                assert!(store.insert(*value).unwrap());
+            }
+            for value in &values {
+                assert!(!store.insert(*value).unwrap());
+            }
+            assert_eq!(store.len(), 1000);
+            assert_eq!(config.memory_limit.is_some(), store.spilled_runs() > 0);
+
+            // Removing spilled and in memory fingerprints, the spilled ones survive a merge as tombstones
+            for value in &values[..500] {
+                store.remove(*value).unwrap();
+            }
+            assert_eq!(store.len(), 500);
+            for value in fingerprints(2000).iter().skip(1000) {
+                store.insert(*value).unwrap();
+            }
+            assert!(!store.contains(values[0]).unwrap());
+            assert!(store.contains(values[999]).unwrap());
+            assert!(store.insert(values[0]).unwrap());
+            assert_eq!(store.len(), 1501);
+        }
+        // Run files are deleted with the store
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        assert!(DedupStore::new(&DedupConfig { shards: 3, ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn dedup_file() {
+        let dir = TempDir::new("dedup").unwrap();
+        let path = dir.path().join("out.dedup");
+        let path = path.to_str().unwrap();
+        let values = fingerprints(10);
+
+        let mut log = DedupLog::open(path, None).unwrap();
+        assert!(log.is_empty());
+        log.append(&values[..6]).unwrap();
+        log.append(&values[6..]).unwrap();
+        drop(log);
+
+        let store = DedupStore::new(&DedupConfig::default()).unwrap();
+        assert_eq!(store.load(path, None).unwrap(), 10);
+        assert_eq!(store.load("missing.dedup", None).unwrap(), 0);
+
+        // Resuming cuts the file back to the committed length
+        let log = DedupLog::open(path, Some(6)).unwrap();
+        assert_eq!(log.len(), 6);
+        assert!(DedupLog::open(path, Some(7)).is_err());
+        let store = DedupStore::new(&DedupConfig::default()).unwrap();
+        assert_eq!(store.load(path, None).unwrap(), 6);
+        assert!(store.contains(values[5]).unwrap() && !store.contains(values[6]).unwrap());
+
+        fs::write(path, "not a dedup file\n").unwrap();
+        assert!(DedupLog::open(path, None).is_err());
+        assert!(store.load(path, None).is_err());
+    }
+}
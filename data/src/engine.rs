@@ -0,0 +1,402 @@
+use std::io::{BufRead, Write};
+
+use anyhow::{anyhow, Error};
+use shakmaty::{variant::VariantPosition, Color, Move, Outcome, Position};
+
+use crate::{model::{KvCache, Transformer}, sampler::{Rng, Sampler}, tokenizer::{Token, Tokenizer}, variant::{play_uci_moves, GameVariant}};
+
+/*
+
+UCI protocol front-end, see src/bin/engine.rs. The position given by the GUI is
+turned into the tokens of a game as written by the builder:
+
+<start> [elo_w elo_b] [<setup> fen <setup_end>] moves
+
+and the move to play is left to a MovePredictor. The start token claims the
+outcome set by the PlayFor option (by default a win of the side to move), Elo
+tokens use the Elo option for both players (0 is an unknown rating).
+
+Supported commands: uci, isready, ucinewgame, setoption, position, go, stop
+and quit. Moves are chosen as soon as go is received, the bestmove of
+"go infinite" / "go ponder" is held back until stop. Errors are reported as
+"info string Error: ..." and the command is ignored.
+
+*/
+
+pub trait MovePredictor {
+    /// The move to play in `pos`, the last position of the game written by `tokens`
+    fn predict(&mut self, tokenizer: &Tokenizer, pos: &VariantPosition, tokens: &[Token]) -> Result<Move, Error>;
+}
+
+/// Plays a uniformly random legal move, for testing GUIs and tournament setups
+pub struct RandomPredictor {
+    rng: Rng,
+}
+
+impl RandomPredictor {
+    pub fn new(seed: u64) -> RandomPredictor {
+        return RandomPredictor { rng: Rng::new(seed) };
+    }
+}
+
+impl MovePredictor for RandomPredictor {
+    fn predict(&mut self, tokenizer: &Tokenizer, pos: &VariantPosition, _tokens: &[Token]) -> Result<Move, Error> {
+        let moves: Vec<Move> = pos.legal_moves().into_iter().filter(|_move| tokenizer.encode_move(pos, _move).is_ok()).collect();
+        if moves.is_empty() {
+            return Err(anyhow!("No legal moves"));
+        }
+        return Ok(moves[(self.rng.next_u64() % moves.len() as u64) as usize].clone());
+    }
+}
+
+/// Samples the move from a Transformer, one token at a time, keeping its KV cache between moves
+pub struct ModelPredictor {
+    model: Transformer,
+    sampler: Sampler,
+    cache: KvCache,
+}
+
+impl ModelPredictor {
+    pub fn new(model: Transformer, sampler: Sampler) -> ModelPredictor {
+        let cache = model.new_cache();
+        return ModelPredictor { model, sampler, cache };
+    }
+}
+
+impl MovePredictor for ModelPredictor {
+    fn predict(&mut self, tokenizer: &Tokenizer, pos: &VariantPosition, tokens: &[Token]) -> Result<Move, Error> {
+        let encodings: Vec<(Move, Vec<Token>)> = pos.legal_moves().into_iter().filter_map(|_move| {
+            tokenizer.encode_move(pos, &_move).ok().map(|move_tokens| (_move, move_tokens))
+        }).collect();
+        let mut context = tokens.to_vec();
+        let mut prefix: Vec<Token> = Vec::new();
+        loop {
+            let logits = self.model.next_logits(&mut self.cache, &context)?;
+            // The engine has to move, <end> is not an option
+            let legal: Vec<Token> = tokenizer.legal_next_tokens(pos, &prefix).into_iter().filter(|token| !tokenizer.is_game_end_token(token)).collect();
+            let sampled = self.sampler.sample_among(tokenizer, &legal, &logits)?;
+            prefix.push(sampled.token);
+            context.push(sampled.token);
+
+            if let Some((_move, _)) = encodings.iter().find(|(_, move_tokens)| *move_tokens == prefix) {
+                return Ok(_move.clone());
+            }
+            // legal_next_tokens and encode_move disagree, sampling on would only grow the context
+            if !encodings.iter().any(|(_, move_tokens)| move_tokens.starts_with(&prefix)) {
+                let values: Vec<u16> = prefix.iter().map(|token| token.value).collect();
+                return Err(anyhow!("Sampled tokens {:?} are not the start of a legal move", values));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayFor {
+    Win,
+    Draw,
+    Loss,
+    Unknown,
+}
+
+impl PlayFor {
+    fn name(&self) -> &'static str {
+        return match self {
+            PlayFor::Win => "win",
+            PlayFor::Draw => "draw",
+            PlayFor::Loss => "loss",
+            PlayFor::Unknown => "unknown",
+        };
+    }
+
+    fn outcome(&self, turn: Color) -> Option<Outcome> {
+        return match self {
+            PlayFor::Win => Some(Outcome::Decisive { winner: turn }),
+            PlayFor::Draw => Some(Outcome::Draw),
+            PlayFor::Loss => Some(Outcome::Decisive { winner: !turn }),
+            PlayFor::Unknown => None,
+        };
+    }
+}
+
+pub struct UciEngine<P: MovePredictor> {
+    tokenizer: Tokenizer,
+    predictor: P,
+    variant: GameVariant,
+    play_for: PlayFor,
+    elo: Option<u32>,
+    start: VariantPosition,
+    pos: VariantPosition,
+    moves: Vec<Move>,
+    // bestmove of a "go infinite", sent on stop
+    pending: Option<String>,
+}
+
+impl<P: MovePredictor> UciEngine<P> {
+    pub fn new(tokenizer: Tokenizer, predictor: P) -> UciEngine<P> {
+        let start = GameVariant::Standard.start_position(None).unwrap();
+        return UciEngine {
+            tokenizer,
+            predictor,
+            variant: GameVariant::Standard,
+            play_for: PlayFor::Win,
+            elo: None,
+            pos: start.clone(),
+            start,
+            moves: Vec::new(),
+            pending: None,
+        };
+    }
+
+    /// Reads commands until quit or the end of `input`
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> Result<(), Error> {
+        for line in input.lines() {
+            let line = line?;
+            let keep_going = match self.handle(&line, &mut output) {
+                Ok(keep_going) => keep_going,
+                Err(e) => {
+                    writeln!(output, "info string Error: {}", e)?;
+                    true
+                },
+            };
+            output.flush()?;
+            if !keep_going {
+                break;
+            }
+        }
+        return Ok(());
+    }
+
+    /// Handles one command, returns false on quit
+    pub fn handle(&mut self, line: &str, output: &mut impl Write) -> Result<bool, Error> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.first() {
+            Some(&"uci") => self.write_id(output)?,
+            Some(&"isready") => writeln!(output, "readyok")?,
+            Some(&"ucinewgame") => self.set_position(&["startpos"])?,
+            Some(&"setoption") => self.set_option(&args[1..])?,
+            Some(&"position") => self.set_position(&args[1..])?,
+            Some(&"go") => {
+                let bestmove = format!("bestmove {}", self.best_move()?);
+                match args.contains(&"infinite") || args.contains(&"ponder") {
+                    true => self.pending = Some(bestmove),
+                    false => writeln!(output, "{}", bestmove)?,
+                }
+            },
+            Some(&"stop") | Some(&"ponderhit") => {
+                if let Some(bestmove) = self.pending.take() {
+                    writeln!(output, "{}", bestmove)?;
+                }
+            },
+            Some(&"quit") => return Ok(false),
+            // Unknown commands are ignored, as the protocol asks
+            _ => (),
+        }
+        return Ok(true);
+    }
+
+    fn write_id(&self, output: &mut impl Write) -> Result<(), Error> {
+        writeln!(output, "id name chessGPT")?;
+        writeln!(output, "id author chessGPT")?;
+        let mut play_for = vec![PlayFor::Win, PlayFor::Draw, PlayFor::Loss];
+        if self.tokenizer.has_unknown_outcome_token() {
+            play_for.push(PlayFor::Unknown);
+        }
+        let vars: Vec<String> = play_for.iter().map(|p| format!("var {}", p.name())).collect();
+        writeln!(output, "option name PlayFor type combo default win {}", vars.join(" "))?;
+        if !self.tokenizer.elo_buckets().is_empty() {
+            writeln!(output, "option name Elo type spin default 0 min 0 max 4000")?;
+        }
+        if self.tokenizer.supports_variant(GameVariant::Chess960) {
+            writeln!(output, "option name UCI_Chess960 type check default false")?;
+        }
+        let variants: Vec<&GameVariant> = self.tokenizer.variants().iter().filter(|v| **v != GameVariant::Chess960).collect();
+        if !variants.is_empty() {
+            let vars: Vec<String> = variants.iter().map(|v| format!("var {}", v.name())).collect();
+            writeln!(output, "option name UCI_Variant type combo default chess var chess {}", vars.join(" "))?;
+        }
+        writeln!(output, "uciok")?;
+        return Ok(());
+    }
+
+    fn set_option(&mut self, args: &[&str]) -> Result<(), Error> {
+        let value_at = args.iter().position(|arg| *arg == "value").unwrap_or(args.len());
+        if args.first() != Some(&"name") {
+            return Err(anyhow!("Expected setoption name <id> [value <x>]"));
+        }
+        let name = args[1..value_at].join(" ").to_lowercase();
+        let value = args.get(value_at + 1..).unwrap_or(&[]).join(" ");
+
+        match name.as_str() {
+            "playfor" => {
+                self.play_for = match value.to_lowercase().as_str() {
+                    "win" => PlayFor::Win,
+                    "draw" => PlayFor::Draw,
+                    "loss" => PlayFor::Loss,
+                    "unknown" if self.tokenizer.has_unknown_outcome_token() => PlayFor::Unknown,
+                    _ => return Err(anyhow!("Unsupported PlayFor value {}", value)),
+                };
+            },
+            "elo" => {
+                self.elo = match value.parse::<u32>() {
+                    Ok(0) => None,
+                    Ok(elo) => Some(elo),
+                    Err(_) => return Err(anyhow!("Invalid Elo {}", value)),
+                };
+            },
+            "uci_chess960" => {
+                let variant = match value.as_str() {
+                    "true" => GameVariant::Chess960,
+                    _ => GameVariant::Standard,
+                };
+                self.set_variant(variant)?;
+            },
+            "uci_variant" => self.set_variant(GameVariant::from_tag(Some(&value))?)?,
+            _ => return Err(anyhow!("Unknown option {}", name)),
+        }
+        return Ok(());
+    }
+
+    fn set_variant(&mut self, variant: GameVariant) -> Result<(), Error> {
+        if !self.tokenizer.supports_variant(variant) {
+            return Err(anyhow!("Variant {} is not enabled in the vocabulary", variant.name()));
+        }
+        self.variant = variant;
+        return self.set_position(&["startpos"]);
+    }
+
+    // position [startpos | fen <fen>] [moves <move>...]
+    fn set_position(&mut self, args: &[&str]) -> Result<(), Error> {
+        let moves_at = args.iter().position(|arg| *arg == "moves").unwrap_or(args.len());
+        let start = match args.first() {
+            Some(&"startpos") => self.variant.start_position(None)?,
+            Some(&"fen") => self.variant.start_position(Some(&args[1..moves_at].join(" ")))?,
+            _ => return Err(anyhow!("Expected position startpos or position fen <fen>")),
+        };
+
+        let mut pos = start.clone();
+        let moves = play_uci_moves(&mut pos, args.get(moves_at + 1..).unwrap_or(&[]).iter().copied())?;
+
+        self.start = start;
+        self.pos = pos;
+        self.moves = moves;
+        self.pending = None;
+        return Ok(());
+    }
+
+    /// The tokens of the game up to the current position
+    pub fn tokens(&self) -> Result<Vec<Token>, Error> {
+        let outcome = self.play_for.outcome(self.pos.turn());
+        return self.tokenizer.game_tokens(&self.start, &self.moves, outcome, self.elo, self.elo);
+    }
+
+    fn best_move(&mut self) -> Result<String, Error> {
+        // "0000" is the null move, sent when the game is over
+        if self.pos.legal_moves().is_empty() {
+            return Ok("0000".to_string());
+        }
+        let tokens = self.tokens()?;
+        let _move = self.predictor.predict(&self.tokenizer, &self.pos, &tokens)?;
+        return Ok(_move.to_uci(self.pos.castles().mode()).to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use shakmaty::uci::Uci;
+
+    use crate::{model::{tests::{random_state_dict, serialize}, ModelConfig}, sampler::SamplingConfig};
+
+    use super::*;
+
+    fn run<P: MovePredictor>(engine: &mut UciEngine<P>, input: &str) -> Vec<String> {
+        let mut output = Vec::new();
+        engine.run(input.as_bytes(), &mut output).unwrap();
+        return String::from_utf8(output).unwrap().lines().map(|line| line.to_string()).collect();
+    }
+
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        return tokenizer;
+    }
+
+    // Records the tokens it is given and plays the first legal move
+    struct Recorder {
+        tokens: Vec<Vec<Token>>,
+    }
+
+    impl MovePredictor for Recorder {
+        fn predict(&mut self, _tokenizer: &Tokenizer, pos: &VariantPosition, tokens: &[Token]) -> Result<Move, Error> {
+            self.tokens.push(tokens.to_vec());
+            return Ok(pos.legal_moves()[0].clone());
+        }
+    }
+
+    #[test]
+    fn protocol() {
+        let mut engine = UciEngine::new(tokenizer(), RandomPredictor::new(0));
+        let output = run(&mut engine, "uci\nisready\nposition startpos moves e2e4 e7e5\ngo movetime 100\nposition startpos moves e2e5\ngo infinite\nstop\nquit\nisready\n");
+        assert_eq!(output[0], "id name chessGPT");
+        assert!(output.contains(&"uciok".to_string()));
+        assert!(output.contains(&"readyok".to_string()));
+
+        let bestmoves: Vec<&String> = output.iter().filter(|line| line.starts_with("bestmove")).collect();
+        assert_eq!(bestmoves.len(), 2);
+        let mut pos = GameVariant::Standard.start_position(None).unwrap();
+        for uci in ["e2e4", "e7e5", bestmoves[0].strip_prefix("bestmove ").unwrap()] {
+            let _move = Uci::from_str(uci).unwrap().to_move(&pos).unwrap();
+            pos.play_unchecked(&_move);
+        }
+        assert!(output.iter().any(|line| line.starts_with("info string Error: Illegal move e2e5")));
+        // The position is kept and nothing is read after quit
+        assert_eq!(output.iter().filter(|line| *line == "readyok").count(), 1);
+
+        let output = run(&mut engine, "position fen 7k/6Q1/6K1/8/8/8/8/8 b - - 0 1\ngo\n");
+        assert_eq!(output, vec!["bestmove 0000"]);
+    }
+
+    #[test]
+    fn tokens() {
+        let tokenizer = || {
+            let mut tokenizer = tokenizer();
+            tokenizer.set_setup_tokens(true);
+            tokenizer.set_elo_buckets(&[1500]).unwrap();
+            return tokenizer;
+        };
+        let mut engine = UciEngine::new(tokenizer(), Recorder { tokens: Vec::new() });
+        let tokenizer = tokenizer();
+        run(&mut engine, "position startpos moves e2e4\ngo\nsetoption name PlayFor value draw\nsetoption name Elo value 1600\nposition fen 4k3/8/8/8/8/8/4P3/4K3 w - - 0 1\ngo\n");
+
+        let e2e4 = tokenizer.uci_to_token(Uci::from_str("e2e4").unwrap()).unwrap();
+        let start = tokenizer.game_start_token(Some(Outcome::Decisive { winner: Color::Black })).unwrap();
+        let unknown_elo = [tokenizer.elo_token(Color::White, None).unwrap(), tokenizer.elo_token(Color::Black, None).unwrap()];
+        assert_eq!(engine.predictor.tokens[0], [&[start][..], &unknown_elo, &[e2e4]].concat());
+
+        let setup = engine.predictor.tokens[1].clone();
+        assert_eq!(setup[0], tokenizer.game_start_token(Some(Outcome::Draw)).unwrap());
+        assert_eq!(setup[1], tokenizer.elo_token(Color::White, Some(1600)).unwrap());
+        let (pos, _, used) = tokenizer.decode_setup(&setup[3..], GameVariant::Standard).unwrap().unwrap();
+        assert_eq!(used, setup.len() - 3);
+        assert_eq!(pos.board().occupied().count(), 3);
+
+        // Variants need to be in the vocabulary
+        let output = run(&mut engine, "setoption name UCI_Variant value crazyhouse\n");
+        assert!(output[0].starts_with("info string Error: Variant crazyhouse is not enabled"));
+    }
+
+    #[test]
+    fn model_predictor() {
+        let tokenizer = tokenizer();
+        let config = ModelConfig { block_size: 8, n_vocab: tokenizer.vocab_size(), n_embd: 8, n_heads: 2, n_blocks: 1 };
+        let model = Transformer::from_bytes(&serialize(&random_state_dict(&config))).unwrap();
+        let sampler = Sampler::new(SamplingConfig::default(), 0).unwrap();
+        let mut engine = UciEngine::new(tokenizer, ModelPredictor::new(model, sampler));
+
+        // Past the block size the context is cut to its last tokens
+        let output = run(&mut engine, "position startpos moves g1f3 g8f6 f3g1 f6g8 g1f3 g8f6 f3g1 f6g8\ngo\n");
+        let uci = output[0].strip_prefix("bestmove ").unwrap();
+        assert!(Uci::from_str(uci).unwrap().to_move(&GameVariant::Standard.start_position(None).unwrap()).is_ok());
+    }
+}
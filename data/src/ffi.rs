@@ -0,0 +1,270 @@
+use std::{cell::RefCell, ffi::{c_char, CStr, CString}, ptr, slice, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use shakmaty::{variant::VariantPosition, Outcome};
+
+use crate::{sampler::{Sampler, SamplingConfig}, tokenizer::{Token, Tokenizer}, variant::{play_uci_moves, GameVariant}};
+
+/*
+
+C interface of the move sampler, built into the cdylib (libdata.so) and used
+from Python through ctypes, see sampler.py in the repository root.
+
+A sampler is created from the encoder json written by the builder and freed
+with chess_sampler_free. Positions are passed as an optional FEN (NULL for the
+start position of the variant) plus the space separated uci moves played since.
+chess_sampler_encode turns such a game into the token ids the model was
+trained on, with the same vocabulary the moves are sampled from. Functions
+return -1 on error, chess_sampler_last_error then describes it. When
+chess_sampler_new returns NULL, chess_sampler_last_error(NULL) describes why.
+
+*/
+
+pub struct FfiSampler {
+    tokenizer: Tokenizer,
+    sampler: Sampler,
+    last_error: CString,
+}
+
+thread_local! {
+    // The error of the last failed chess_sampler_new on this thread
+    static NEW_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn error_string(error: &Error) -> CString {
+    return CString::new(error.to_string().replace('\0', " ")).unwrap_or_default();
+}
+
+impl FfiSampler {
+    fn set_error(&mut self, error: &Error) {
+        self.last_error = error_string(error);
+    }
+}
+
+unsafe fn optional_str<'a>(value: *const c_char) -> Result<Option<&'a str>, Error> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    return match CStr::from_ptr(value).to_str() {
+        Ok(value) => Ok(Some(value)),
+        Err(e) => Err(anyhow!("Invalid utf-8 string: {}", e)),
+    };
+}
+
+// The position reached by playing `moves` from `fen` (or the start position of the variant)
+pub fn position_after(variant: GameVariant, fen: Option<&str>, moves: &str) -> Result<VariantPosition, Error> {
+    let mut pos = variant.start_position(fen)?;
+    play_uci_moves(&mut pos, moves.split_whitespace())?;
+    return Ok(pos);
+}
+
+// The tokens of the game `moves` played from `fen`, as written by the builder without <end>
+pub fn encode_game(tokenizer: &Tokenizer, variant: GameVariant, fen: Option<&str>, moves: &str, outcome: Option<Outcome>, white_elo: Option<u32>, black_elo: Option<u32>) -> Result<Vec<Token>, Error> {
+    let start = variant.start_position(fen)?;
+    let moves = play_uci_moves(&mut start.clone(), moves.split_whitespace())?;
+    return tokenizer.game_tokens(&start, &moves, outcome, white_elo, black_elo);
+}
+
+/// Creates a sampler from an encoder json, returns NULL when it can not be loaded, see chess_sampler_last_error
+///
+/// # Safety
+///
+/// `vocab_path` must be a valid NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn chess_sampler_new(vocab_path: *const c_char, seed: u64, temperature: f32, top_k: usize, top_p: f32) -> *mut FfiSampler {
+    let result = optional_str(vocab_path).and_then(|vocab_path| {
+        let tokenizer = Tokenizer::load(vocab_path.ok_or(anyhow!("Missing vocabulary path"))?)?;
+        let sampler = Sampler::new(SamplingConfig { temperature, top_k, top_p }, seed)?;
+        return Ok(FfiSampler { tokenizer, sampler, last_error: CString::default() });
+    });
+    return match result {
+        Ok(sampler) => Box::into_raw(Box::new(sampler)),
+        Err(e) => {
+            NEW_ERROR.with(|error| *error.borrow_mut() = error_string(&e));
+            ptr::null_mut()
+        },
+    };
+}
+
+/// # Safety
+///
+/// `sampler` must come from chess_sampler_new (or be NULL) and must not be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn chess_sampler_free(sampler: *mut FfiSampler) {
+    if !sampler.is_null() {
+        drop(Box::from_raw(sampler));
+    }
+}
+
+/// Number of logits the sampler expects
+///
+/// # Safety
+///
+/// `sampler` must come from chess_sampler_new
+#[no_mangle]
+pub unsafe extern "C" fn chess_sampler_vocab_size(sampler: *const FfiSampler) -> usize {
+    return (*sampler).tokenizer.vocab_size();
+}
+
+/// Description of the last error, valid until the next call with this sampler. With a NULL `sampler` it
+/// describes the last failed chess_sampler_new of the calling thread, valid until its next call
+///
+/// # Safety
+///
+/// `sampler` must come from chess_sampler_new or be NULL
+#[no_mangle]
+pub unsafe extern "C" fn chess_sampler_last_error(sampler: *const FfiSampler) -> *const c_char {
+    if sampler.is_null() {
+        return NEW_ERROR.with(|error| error.borrow().as_ptr());
+    }
+    return (*sampler).last_error.as_ptr();
+}
+
+/// Samples a legal move from `n_logits` logits. Writes the uci move (NUL terminated) to `uci_out` and its
+/// probability to `probability_out`, returns the token id of the move or -1 on error.
+/// `variant`, `fen` and `moves` may be NULL
+///
+/// # Safety
+///
+/// `sampler` must come from chess_sampler_new, the strings must be NUL terminated, `logits` must hold
+/// `n_logits` values, `uci_out` must have room for `uci_out_len` bytes and `probability_out` must be writable
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn chess_sampler_sample_move(
+    sampler: *mut FfiSampler,
+    variant: *const c_char,
+    fen: *const c_char,
+    moves: *const c_char,
+    logits: *const f32,
+    n_logits: usize,
+    uci_out: *mut c_char,
+    uci_out_len: usize,
+    probability_out: *mut f32,
+) -> i32 {
+    let sampler = &mut *sampler;
+    let result = (|| {
+        let variant = GameVariant::from_tag(optional_str(variant)?)?;
+        let pos = position_after(variant, optional_str(fen)?, optional_str(moves)?.unwrap_or(""))?;
+        let logits = slice::from_raw_parts(logits, n_logits);
+        let sampled = sampler.sampler.sample_move(&sampler.tokenizer, &pos, logits)?;
+
+        let uci = sampled.uci.to_string();
+        if uci.len() + 1 > uci_out_len {
+            return Err(anyhow!("Output buffer of {} bytes is too small for {}", uci_out_len, uci));
+        }
+        ptr::copy_nonoverlapping(uci.as_ptr() as *const c_char, uci_out, uci.len());
+        *uci_out.add(uci.len()) = 0;
+        *probability_out = sampled.probability;
+        return Ok(sampled.token.value as i32);
+    })();
+
+    return match result {
+        Ok(token) => token,
+        Err(e) => {
+            sampler.set_error(&e);
+            -1
+        },
+    };
+}
+
+/// Encodes a game into token ids: the start token claiming `result` ("1-0", "0-1", "1/2-1/2", "*" or NULL
+/// for an unknown outcome), the Elo tokens of `white_elo` and `black_elo` (0 is an unknown rating, ignored
+/// without Elo buckets in the vocabulary), the setup of `fen` and `moves`. Writes at most `tokens_out_len` ids to
+/// `tokens_out` and returns the number of tokens of the game, call again with a larger buffer when it
+/// is larger than `tokens_out_len`. Returns -1 on error. `variant`, `fen`, `moves` and `result` may be NULL
+///
+/// # Safety
+///
+/// `sampler` must come from chess_sampler_new, the strings must be NUL terminated and `tokens_out` must
+/// have room for `tokens_out_len` values
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn chess_sampler_encode(
+    sampler: *mut FfiSampler,
+    variant: *const c_char,
+    fen: *const c_char,
+    moves: *const c_char,
+    result: *const c_char,
+    white_elo: u32,
+    black_elo: u32,
+    tokens_out: *mut u16,
+    tokens_out_len: usize,
+) -> i32 {
+    let sampler = &mut *sampler;
+    let encoded = (|| {
+        let variant = GameVariant::from_tag(optional_str(variant)?)?;
+        let outcome = match optional_str(result)? {
+            None | Some("*") => None,
+            Some(result) => match Outcome::from_str(result) {
+                Ok(outcome) => Some(outcome),
+                Err(_) => return Err(anyhow!("Invalid result {}", result)),
+            },
+        };
+        let (white_elo, black_elo) = (Some(white_elo).filter(|elo| *elo > 0), Some(black_elo).filter(|elo| *elo > 0));
+        return encode_game(&sampler.tokenizer, variant, optional_str(fen)?, optional_str(moves)?.unwrap_or(""), outcome, white_elo, black_elo);
+    })();
+
+    return match encoded {
+        Ok(tokens) => {
+            for (i, token) in tokens.iter().take(tokens_out_len).enumerate() {
+                *tokens_out.add(i) = token.value;
+            }
+            tokens.len() as i32
+        },
+        Err(e) => {
+            sampler.set_error(&e);
+            -1
+        },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use shakmaty::uci::Uci;
+
+    use super::*;
+
+    #[test]
+    fn sample_move() {
+        let path = CString::new("assets/vocab.json").unwrap();
+        unsafe {
+            let sampler = chess_sampler_new(path.as_ptr(), 0, 0.0, 0, 1.0);
+            assert!(!sampler.is_null());
+            let vocab_size = chess_sampler_vocab_size(sampler);
+            let mut logits = vec![0.0f32; vocab_size];
+            let e7e5 = (*sampler).tokenizer.uci_to_token(Uci::from_str("e7e5").unwrap()).unwrap();
+            logits[e7e5.value as usize] = 5.0;
+
+            let moves = CString::new("e2e4").unwrap();
+            let mut uci = [0 as c_char; 8];
+            let mut probability = 0.0f32;
+            let token = chess_sampler_sample_move(sampler, ptr::null(), ptr::null(), moves.as_ptr(), logits.as_ptr(), logits.len(), uci.as_mut_ptr(), uci.len(), &mut probability);
+            assert_eq!(token, e7e5.value as i32);
+            assert_eq!(CStr::from_ptr(uci.as_ptr()).to_str().unwrap(), "e7e5");
+            assert_eq!(probability, 1.0);
+
+            let moves = CString::new("e2e5").unwrap();
+            assert_eq!(chess_sampler_sample_move(sampler, ptr::null(), ptr::null(), moves.as_ptr(), logits.as_ptr(), logits.len(), uci.as_mut_ptr(), uci.len(), &mut probability), -1);
+            assert!(CStr::from_ptr(chess_sampler_last_error(sampler)).to_str().unwrap().starts_with("Illegal move e2e5"));
+
+            // The tokens of "1-0 e2e4 e7e5", the sampled e7e5 included
+            let (moves, result) = (CString::new("e2e4 e7e5").unwrap(), CString::new("1-0").unwrap());
+            let mut tokens = [0u16; 2];
+            let n_tokens = chess_sampler_encode(sampler, ptr::null(), ptr::null(), moves.as_ptr(), result.as_ptr(), 0, 0, tokens.as_mut_ptr(), tokens.len());
+            assert_eq!(n_tokens, 3);
+            let mut tokens = vec![0u16; n_tokens as usize];
+            assert_eq!(chess_sampler_encode(sampler, ptr::null(), ptr::null(), moves.as_ptr(), result.as_ptr(), 0, 0, tokens.as_mut_ptr(), tokens.len()), 3);
+            let tokenizer = &(*sampler).tokenizer;
+            assert_eq!(tokens[0], tokenizer.game_start_token(Some(Outcome::Decisive { winner: shakmaty::Color::White })).unwrap().value);
+            assert_eq!(tokens[2], e7e5.value);
+
+            let result = CString::new("2-0").unwrap();
+            assert_eq!(chess_sampler_encode(sampler, ptr::null(), ptr::null(), moves.as_ptr(), result.as_ptr(), 0, 0, tokens.as_mut_ptr(), tokens.len()), -1);
+            chess_sampler_free(sampler);
+
+            let path = CString::new("assets/missing.json").unwrap();
+            assert!(chess_sampler_new(path.as_ptr(), 0, 0.0, 0, 1.0).is_null());
+            assert!(!CStr::from_ptr(chess_sampler_last_error(ptr::null())).to_str().unwrap().is_empty());
+        }
+    }
+}
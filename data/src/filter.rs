@@ -0,0 +1,288 @@
+use std::{fmt, fs};
+
+use anyhow::{anyhow, Error};
+use regex::Regex;
+use shakmaty::Color;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::GameMetadata;
+
+/*
+
+Game level filters over the PGN header tags. Every option is optional, a game
+is kept when it passes all the configured ones. Games that lack a tag needed by
+a configured filter (e.g. no WhiteElo with --min-elo) are rejected.
+
+Time controls are classified like lichess does, by the estimated game duration
+of base + 40 * increment seconds.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeControlClass {
+    #[value(name = "ultrabullet")]
+    UltraBullet,
+    Bullet,
+    Blitz,
+    Rapid,
+    Classical,
+    Correspondence,
+}
+
+impl TimeControlClass {
+    pub fn from_header(time_control: &str) -> Option<TimeControlClass> {
+        let time_control = time_control.trim();
+        if time_control == "-" {
+            return Some(TimeControlClass::Correspondence);
+        }
+        // Multi period controls such as "40/7200:3600" are only used for classical games
+        if time_control.contains('/') || time_control.contains(':') {
+            return Some(TimeControlClass::Classical);
+        }
+
+        let (base, increment) = match time_control.split_once('+') {
+            Some((base, increment)) => (base.parse::<u64>().ok()?, increment.parse::<u64>().ok()?),
+            None => (time_control.parse::<u64>().ok()?, 0),
+        };
+        return Some(match base + 40 * increment {
+            0..=29 => TimeControlClass::UltraBullet,
+            30..=179 => TimeControlClass::Bullet,
+            180..=479 => TimeControlClass::Blitz,
+            480..=1499 => TimeControlClass::Rapid,
+            _ => TimeControlClass::Classical,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterReason {
+    MissingElo,
+    MinElo,
+    MaxElo,
+    AverageElo,
+    TimeControl,
+    Date,
+    Event,
+    Termination,
+    Variant,
+}
+
+impl fmt::Display for FilterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f.write_str(match self {
+            FilterReason::MissingElo => "missing_elo",
+            FilterReason::MinElo => "min_elo",
+            FilterReason::MaxElo => "max_elo",
+            FilterReason::AverageElo => "average_elo",
+            FilterReason::TimeControl => "time_control",
+            FilterReason::Date => "date",
+            FilterReason::Event => "event",
+            FilterReason::Termination => "termination",
+            FilterReason::Variant => "variant",
+        });
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    pub min_elo: Option<u32>,
+    pub max_elo: Option<u32>,
+    pub min_average_elo: Option<u32>,
+    pub max_average_elo: Option<u32>,
+    pub time_controls: Option<Vec<TimeControlClass>>,
+    pub min_date: Option<String>,
+    pub max_date: Option<String>,
+    pub event_regex: Option<String>,
+    pub terminations: Option<Vec<String>>,
+    pub variants: Option<Vec<String>>,
+}
+
+impl FilterConfig {
+    pub fn load(path: &str) -> Result<FilterConfig, Error> {
+        return Ok(serde_json::from_str(&fs::read_to_string(path)?)?);
+    }
+}
+
+pub struct GameFilter {
+    config: FilterConfig,
+    min_date: Option<(u32, u32, u32)>,
+    max_date: Option<(u32, u32, u32)>,
+    event_regex: Option<Regex>,
+}
+
+// "YYYY.MM.DD", unknown month / day ("??") count as the first of the year / month
+fn parse_date(date: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = date.trim().split(['.', '-', '/']);
+    let year = parts.next()?.parse::<u32>().ok()?;
+    let month = parts.next().and_then(|v| v.parse::<u32>().ok()).unwrap_or(1);
+    let day = parts.next().and_then(|v| v.parse::<u32>().ok()).unwrap_or(1);
+    return Some((year, month, day));
+}
+
+impl GameFilter {
+    pub fn new(config: FilterConfig) -> Result<GameFilter, Error> {
+        let min_date = match &config.min_date {
+            Some(date) => Some(parse_date(date).ok_or(anyhow!("Invalid min date \"{}\"", date))?),
+            None => None,
+        };
+        let max_date = match &config.max_date {
+            Some(date) => Some(parse_date(date).ok_or(anyhow!("Invalid max date \"{}\"", date))?),
+            None => None,
+        };
+        let event_regex = match &config.event_regex {
+            Some(event_regex) => Some(Regex::new(event_regex)?),
+            None => None,
+        };
+        return Ok(GameFilter { config, min_date, max_date, event_regex });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let config = &self.config;
+        return config.min_elo.is_none() && config.max_elo.is_none() && config.min_average_elo.is_none() && config.max_average_elo.is_none()
+            && config.time_controls.is_none() && self.min_date.is_none() && self.max_date.is_none() && self.event_regex.is_none()
+            && config.terminations.is_none() && config.variants.is_none();
+    }
+
+    pub fn check(&self, headers: &GameMetadata) -> Result<(), FilterReason> {
+        let config = &self.config;
+
+        if config.min_elo.is_some() || config.max_elo.is_some() || config.min_average_elo.is_some() || config.max_average_elo.is_some() {
+            let (white_elo, black_elo) = match (headers.elo(Color::White), headers.elo(Color::Black)) {
+                (Some(white_elo), Some(black_elo)) => (white_elo, black_elo),
+                _ => return Err(FilterReason::MissingElo),
+            };
+            if config.min_elo.is_some_and(|min_elo| white_elo < min_elo || black_elo < min_elo) {
+                return Err(FilterReason::MinElo);
+            }
+            if config.max_elo.is_some_and(|max_elo| white_elo > max_elo || black_elo > max_elo) {
+                return Err(FilterReason::MaxElo);
+            }
+            let average_elo = (white_elo + black_elo) / 2;
+            if config.min_average_elo.is_some_and(|min| average_elo < min) || config.max_average_elo.is_some_and(|max| average_elo > max) {
+                return Err(FilterReason::AverageElo);
+            }
+        }
+
+        if let Some(time_controls) = &config.time_controls {
+            match headers.get("TimeControl").and_then(TimeControlClass::from_header) {
+                Some(class) if time_controls.contains(&class) => {},
+                _ => return Err(FilterReason::TimeControl),
+            }
+        }
+
+        if self.min_date.is_some() || self.max_date.is_some() {
+            let date = headers.get("UTCDate").or(headers.get("Date")).and_then(parse_date);
+            match date {
+                Some(date) if self.min_date.is_none_or(|min| date >= min) && self.max_date.is_none_or(|max| date <= max) => {},
+                _ => return Err(FilterReason::Date),
+            }
+        }
+
+        if let Some(event_regex) = &self.event_regex {
+            if !headers.get("Event").is_some_and(|event| event_regex.is_match(event)) {
+                return Err(FilterReason::Event);
+            }
+        }
+
+        if let Some(terminations) = &config.terminations {
+            if !headers.get("Termination").is_some_and(|termination| terminations.iter().any(|t| t.eq_ignore_ascii_case(termination))) {
+                return Err(FilterReason::Termination);
+            }
+        }
+
+        if let Some(variants) = &config.variants {
+            // Games without a Variant tag are standard chess
+            let variant = headers.get("Variant").unwrap_or("Standard");
+            if !variants.iter().any(|v| v.eq_ignore_ascii_case(variant)) {
+                return Err(FilterReason::Variant);
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(tags: &[(&str, &str)]) -> GameMetadata {
+        let mut metadata = GameMetadata::default();
+        for (key, value) in tags {
+            metadata.insert(key, value);
+        }
+        return metadata;
+    }
+
+    #[test]
+    fn time_control_class() {
+        assert_eq!(TimeControlClass::from_header("15+0"), Some(TimeControlClass::UltraBullet));
+        assert_eq!(TimeControlClass::from_header("60+0"), Some(TimeControlClass::Bullet));
+        assert_eq!(TimeControlClass::from_header("180+2"), Some(TimeControlClass::Blitz));
+        assert_eq!(TimeControlClass::from_header("600+5"), Some(TimeControlClass::Rapid));
+        assert_eq!(TimeControlClass::from_header("1800+30"), Some(TimeControlClass::Classical));
+        assert_eq!(TimeControlClass::from_header("40/7200:3600"), Some(TimeControlClass::Classical));
+        assert_eq!(TimeControlClass::from_header("-"), Some(TimeControlClass::Correspondence));
+        assert_eq!(TimeControlClass::from_header("?"), None);
+    }
+
+    #[test]
+    fn elo() {
+        let filter = GameFilter::new(FilterConfig { min_elo: Some(2000), max_elo: Some(2800), min_average_elo: Some(2200), ..Default::default() }).unwrap();
+        assert_eq!(filter.check(&headers(&[("WhiteElo", "2300"), ("BlackElo", "2250")])), Ok(()));
+        assert_eq!(filter.check(&headers(&[("WhiteElo", "2300"), ("BlackElo", "1950")])), Err(FilterReason::MinElo));
+        assert_eq!(filter.check(&headers(&[("WhiteElo", "2900"), ("BlackElo", "2250")])), Err(FilterReason::MaxElo));
+        assert_eq!(filter.check(&headers(&[("WhiteElo", "2100"), ("BlackElo", "2100")])), Err(FilterReason::AverageElo));
+        assert_eq!(filter.check(&headers(&[("WhiteElo", "2300"), ("BlackElo", "?")])), Err(FilterReason::MissingElo));
+        assert_eq!(filter.check(&headers(&[])), Err(FilterReason::MissingElo));
+    }
+
+    #[test]
+    fn other_headers() {
+        let filter = GameFilter::new(FilterConfig {
+            time_controls: Some(Vec::from([TimeControlClass::Rapid, TimeControlClass::Classical])),
+            min_date: Some(String::from("2020.01.01")),
+            max_date: Some(String::from("2020.12.31")),
+            event_regex: Some(String::from("(?i)rated")),
+            terminations: Some(Vec::from([String::from("Normal")])),
+            ..Default::default()
+        }).unwrap();
+
+        let game = [("TimeControl", "600+0"), ("UTCDate", "2020.06.01"), ("Event", "Rated Rapid game"), ("Termination", "Normal")];
+        assert_eq!(filter.check(&headers(&game)), Ok(()));
+
+        let mut tags = game;
+        tags[0] = ("TimeControl", "60+0");
+        assert_eq!(filter.check(&headers(&tags)), Err(FilterReason::TimeControl));
+        let mut tags = game;
+        tags[1] = ("UTCDate", "2021.01.01");
+        assert_eq!(filter.check(&headers(&tags)), Err(FilterReason::Date));
+        let mut tags = game;
+        tags[1] = ("Date", "2020.??.??");
+        assert_eq!(filter.check(&headers(&tags)), Ok(()));
+        let mut tags = game;
+        tags[2] = ("Event", "Casual Rapid game");
+        assert_eq!(filter.check(&headers(&tags)), Err(FilterReason::Event));
+        let mut tags = game;
+        tags[3] = ("Termination", "Time forfeit");
+        assert_eq!(filter.check(&headers(&tags)), Err(FilterReason::Termination));
+
+        let filter = GameFilter::new(FilterConfig { variants: Some(Vec::from([String::from("standard")])), ..Default::default() }).unwrap();
+        assert_eq!(filter.check(&headers(&[])), Ok(()));
+        assert_eq!(filter.check(&headers(&[("Variant", "Chess960")])), Err(FilterReason::Variant));
+
+        assert!(GameFilter::new(FilterConfig::default()).unwrap().is_empty());
+        assert!(!filter.is_empty());
+    }
+
+    #[test]
+    fn config() {
+        let config: FilterConfig = serde_json::from_str(r#"{"min_elo": 1800, "time_controls": ["blitz", "ultrabullet"]}"#).unwrap();
+        assert_eq!(config.min_elo, Some(1800));
+        assert_eq!(config.time_controls, Some(Vec::from([TimeControlClass::Blitz, TimeControlClass::UltraBullet])));
+        assert!(serde_json::from_str::<FilterConfig>(r#"{"min_rating": 1800}"#).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use std::{collections::{BTreeMap, BTreeSet}, fs, io::{BufReader, Read, Seek, SeekFrom}};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use shakmaty::{Color, Outcome};
+
+use crate::{dataset::{self, DatasetHeader, FileKind}, decoder, sampler::Rng, stats::OutcomeCounts, tokenizer::{Token, Tokenizer}};
+
+/*
+
+Read access to a built dataset (token file + index) for the inspect / sample /
+show / verify subcommands of the builder.
+
+The index is loaded in full (8 bytes per game), the token data is read game by
+game so multi GB datasets never have to fit in memory. Games read in index
+order are read sequentially through a single buffered reader.
+
+Game `i` spans the bytes [index[i], index[i + 1]) of the token data, the last
+game runs up to the end of the data. verify() checks everything the training
+loaders rely on: offsets that are token aligned, start at 0 and strictly
+increase, games that begin with a <start:*> token, end with <end> and replay
+legally.
+
+*/
+
+pub struct DatasetReader {
+    tokens: BufReader<fs::File>,
+    position: u64,
+    data_len: u64,
+    offsets: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DatasetSummary {
+    pub games: u64,
+    pub tokens: u64,
+    pub outcomes: OutcomeCounts,
+    pub variants: BTreeMap<String, u64>,
+    pub min_game_tokens: u64,
+    pub max_game_tokens: u64,
+    pub mean_game_tokens: f64,
+    // Games with more tokens than the context length
+    pub long_games: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    pub game: usize,
+    pub message: String,
+}
+
+impl DatasetReader {
+    pub fn open(tokens_path: &str, index_path: &str, tokenizer: &Tokenizer) -> Result<DatasetReader, Error> {
+        let offsets = dataset::read_index(index_path, tokenizer)?;
+        let mut tokens_file = fs::File::open(tokens_path)?;
+        DatasetHeader::read(&mut tokens_file)?.validate(FileKind::Tokens, tokenizer)?;
+        let data_len = tokens_file.metadata()?.len() - dataset::HEADER_SIZE as u64;
+        return Ok(DatasetReader { tokens: BufReader::new(tokens_file), position: 0, data_len, offsets });
+    }
+
+    pub fn len(&self) -> usize {
+        return self.offsets.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.offsets.is_empty();
+    }
+
+    /// Bytes of token data
+    pub fn data_len(&self) -> u64 {
+        return self.data_len;
+    }
+
+    pub fn offsets(&self) -> &[u64] {
+        return &self.offsets;
+    }
+
+    /// Byte range of game `game` in the token data
+    pub fn game_range(&self, game: usize) -> Result<(u64, u64), Error> {
+        let start = match self.offsets.get(game) {
+            Some(start) => *start,
+            None => return Err(anyhow!("Game {} out of range, the dataset holds {} games", game, self.offsets.len())),
+        };
+        let end = self.offsets.get(game + 1).copied().unwrap_or(self.data_len);
+        if start % 2 != 0 || end % 2 != 0 {
+            return Err(anyhow!("Offset {} or {} is not token aligned", start, end));
+        }
+        if end <= start {
+            return Err(anyhow!("Offset {} of the next game does not follow offset {}", end, start));
+        }
+        if end > self.data_len {
+            return Err(anyhow!("Offset {} is past the end of the token data ({} bytes)", end, self.data_len));
+        }
+        return Ok((start, end));
+    }
+
+    pub fn read_game(&mut self, game: usize) -> Result<Vec<Token>, Error> {
+        let (start, end) = self.game_range(game)?;
+        if start != self.position {
+            self.tokens.seek(SeekFrom::Start(dataset::HEADER_SIZE as u64 + start))?;
+        }
+        let mut bytes = vec![0u8; (end - start) as usize];
+        self.tokens.read_exact(&mut bytes)?;
+        self.position = end;
+        return decoder::tokens_from_bytes(&bytes);
+    }
+
+    pub fn summary(&mut self, tokenizer: &Tokenizer, context_length: usize) -> Result<DatasetSummary, Error> {
+        let mut summary = DatasetSummary { tokens: self.data_len / 2, ..Default::default() };
+        for game in 0..self.len() {
+            let tokens = self.read_game(game)?;
+            let start = &tokens[0];
+            match tokenizer.token_to_game_outcome(start)? {
+                Some(Outcome::Decisive { winner: Color::White }) => summary.outcomes.white += 1,
+                Some(Outcome::Decisive { winner: Color::Black }) => summary.outcomes.black += 1,
+                Some(Outcome::Draw) => summary.outcomes.draw += 1,
+                None => summary.outcomes.unknown += 1,
+            }
+            *summary.variants.entry(String::from(tokenizer.token_to_variant(start)?.tag())).or_insert(0) += 1;
+
+            let n_tokens = tokens.len() as u64;
+            summary.min_game_tokens = match summary.games {
+                0 => n_tokens,
+                _ => summary.min_game_tokens.min(n_tokens),
+            };
+            summary.max_game_tokens = summary.max_game_tokens.max(n_tokens);
+            if tokens.len() > context_length {
+                summary.long_games += 1;
+            }
+            summary.games += 1;
+        }
+        if summary.games > 0 {
+            summary.mean_game_tokens = summary.tokens as f64 / summary.games as f64;
+        }
+        return Ok(summary);
+    }
+
+    /// Checks every indexed game, returns the problems found (empty for a valid dataset)
+    pub fn verify(&mut self, tokenizer: &Tokenizer) -> Result<Vec<VerifyError>, Error> {
+        let mut errors = Vec::new();
+        if let Some(first) = self.offsets.first() {
+            if *first != 0 {
+                errors.push(VerifyError { game: 0, message: format!("First game starts at offset {}, not 0", first) });
+            }
+        } else if self.data_len > 0 {
+            errors.push(VerifyError { game: 0, message: format!("Empty index for {} bytes of token data", self.data_len) });
+        }
+
+        for game in 0..self.len() {
+            // A broken offset leaves nothing to check in the game itself
+            let tokens = match self.game_range(game) {
+                Ok(_) => self.read_game(game)?,
+                Err(e) => {
+                    errors.push(VerifyError { game, message: e.to_string() });
+                    continue;
+                },
+            };
+            let mut error = |message: String| errors.push(VerifyError { game, message });
+            if !tokenizer.is_game_start_token(&tokens[0]) {
+                error(format!("Does not begin with a <start:*> token but {}", tokens[0].value));
+            } else if !tokenizer.is_game_end_token(&tokens[tokens.len() - 1]) {
+                error(String::from("Does not end with <end>"));
+            } else if let Err(e) = decoder::decode_game(&tokens, tokenizer) {
+                error(e.to_string());
+            }
+        }
+        return Ok(errors);
+    }
+}
+
+/// `count` distinct game numbers below `n_games` in increasing order, all of them when there are fewer
+pub fn sample_games(n_games: usize, count: usize, seed: u64) -> Vec<usize> {
+    if count >= n_games {
+        return (0..n_games).collect();
+    }
+
+    // Floyd's algorithm, no list of all the games for large datasets
+    let mut rng = Rng::new(seed);
+    let mut games = BTreeSet::new();
+    for i in n_games - count..n_games {
+        let game = (rng.next_u64() % (i as u64 + 1)) as usize;
+        if !games.insert(game) {
+            games.insert(i);
+        }
+    }
+    return games.into_iter().collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, str::FromStr};
+
+    use shakmaty::uci::Uci;
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn build(dir: &TempDir, tokenizer: &Tokenizer, games: &[Vec<u16>], offsets: Option<&[u64]>) -> (String, String) {
+        let tokens_path = dir.path().join("out.games").to_str().unwrap().to_string();
+        let index_path = dir.path().join("out.index").to_str().unwrap().to_string();
+        let _ = fs::remove_file(&tokens_path);
+        let _ = fs::remove_file(&index_path);
+        let (mut tokens_file, _) = dataset::open_for_append(&tokens_path, FileKind::Tokens, tokenizer).unwrap();
+        let (mut index_file, _) = dataset::open_for_append(&index_path, FileKind::Index, tokenizer).unwrap();
+        let mut offset = 0u64;
+        for (i, game) in games.iter().enumerate() {
+            let entry = offsets.map_or(offset, |offsets| offsets[i]);
+            index_file.write_all(&entry.to_be_bytes()).unwrap();
+            for tok in game {
+                tokens_file.write_all(&tok.to_be_bytes()).unwrap();
+            }
+            offset += 2 * game.len() as u64;
+        }
+        return (tokens_path, index_path);
+    }
+
+    fn encode(tokenizer: &Tokenizer, start: u16, moves: &[&str]) -> Vec<u16> {
+        let mut tokens = Vec::from([start]);
+        for uci in moves {
+            tokens.push(tokenizer.uci_to_token(Uci::from_str(uci).unwrap()).unwrap().value);
+        }
+        tokens.push(3);
+        return tokens;
+    }
+
+    #[test]
+    fn dataset() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        let dir = TempDir::new("inspect").unwrap();
+        let games = Vec::from([
+            encode(&tokenizer, 0, &["e2e4", "e7e5", "d1h5"]),
+            encode(&tokenizer, 2, &["d2d4"]),
+            encode(&tokenizer, 1, &["g1f3", "g8f6", "f3g1", "f6g8"]),
+        ]);
+
+        let (tokens_path, index_path) = build(&dir, &tokenizer, &games, None);
+        let mut reader = DatasetReader::open(&tokens_path, &index_path, &tokenizer).unwrap();
+        assert_eq!(reader.len(), 3);
+        assert_eq!(reader.read_game(2).unwrap().len(), 6);
+        assert_eq!(reader.read_game(0).unwrap().len(), 5);
+        assert!(reader.read_game(3).is_err());
+        assert_eq!(decoder::decode_game(&reader.read_game(1).unwrap(), &tokenizer).unwrap().result(), "1/2-1/2");
+
+        let summary = reader.summary(&tokenizer, 5).unwrap();
+        assert_eq!(summary.games, 3);
+        assert_eq!(summary.tokens, 14);
+        assert_eq!(summary.outcomes, OutcomeCounts { white: 1, black: 1, draw: 1, unknown: 0 });
+        assert_eq!(summary.variants, BTreeMap::from([(String::from("Standard"), 3)]));
+        assert_eq!((summary.min_game_tokens, summary.max_game_tokens, summary.long_games), (3, 6, 1));
+        assert!(reader.verify(&tokenizer).unwrap().is_empty());
+
+        // An illegal move, a missing <end>, a game without a start token and offsets that go backwards
+        let broken = Vec::from([
+            encode(&tokenizer, 0, &["e2e4", "e2e4"]),
+            Vec::from([0, tokenizer.uci_to_token(Uci::from_str("e2e4").unwrap()).unwrap().value]),
+            encode(&tokenizer, 0, &["d2d4"])[1..].to_vec(),
+            encode(&tokenizer, 1, &["d2d4"]),
+        ]);
+        let (tokens_path, index_path) = build(&dir, &tokenizer, &broken, None);
+        let mut reader = DatasetReader::open(&tokens_path, &index_path, &tokenizer).unwrap();
+        let errors = reader.verify(&tokenizer).unwrap();
+        assert_eq!(errors.iter().map(|error| error.game).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(errors[1].message.contains("<end>"));
+        assert!(errors[2].message.contains("<start:*>"));
+
+        let (tokens_path, index_path) = build(&dir, &tokenizer, &games, Some(&[2, 10, 4]));
+        let mut reader = DatasetReader::open(&tokens_path, &index_path, &tokenizer).unwrap();
+        let errors = reader.verify(&tokenizer).unwrap();
+        assert_eq!(errors.iter().map(|error| error.game).collect::<Vec<_>>(), [0, 0, 1, 2]);
+        assert!(errors[0].message.contains("not 0"));
+        assert!(errors[2].message.contains("does not follow"));
+    }
+
+    #[test]
+    fn sample() {
+        assert_eq!(sample_games(3, 5, 0), [0, 1, 2]);
+        let games = sample_games(1000, 10, 7);
+        assert_eq!(games.len(), 10);
+        assert!(games.windows(2).all(|pair| pair[0] < pair[1]) && games[9] < 1000);
+        assert_eq!(sample_games(1000, 10, 7), games);
+        assert_ne!(sample_games(1000, 10, 8), games);
+    }
+}
@@ -0,0 +1,24 @@
+#![allow(clippy::needless_return)]
+
+pub mod utils;
+pub mod scheme;
+pub mod tokenizer;
+pub mod decoder;
+pub mod dataset;
+pub mod manifest;
+pub mod npy;
+pub mod split;
+pub mod filter;
+pub mod pgn_reader;
+pub mod metadata;
+pub mod pgn_parser;
+pub mod variant;
+pub mod sampler;
+pub mod ffi;
+pub mod model;
+pub mod engine;
+pub mod dedup;
+pub mod pipeline;
+pub mod rejection;
+pub mod stats;
+pub mod inspect;
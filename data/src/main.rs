@@ -1,124 +1,554 @@
-use std::sync::{Arc, Mutex};
+#![allow(clippy::needless_return)]
+
 //use std::thread::{self, JoinHandle};
+use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
-use clap::Parser;
+use std::time::Instant;
+use clap::{Args, Parser, Subcommand};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use data::{dataset, decoder, dedup, filter, inspect, manifest, metadata, npy, pgn_parser, pipeline, rejection, scheme, split, stats, tokenizer, utils, variant};
+use dataset::FileKind;
+use dedup::{DedupConfig, DedupLog, DedupStore, Fingerprint};
+use filter::{FilterConfig, GameFilter, TimeControlClass};
+use inspect::DatasetReader;
+use metadata::GameMetadata;
+use manifest::{BuildManifest, OutputRange, SourceEntry};
 use pgn_parser::PgnVisitor;
-use shakmaty::{Color, Move, Outcome};
+use pipeline::{Event, PipelineConfig};
+use rejection::{RejectReason, Rejection, RejectionLog};
+use stats::{GameStats, StatsReport};
+use scheme::SchemeKind;
+use split::{Split, SplitConfig};
+use shakmaty::{variant::VariantPosition, Chess, Color, Move, Outcome, Position};
 use tokenizer::{Token, Tokenizer};
-use std::collections::HashSet;
+use variant::GameVariant;
 
-mod utils;
-mod tokenizer;
-mod pgn_reader;
-mod pgn_parser;
+struct OutputFiles {
+    name: &'static str,
+    file: fs::File,
+    game_indexes_file: fs::File,
+    mask_file: Option<fs::File>,
+    global_game_idx: u64,
+    index_len: u64,
+    mask_len: u64,
+    mask_size: u64,
+}
 
-struct Visitor<'a> {
+impl OutputFiles {
+    fn open(name: &'static str, path: &str, index_path: &str, mask_path: Option<&str>, tokenizer: &Tokenizer) -> Result<OutputFiles, Error> {
+        let (file, global_game_idx) = dataset::open_for_append(path, FileKind::Tokens, tokenizer)?;
+        let (game_indexes_file, index_len) = dataset::open_for_append(index_path, FileKind::Index, tokenizer)?;
+        let (mask_file, mask_len) = match mask_path {
+            Some(mask_path) => {
+                let (mask_file, mask_len) = dataset::open_for_append(mask_path, FileKind::Mask, tokenizer)?;
+                (Some(mask_file), mask_len)
+            },
+            None => (None, 0),
+        };
+        let mask_size = tokenizer.mask_size() as u64;
+        return Ok(OutputFiles { name, file, game_indexes_file, mask_file, global_game_idx, index_len, mask_len, mask_size });
+    }
+
+    // Length of the mask data matching `tokens_len` bytes of tokens, one mask per token
+    fn expected_mask_len(&self, tokens_len: u64) -> u64 {
+        return match self.mask_file {
+            Some(_) => tokens_len / 2 * self.mask_size,
+            None => 0,
+        };
+    }
+
+    fn truncate(&mut self, global_game_idx: u64, index_len: u64) -> Result<(), Error> {
+        dataset::truncate_data(&self.file, global_game_idx)?;
+        dataset::truncate_data(&self.game_indexes_file, index_len)?;
+        if let Some(mask_file) = &self.mask_file {
+            self.mask_len = self.expected_mask_len(global_game_idx);
+            dataset::truncate_data(mask_file, self.mask_len)?;
+        }
+        self.global_game_idx = global_game_idx;
+        self.index_len = index_len;
+        return Ok(());
+    }
+
+    // Returns the bytes written
+    fn append(&mut self, pending: &PendingOutput) -> Result<u64, Error> {
+        if pending.output.is_empty() {
+            return Ok(0);
+        }
+
+        let mut indexes = Vec::<u8>::new();
+        for idx in &pending.game_indexes {
+            indexes.extend((idx + self.global_game_idx).to_be_bytes());
+        }
+        self.file.write_all(&pending.output)?;
+        self.game_indexes_file.write_all(&indexes)?;
+        self.global_game_idx += pending.output.len() as u64;
+        self.index_len += indexes.len() as u64;
+        if let Some(mask_file) = &mut self.mask_file {
+            mask_file.write_all(&pending.masks)?;
+            self.mask_len += pending.masks.len() as u64;
+        }
+        return Ok((pending.output.len() + indexes.len() + pending.masks.len()) as u64);
+    }
+}
+
+// Games of one chunk, appended to the matching OutputFiles by the writer
+#[derive(Default)]
+struct PendingOutput {
     output: Vec<u8>,
-    tokenizer: &'a Tokenizer,
-    hash_collection: Arc<Mutex<HashSet<String>>>,
     game_indexes: Vec<u64>,
-    current_outcome: Option<Outcome>,
-    current_ply: u32,
+    masks: Vec<u8>,
+    games: u64,
+}
+
+impl PendingOutput {
+    // Byte range of game `i` in `output`
+    fn game_range(&self, i: usize) -> (usize, usize) {
+        let start = self.game_indexes[i] as usize;
+        let end = self.game_indexes.get(i + 1).map_or(self.output.len(), |end| *end as usize);
+        return (start, end);
+    }
+
+    fn game_tokens(&self, i: usize) -> Vec<Token> {
+        let (start, end) = self.game_range(i);
+        return self.output[start..end].chunks(2).map(|bytes| Token { value: u16::from_be_bytes([bytes[0], bytes[1]]) }).collect();
+    }
 
-    white_winning_games: u32,
-    black_winning_games: u32,
-    draw_games: u32,
-    duplicate_games: u32,
-    ply: u32,
+    // Drops the games whose `keep` flag is false
+    fn retain(&mut self, keep: &[bool]) {
+        let token_mask_size = match self.output.len() {
+            0 => 0,
+            len => self.masks.len() / (len / 2),
+        };
+        let mut kept = PendingOutput::default();
+        for (i, keep) in keep.iter().enumerate() {
+            if !keep {
+                continue;
+            }
+            let (start, end) = self.game_range(i);
+            kept.game_indexes.push(kept.output.len() as u64);
+            kept.output.extend_from_slice(&self.output[start..end]);
+            kept.masks.extend_from_slice(&self.masks[start / 2 * token_mask_size..end / 2 * token_mask_size]);
+            kept.games += 1;
+        }
+        *self = kept;
+    }
+}
+
+// A game kept by a worker of a deterministic build, the writer decides whether it is a duplicate
+struct SavedGame {
+    fingerprint: Fingerprint,
+    output: usize,
+    outcome: Option<Outcome>,
+    ply: u64,
+    // Reported when it is a duplicate
+    rejection: Option<Rejection>,
+}
+
+// What a worker made of one chunk of PGN text
+struct ChunkOutput {
+    outputs: Vec<PendingOutput>,
+    new_hashes: Vec<Fingerprint>,
+    // Fingerprints of the games a worker of a non-deterministic build dropped as duplicates
+    duplicate_hashes: Vec<Fingerprint>,
+    saved_games: Vec<SavedGame>,
+    // Numbered and placed relative to the chunk
+    rejections: Vec<Rejection>,
+    n_games: u64,
+    counts: GameStats,
+    result: Result<(), Error>,
+}
+
+impl ChunkOutput {
+    // Chunks reach the writer in input order, so deduplicating here always keeps the first copy of a game
+    fn resolve_duplicates(&mut self, dedup: &DedupStore, context_length: usize) -> Result<(), Error> {
+        let mut keep: Vec<Vec<bool>> = self.outputs.iter().map(|_| Vec::new()).collect();
+        for game in self.saved_games.drain(..) {
+            let is_new = dedup.insert(game.fingerprint)?;
+            let index = keep[game.output].len();
+            keep[game.output].push(is_new);
+            if is_new {
+                self.new_hashes.push(game.fingerprint);
+            } else {
+                let tokens = self.outputs[game.output].game_tokens(index);
+                self.counts.remove_game(game.outcome, game.ply, &tokens, context_length);
+                self.counts.duplicate_games += 1;
+                self.counts.reject(RejectReason::Duplicate);
+                self.rejections.extend(game.rejection);
+            }
+        }
+        self.rejections.sort_by_key(|rejection| rejection.game);
+        for (pending, keep) in self.outputs.iter_mut().zip(&keep) {
+            if keep.contains(&false) {
+                pending.retain(keep);
+            }
+        }
+        return Ok(());
+    }
+}
+
+// Written chunks of the current source, committed to the manifest or rolled back at its end
+struct SourceState {
+    starts: Vec<(u64, u64)>,
+    games: Vec<u64>,
+    new_hashes: Vec<Fingerprint>,
+    counts: GameStats,
+    n_games: u64,
+    report_start: u64,
+    started: Instant,
+    error: Option<Error>,
+}
+
+impl SourceState {
+    fn new(output_files: &[OutputFiles], report: Option<&RejectionLog>) -> SourceState {
+        return SourceState {
+            starts: output_files.iter().map(|output_files| (output_files.global_game_idx, output_files.index_len)).collect(),
+            games: vec![0; output_files.len()],
+            new_hashes: Vec::new(),
+            counts: GameStats::default(),
+            n_games: 0,
+            report_start: report.map_or(0, |report| report.len()),
+            started: Instant::now(),
+            error: None,
+        };
+    }
+}
+
+// Build settings the workers need
+#[derive(Clone, Copy)]
+struct VisitorOptions {
+    deterministic: bool,
+    write_masks: bool,
+    report: bool,
+    context_length: usize,
+}
+
+// Tokenizes the games of one chunk
+struct Visitor<'a> {
+    outputs: Vec<PendingOutput>,
+    tokenizer: &'a Tokenizer,
+    split_config: Option<&'a SplitConfig>,
+    filter: &'a GameFilter,
+    dedup: &'a DedupStore,
+    new_hashes: Vec<Fingerprint>,
+    duplicate_hashes: Vec<Fingerprint>,
+    saved_games: Vec<SavedGame>,
+    options: VisitorOptions,
+    rejections: Vec<Rejection>,
+    n_games: u64,
+    current_offset: u64,
+    current_metadata: GameMetadata,
+    current_outcome: Option<Outcome>,
+    current_pos: VariantPosition,
+    current_masks: Vec<u8>,
+    current_game_key: String,
+    current_ply: u64,
+    counts: GameStats,
+    // A dedup store failure, fails the chunk (and with it the source) like an I/O error
+    error: Option<Error>,
 }
 
-impl Visitor<'_> {
-    fn new<'a>(tokenizer: &'a Tokenizer, hash_collection: Arc<Mutex<HashSet<String>>>) -> Visitor<'a> {
+impl<'a> Visitor<'a> {
+    fn new(tokenizer: &'a Tokenizer, split_config: Option<&'a SplitConfig>, filter: &'a GameFilter, dedup: &'a DedupStore, options: VisitorOptions) -> Visitor<'a> {
+        let n_outputs = match split_config {
+            Some(_) => Split::ALL.len(),
+            None => 1,
+        };
+
         Visitor {
-            output: Vec::new(),
+            outputs: (0..n_outputs).map(|_| PendingOutput::default()).collect(),
             tokenizer,
-            hash_collection,
-            game_indexes: Vec::new(),
+            split_config,
+            filter,
+            dedup,
+            new_hashes: Vec::new(),
+            duplicate_hashes: Vec::new(),
+            saved_games: Vec::new(),
+            options,
+            rejections: Vec::new(),
+            n_games: 0,
+            current_offset: 0,
+            current_metadata: GameMetadata::default(),
             current_outcome: Option::None,
+            current_pos: Chess::default().into(),
+            current_masks: Vec::new(),
+            current_game_key: String::new(),
             current_ply: 0,
+            counts: GameStats::default(),
+            error: None,
+        }
+    }
+
+    fn into_output(self, result: Result<(), Error>) -> ChunkOutput {
+        return ChunkOutput {
+            outputs: self.outputs,
+            new_hashes: self.new_hashes,
+            duplicate_hashes: self.duplicate_hashes,
+            saved_games: self.saved_games,
+            rejections: self.rejections,
+            n_games: self.n_games,
+            counts: self.counts,
+            result: match self.error {
+                Some(e) => Err(e),
+                None => result,
+            },
+        };
+    }
+
+    // The report entry of the current game, None without --reject-report
+    fn rejection(&self, reason: RejectReason, detail: Option<String>, metadata: &GameMetadata) -> Option<Rejection> {
+        if !self.options.report {
+            return None;
+        }
+        let mut rejection = Rejection::new(reason, detail, metadata);
+        rejection.game = self.n_games.saturating_sub(1);
+        rejection.offset = self.current_offset;
+        return Some(rejection);
+    }
 
-            white_winning_games: 0,
-            black_winning_games: 0,
-            draw_games: 0,
-            duplicate_games: 0,
-            ply: 0,
+    fn reject(&mut self, reason: RejectReason, detail: Option<String>, metadata: &GameMetadata) {
+        self.counts.reject(reason);
+        if let Some(rejection) = self.rejection(reason, detail, metadata) {
+            self.rejections.push(rejection);
         }
     }
 }
 
 impl PgnVisitor for Visitor<'_> {
-    fn begin_game(&mut self, _outcome: Outcome) -> Result<Vec<Token>, Error> { 
-        match self.tokenizer.game_start_token(Option::Some(_outcome)) {
-            Ok(v) => {
-                self.current_outcome = Some(_outcome);
-                Ok(Vec::from([v]))
+    fn accept_game(&mut self, _metadata: &GameMetadata) -> bool {
+        // Games of a variant without start tokens would be mistaken for standard games
+        match GameVariant::from_tag(_metadata.get("Variant")) {
+            Ok(variant) if self.tokenizer.supports_variant(variant) => {},
+            _ => {
+                self.counts.unsupported_variant_games += 1;
+                self.reject(RejectReason::UnsupportedVariant, _metadata.get("Variant").map(String::from), _metadata);
+                return false;
+            },
+        }
+        // Without setup tokens a game from a custom position can not be told apart from a standard one
+        if _metadata.get("FEN").is_some() && !self.tokenizer.has_setup_tokens() {
+            self.counts.unsupported_setup_games += 1;
+            self.reject(RejectReason::UnsupportedSetup, None, _metadata);
+            return false;
+        }
+        match self.filter.check(_metadata) {
+            Ok(_) => true,
+            Err(reason) => {
+                *self.counts.filtered_games.entry(reason).or_insert(0) += 1;
+                self.reject(RejectReason::Filter, Some(reason.to_string()), _metadata);
+                false
+            },
+        }
+    }
+
+    fn game_offset(&mut self, _offset: u64) {
+        self.n_games += 1;
+        self.current_offset = _offset;
+    }
+
+    fn reject_game(&mut self, _metadata: &GameMetadata, _reason: RejectReason, _detail: &str) {
+        if !self.options.report && _reason != RejectReason::MissingResult {
+            eprintln!("Error: {}", _detail);
+        }
+        self.reject(_reason, Some(String::from(_detail)), _metadata);
+    }
+
+    fn keep_unknown_outcomes(&self) -> bool {
+        return self.tokenizer.has_unknown_outcome_token();
+    }
+
+    fn invalid_setup(&mut self, _fen: &str, _error: &Error) {
+        self.counts.unsupported_setup_games += 1;
+    }
+
+    fn begin_game_with_metadata(&mut self, _outcome: Option<Outcome>, _metadata: &GameMetadata, _start: &VariantPosition) -> Result<Vec<Token>, Error> {
+        let variant = GameVariant::of(_start);
+        match self.tokenizer.game_prefix(_start, _outcome, _metadata.elo(Color::White), _metadata.elo(Color::Black)) {
+            Ok(tokens) => {
+                self.current_outcome = _outcome;
+                self.current_pos = _start.clone();
+                if self.options.report {
+                    // Duplicates are only found once the game is saved
+                    self.current_metadata = _metadata.clone();
+                }
+                self.current_game_key = match _outcome {
+                    Some(outcome) => format!("{}", outcome),
+                    None => String::from("*"),
+                };
+                if variant != GameVariant::Standard {
+                    self.current_game_key.push_str(&format!(" <{}>", variant.name()));
+                }
+                if tokens.iter().any(|token| self.tokenizer.is_setup_token(token)) {
+                    // Games from different positions must not share a key
+                    self.current_game_key.push_str(&format!(" [{}]", _metadata.get("FEN").unwrap_or("")));
+                }
+
+                // Start, Elo and setup tokens are not constrained by the position
+                self.current_masks.clear();
+                if self.options.write_masks {
+                    for _ in &tokens {
+                        self.current_masks.extend(vec![0xff; self.tokenizer.mask_size()]);
+                    }
+                }
+                Ok(tokens)
             },
             Err(e) => Err(e),
         }
     }
 
     fn game_move(&mut self, _move: Move) -> Result<Vec<Token>, Error> {
-        match self.tokenizer.uci_to_token(_move.to_uci(shakmaty::CastlingMode::Standard)) {
+        let uci = _move.to_uci(self.current_pos.castles().mode());
+        self.current_game_key.push_str(&format!(" {}", uci));
+        match self.tokenizer.encode_move(&self.current_pos, &_move) {
             Ok(v) => {
+                if self.options.write_masks {
+                    for k in 0..v.len() {
+                        let legal = self.tokenizer.legal_next_tokens(&self.current_pos, &v[..k]);
+                        self.current_masks.extend(self.tokenizer.pack_mask(&legal));
+                    }
+                }
+                self.current_pos.play_unchecked(&_move);
                 self.current_ply += 1;
-                Ok(Vec::from([v]))
+                Ok(v)
             },
             Err(e) => Err(e),
         }
     }
 
     fn end_game(&mut self) -> Result<Vec<Token>, Error> {
+        if self.options.write_masks {
+            let legal = self.tokenizer.legal_move_tokens(&self.current_pos);
+            self.current_masks.extend(self.tokenizer.pack_mask(&legal));
+        }
         Ok(Vec::from([self.tokenizer.game_end_token()]))
     }
 
     fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> {
-        match self.hash_collection.lock() {
-            Ok(mut hash_collection) => {
-                let mut game: Vec<u8> = Vec::new();
-                for tok in _game {
-                    game.extend(tok.value.to_be_bytes());
-                }
+        let mut game: Vec<u8> = Vec::new();
+        for tok in &_game {
+            game.extend(tok.value.to_be_bytes());
+        }
 
-                let hash = sha256::digest(game.clone());
-                
-                if !hash_collection.contains(&hash) {
-                    hash_collection.insert(hash);
-                    self.game_indexes.push(self.output.len() as u64);
-
-                    self.output.extend(game);
-                    match self.current_outcome {
-                        Some(outcome) => match outcome {
-                            Outcome::Decisive { winner } => match winner {
-                                Color::White => self.white_winning_games += 1,
-                                Color::Black => self.black_winning_games += 1,
-                            },
-                            Outcome::Draw => self.draw_games += 1,
+        let fingerprint = dedup::fingerprint(&game);
+        // Deterministic builds leave the dedup to the writer
+        let is_new = match self.options.deterministic {
+            true => Ok(true),
+            false => self.dedup.insert(fingerprint),
+        };
+        match is_new {
+            Ok(is_new) => {
+                if is_new {
+                    let output = match self.split_config {
+                        Some(split_config) => {
+                            let split = split_config.assign(&self.current_game_key);
+                            Split::ALL.iter().position(|s| *s == split).unwrap()
                         },
-                        None => {}
+                        None => 0,
+                    };
+                    match self.options.deterministic {
+                        true => {
+                            let rejection = self.rejection(RejectReason::Duplicate, None, &self.current_metadata);
+                            self.saved_games.push(SavedGame { fingerprint, output, outcome: self.current_outcome, ply: self.current_ply, rejection });
+                        },
+                        false => self.new_hashes.push(fingerprint),
                     }
-                    self.ply += self.current_ply;
+
+                    let pending = &mut self.outputs[output];
+                    pending.game_indexes.push(pending.output.len() as u64);
+                    pending.output.extend(game);
+                    pending.masks.append(&mut self.current_masks);
+                    pending.games += 1;
+                    self.counts.add_game(self.current_outcome, self.current_ply, &_game, self.options.context_length);
                 } else {
                     // Duplicate game!!!
-                    self.duplicate_games += 1;
+                    self.counts.duplicate_games += 1;
+                    self.duplicate_hashes.push(fingerprint);
+                    let metadata = std::mem::take(&mut self.current_metadata);
+                    self.reject(RejectReason::Duplicate, None, &metadata);
+                }
+            },
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e);
                 }
             },
-            Err(e) => println!("Error: {e}"),
         }
 
         self.current_ply = 0;
         self.current_outcome = Option::None;
+        self.current_game_key.clear();
+        self.current_masks.clear();
 
         Ok(())
     }
 }
 
-/// Simple program to greet a person
+/// Builds tokenized chess datasets from PGN sources (without a command) and looks inside built ones
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    build: Option<BuildArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print summary counts of a dataset as json
+    Inspect {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        #[arg(long, default_value_t = 256, help="Games with more tokens than this are counted as long games")]
+        context_length: usize,
+    },
+
+    /// Decode random games of a dataset to PGN
+    Sample {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        #[arg(long, default_value_t = 10, help="Number of games")]
+        n: usize,
+
+        #[arg(long, default_value_t = 0, help="Seed of the game selection")]
+        seed: u64,
+    },
+
+    /// Print one game of a dataset as PGN
+    Show {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+
+        #[arg(long, help="0 based number of the game in the index")]
+        game: usize,
+    },
+
+    /// Check the offsets of every indexed game, its start and end tokens and that its moves replay legally
+    Verify {
+        #[command(flatten)]
+        dataset: DatasetArgs,
+    },
+}
+
+#[derive(Args, Debug)]
+struct DatasetArgs {
+
+    #[arg(long, help="Encoder json written by the build (--encoder-output)")]
+    vocab: String,
+
+    #[arg(long, help="Token file of the dataset (--output of the build)")]
+    tokens: String,
+
+    #[arg(long, help="Index file of the dataset (--index-output of the build)")]
+    index: String,
+}
+
+#[derive(Args, Debug)]
+struct BuildArgs {
 
     #[arg(long, help="Input path of a json containing input file urls")]
     input: String,
@@ -134,133 +564,449 @@ struct Args {
 
     #[arg(long, help="Number of worker threads")]
     n_workers: u16,
+
+    #[arg(long, help="Bytes of PGN text handed to a worker at once, 4 MiB by default")]
+    chunk_size: Option<usize>,
+
+    #[arg(long, help="Number of sources downloaded and decompressed ahead of the one being parsed, 2 by default")]
+    prefetch_sources: Option<usize>,
+
+    #[arg(long, help="Byte-identical outputs for identical inputs whatever the worker count, the first copy of a duplicate game in input order is kept")]
+    deterministic: bool,
+
+    #[arg(long, help="Build manifest path, defaults to <output>.manifest.json")]
+    manifest: Option<String>,
+
+    #[arg(long, help="Continue an interrupted build, skipping the sources recorded in the manifest")]
+    resume: bool,
+
+    #[arg(long, help="Keep at most this many dedup fingerprints in memory, spilling the rest to disk")]
+    dedup_memory_limit: Option<usize>,
+
+    #[arg(long, help="Directory of the spilled dedup fingerprints, a temporary directory by default")]
+    dedup_spill_dir: Option<String>,
+
+    #[arg(long, value_delimiter = ',', help="Also skip the games recorded in these dedup files of earlier builds, e.g. old.manifest.json.dedup")]
+    dedup_against: Option<Vec<String>>,
+
+    #[arg(long, help="Write every rejected game to this JSONL report: source url, game number, byte offset, header tags and reason")]
+    reject_report: Option<String>,
+
+    #[arg(long, help="Write per source and total build statistics to this JSON file, rewritten after every source")]
+    stats_output: Option<String>,
+
+    #[arg(long, default_value_t = 256, help="Games with more tokens than this are counted as long games in the statistics, e.g. the model block size")]
+    context_length: usize,
+
+    #[arg(long, help="Path prefix of little endian .npy exports of the tokens and index")]
+    npy_output: Option<String>,
+
+    #[arg(long, help="Also write the legal token mask of every token to this path, for masked softmax training")]
+    mask_output: Option<String>,
+
+    #[arg(long, help="Split the .npy export into shards of at most this many tokens")]
+    npy_shard_tokens: Option<u64>,
+
+    #[arg(long, help="Assign whole games to train/val/test outputs with these ratios, e.g. 0.9,0.05,0.05")]
+    split_ratios: Option<String>,
+
+    #[arg(long, default_value_t = 0, help="Seed of the train/val/test assignment")]
+    split_seed: u64,
+
+    #[arg(long, value_enum, default_value_t = SchemeKind::Uci, help="Move tokenization scheme")]
+    scheme: SchemeKind,
+
+    #[arg(long, help="Refuse to run when the generated move vocabulary differs from this encoder json, e.g. assets/vocab.json")]
+    frozen_vocab: Option<String>,
+
+    #[arg(long, help="Keep games with an unknown result (\"*\") behind a <start:UNKNOWN> token")]
+    keep_unknown_outcomes: bool,
+
+    #[arg(long, help="Keep games starting from a custom position (FEN tag) behind a <setup> prefix, they are skipped otherwise")]
+    setup_tokens: bool,
+
+    #[arg(long, value_enum, value_delimiter = ',', help="Also keep games of these variants, each behind its own start tokens, e.g. chess960,crazyhouse. Chess960 games need --setup-tokens")]
+    enable_variants: Option<Vec<GameVariant>>,
+
+    #[arg(long, value_delimiter = ',', help="Prefix games with Elo bucket tokens of both players, bucket edges e.g. 1200,1400,1600,1800,2000,2200,2400")]
+    elo_buckets: Option<Vec<u32>>,
+
+    #[arg(long, help="Json file with game filters, the filter flags below override its values")]
+    filter_config: Option<String>,
+
+    #[arg(long, help="Skip games where either player is rated below this")]
+    min_elo: Option<u32>,
+
+    #[arg(long, help="Skip games where either player is rated above this")]
+    max_elo: Option<u32>,
+
+    #[arg(long, help="Skip games with a lower average rating")]
+    min_average_elo: Option<u32>,
+
+    #[arg(long, help="Skip games with a higher average rating")]
+    max_average_elo: Option<u32>,
+
+    #[arg(long, value_delimiter = ',', help="Only keep games of these time control classes")]
+    time_control: Option<Vec<TimeControlClass>>,
+
+    #[arg(long, help="Skip games played before this date (YYYY.MM.DD)")]
+    min_date: Option<String>,
+
+    #[arg(long, help="Skip games played after this date (YYYY.MM.DD)")]
+    max_date: Option<String>,
+
+    #[arg(long, help="Only keep games whose Event tag matches this regex")]
+    event_regex: Option<String>,
+
+    #[arg(long, value_delimiter = ',', help="Only keep games with one of these Termination tags, e.g. Normal")]
+    termination: Option<Vec<String>>,
+
+    #[arg(long, value_delimiter = ',', help="Only keep games of these variants, games without a Variant tag are Standard")]
+    variant: Option<Vec<String>>,
+}
+
+impl BuildArgs {
+    fn filter_config(&self) -> Result<FilterConfig, Error> {
+        let mut config = match &self.filter_config {
+            Some(path) => FilterConfig::load(path)?,
+            None => FilterConfig::default(),
+        };
+        config.min_elo = self.min_elo.or(config.min_elo);
+        config.max_elo = self.max_elo.or(config.max_elo);
+        config.min_average_elo = self.min_average_elo.or(config.min_average_elo);
+        config.max_average_elo = self.max_average_elo.or(config.max_average_elo);
+        config.time_controls = self.time_control.clone().or(config.time_controls);
+        config.min_date = self.min_date.clone().or(config.min_date);
+        config.max_date = self.max_date.clone().or(config.max_date);
+        config.event_regex = self.event_regex.clone().or(config.event_regex);
+        config.terminations = self.termination.clone().or(config.terminations);
+        config.variants = self.variant.clone().or(config.variants);
+        return Ok(config);
+    }
 }
 
-struct Stats {
-    white_winning_games: u32,
-    black_winning_games: u32,
-    draw_games: u32,
-    duplicate_games: u32,
-    ply: u32,
+fn open_dataset(args: &DatasetArgs) -> (Tokenizer, DatasetReader) {
+    let tokenizer = Tokenizer::load(&args.vocab).expect("Unable to load the vocabulary");
+    let reader = match DatasetReader::open(&args.tokens, &args.index, &tokenizer) {
+        Ok(reader) => reader,
+        Err(e) => panic!("Unable to open the dataset: {}", e),
+    };
+    return (tokenizer, reader);
 }
 
-impl Stats {
-    fn new() -> Stats {
-        Stats {
-            white_winning_games: 0,
-            black_winning_games: 0,
-            draw_games: 0,
-            duplicate_games: 0,
-            ply: 0,
-        }
+fn print_game(reader: &mut DatasetReader, tokenizer: &Tokenizer, game: usize) {
+    match reader.read_game(game).and_then(|tokens| decoder::decode_game(&tokens, tokenizer)) {
+        Ok(decoded) => println!("{}", decoded.pgn()),
+        Err(e) => println!("Error: game {}: {}", game, e),
     }
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let command = match cli.command {
+        Some(command) => command,
+        None => return build(cli.build.expect("Build arguments are required without a command")),
+    };
+    match command {
+        Command::Inspect { dataset, context_length } => {
+            let (tokenizer, mut reader) = open_dataset(&dataset);
+            match reader.summary(&tokenizer, context_length) {
+                Ok(summary) => println!("{}", serde_json::to_string_pretty(&summary).unwrap()),
+                Err(e) => println!("Error: {}", e),
+            }
+        },
+        Command::Sample { dataset, n, seed } => {
+            let (tokenizer, mut reader) = open_dataset(&dataset);
+            for game in inspect::sample_games(reader.len(), n, seed) {
+                print_game(&mut reader, &tokenizer, game);
+            }
+        },
+        Command::Show { dataset, game } => {
+            let (tokenizer, mut reader) = open_dataset(&dataset);
+            print_game(&mut reader, &tokenizer, game);
+        },
+        Command::Verify { dataset } => {
+            let (tokenizer, mut reader) = open_dataset(&dataset);
+            let errors = match reader.verify(&tokenizer) {
+                Ok(errors) => errors,
+                Err(e) => panic!("Unable to read the dataset: {}", e),
+            };
+            for error in &errors {
+                println!("Error: game {}: {}", error.game, error.message);
+            }
+            println!("Verified {} games, {} errors", reader.len(), errors.len());
+            if !errors.is_empty() {
+                std::process::exit(1);
+            }
+        },
+    }
+}
 
-    let pool = rayon::ThreadPoolBuilder::new().num_threads(args.n_workers as usize).build().unwrap();
+fn build(args: BuildArgs) {
 
-    let mut tokenizer = tokenizer::Tokenizer::new();
+    let mut tokenizer = tokenizer::Tokenizer::with_scheme(args.scheme.create());
     tokenizer.create_token_map();
+    if let Some(frozen_vocab) = &args.frozen_vocab {
+        let frozen = Tokenizer::load(frozen_vocab).expect("Unable to load the frozen vocabulary");
+        if let Err(e) = tokenizer.check_compatible(&frozen) {
+            panic!("Generated vocabulary differs from {}: {}", frozen_vocab, e);
+        }
+    }
+    tokenizer.set_unknown_outcome_token(args.keep_unknown_outcomes);
+    tokenizer.set_setup_tokens(args.setup_tokens);
+    if let Some(variants) = &args.enable_variants {
+        tokenizer.set_variants(variants);
+    }
+    if let Some(elo_buckets) = &args.elo_buckets {
+        tokenizer.set_elo_buckets(elo_buckets).expect("Invalid --elo-buckets");
+    }
     tokenizer.save(&args.encoder_output);
-    let tokenizer = Arc::new(tokenizer);
 
-    let stats = Arc::new(Mutex::new(Stats::new()));
+    let filter = match args.filter_config().and_then(GameFilter::new) {
+        Ok(filter) => filter,
+        Err(e) => panic!("Invalid game filters: {}", e),
+    };
 
-    let hash_collection = Arc::new(Mutex::new(HashSet::<String>::new()));
-    let global_game_idx = Arc::new(Mutex::new(u64::MIN));
+    let manifest_path = args.manifest.clone().unwrap_or(format!("{}.manifest.json", &args.output));
+    let dedup_path = format!("{}.dedup", &manifest_path);
 
-    let game_indexes_file = Arc::new(Mutex::new(fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(&args.index_output).expect("Error occured while creating index output file")));
-
-    match utils::read_urls_from_input_json(args.input) {
-        Ok(urls) => {            
-            let file = Arc::new(Mutex::new(fs::OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(&args.output).expect("Error occured while creating output file")));
-
-            for url in urls {
-                let file = Arc::clone(&file);
-                let game_indexes_file = Arc::clone(&game_indexes_file);
-                let tokenizer = Arc::clone(&tokenizer);
-                let stats = Arc::clone(&stats);
-                let hash_collection = Arc::clone(&hash_collection);
-                let global_game_idx = Arc::clone(&global_game_idx);
-
-                pool.install(move || {
-                    match pgn_reader::download_bytes_from_url(url.clone()) {
-                        Ok(content) => {
-                            match pgn_reader::pgn_string_from_bytes(url.clone(), content) {
-                                Ok(pgn_string) => {
-                                    let mut visitor = Visitor::new(&tokenizer, hash_collection);
-                                    match pgn_parser::visit_games_from_pgn_string(pgn_string, &mut visitor) {
-                                        Ok(_) => {
-                                            match file.lock() {
-                                                Ok(mut file) => {
-                                                    match file.write_all(&visitor.output) {
-                                                        Ok(_) => {
-                                                            match global_game_idx.lock() {
-                                                                Ok(mut global_game_idx) => {                                                                    
-                                                                    match game_indexes_file.lock() {
-                                                                        Ok(mut game_indexes_file) => {
-                                                                            let mut indexes = Vec::<u8>::new();
-                                                                            for idx in visitor.game_indexes {
-                                                                                indexes.extend((idx + *global_game_idx).to_be_bytes());
-                                                                            }
-                                                                            match game_indexes_file.write_all(&indexes) {
-                                                                                Ok(_) => *global_game_idx += visitor.output.len() as u64,
-                                                                                Err(e) => panic!("Unable to write to file, {}, Error: {}", &url, e),
-                                                                            }
-                                                                        },
-                                                                        Err(e) => panic!("Unable to lock game_indexes_file, {}, Error: {}", &url, e),
-                                                                    }
-                                                                },
-                                                                Err(e) => panic!("Unable to lock global_game_idx, {}, Error: {}", &url, e),
-                                                            }
-
-                                                            match stats.lock() {
-                                                                Ok(mut stats) => {
-                                                                    stats.white_winning_games += visitor.white_winning_games;
-                                                                    stats.black_winning_games += visitor.black_winning_games;
-                                                                    stats.draw_games += visitor.draw_games;
-                                                                    stats.duplicate_games += visitor.duplicate_games;
-                                                                    stats.ply += visitor.ply;
-                                                                    let total_games = visitor.white_winning_games + visitor.black_winning_games + visitor.draw_games;
-                                                                    println!("Games: {:0width$}, Dup games: {:0width$}, Ply: {:0width$} - {}", total_games, visitor.duplicate_games, visitor.ply, url, width=15);
-                                                                },
-                                                                Err(e) => println!("Error: {}", e),
-                                                            }
-                                                        },
-                                                        Err(e) => panic!("Unable to write to file, {}, Error: {}", &url, e),
-                                                    }
-                                                },
-                                                Err(e) => panic!("Unable to lock file, {}, Error: {}", &url, e),
-                                            }
-                                        },
-                                        Err(e) => println!("Error: {}", e),
-                                    }
-                                },
-                                Err(e) => {println!("Error: {} - {}", &url, e)},
-                            };        
-                        },
-                        Err(e) => println!("Error: {} - {}", &url, e),
-                    };
-                });
+    let split_config = args.split_ratios.as_ref().map(|ratios| SplitConfig::parse(ratios, args.split_seed).expect("Invalid --split-ratios"));
+    // Split builds write every split next to the requested paths, e.g. <output>.train and <index-output>.train
+    let output_paths: Vec<(&'static str, String, String, Option<String>)> = match split_config {
+        Some(_) => Split::ALL.iter().map(|split| (
+            split.name(),
+            format!("{}.{}", &args.output, split.name()),
+            format!("{}.{}", &args.index_output, split.name()),
+            args.mask_output.as_ref().map(|mask_output| format!("{}.{}", mask_output, split.name())),
+        )).collect(),
+        None => Vec::from([("all", args.output.clone(), args.index_output.clone(), args.mask_output.clone())]),
+    };
+
+    let mut output_files: Vec<OutputFiles> = output_paths.iter()
+        .map(|(name, path, index_path, mask_path)| OutputFiles::open(name, path, index_path, mask_path.as_deref(), &tokenizer).expect("Error occured while opening output files"))
+        .collect();
+
+    let dedup_config = DedupConfig { memory_limit: args.dedup_memory_limit, spill_dir: args.dedup_spill_dir.clone(), ..Default::default() };
+    let dedup = DedupStore::new(&dedup_config).expect("Unable to create the dedup store");
+    let (mut manifest, mut dedup_log) = if args.resume {
+        let manifest = BuildManifest::load(&manifest_path).expect("Unable to load build manifest");
+        if manifest.split != split_config {
+            panic!("Split settings differ from the ones recorded in the build manifest");
+        }
+
+        for output_files in output_files.iter_mut() {
+            let (tokens_length, index_length) = match manifest.output(output_files.name) {
+                Some(output) => (output.tokens_length, output.index_length),
+                None => panic!("Output {} is missing from the build manifest", output_files.name),
+            };
+            let mask_length = output_files.expected_mask_len(tokens_length);
+            if output_files.global_game_idx < tokens_length || output_files.index_len < index_length || output_files.mask_len < mask_length {
+                panic!("Output files are shorter than recorded in the build manifest");
             }
-        },
+            if output_files.global_game_idx > tokens_length || output_files.index_len > index_length || output_files.mask_len > mask_length {
+                println!("Truncating {} bytes of unfinished output - {}", output_files.global_game_idx - tokens_length, output_files.name);
+                output_files.truncate(tokens_length, index_length).expect("Unable to truncate output files");
+            }
+        }
+        // Fingerprints appended after the last completed source are dropped
+        let dedup_log = DedupLog::open(&dedup_path, manifest.dedup_length).expect("Unable to open the dedup file");
+        dedup.load(&dedup_path, None).expect("Unable to load the dedup file");
+        (manifest, dedup_log)
+    } else {
+        if output_files.iter().any(|output_files| output_files.global_game_idx > 0 || output_files.index_len > 0 || output_files.mask_len > 0) {
+            panic!("Output files already contain data, pass --resume to continue the build");
+        }
+        let names: Vec<&str> = output_files.iter().map(|output_files| output_files.name).collect();
+        let mut manifest = BuildManifest::new(&names, split_config.clone());
+        manifest.report_length = args.reject_report.as_ref().map(|_| 0);
+        // Saved before any source is dispatched, so a build killed during its first source can be resumed
+        manifest.save(&manifest_path).expect("Unable to save the build manifest");
+        (manifest, DedupLog::open(&dedup_path, Some(0)).expect("Unable to open the dedup file"))
+    };
+    for path in args.dedup_against.iter().flatten() {
+        if fs::metadata(path).is_err() {
+            panic!("Dedup file {} does not exist", path);
+        }
+        let count = dedup.load(path, None).expect("Unable to load the dedup file");
+        println!("Loaded {} fingerprints of an earlier build - {}", count, path);
+    }
+
+    // Records appended after the last completed source are dropped like the fingerprints
+    let mut report = args.reject_report.as_ref().map(|path| {
+        let length = match args.resume {
+            true => manifest.report_length,
+            false => Some(0),
+        };
+        RejectionLog::open(path, length).expect("Unable to open the rejection report")
+    });
+    // A resumed build adds its sources to the earlier report
+    let started = Instant::now();
+    let mut stats_report = match &args.stats_output {
+        Some(path) if args.resume && fs::metadata(path).is_ok() => StatsReport::load(path).expect("Unable to load the stats report"),
+        _ => StatsReport::new(&tokenizer, args.context_length),
+    };
+    let earlier_elapsed = stats_report.elapsed_secs;
+    let options = VisitorOptions {
+        deterministic: args.deterministic,
+        write_masks: args.mask_output.is_some(),
+        report: args.reject_report.is_some(),
+        context_length: args.context_length,
+    };
+
+    let urls: Vec<String> = match utils::read_urls_from_input_json(args.input.clone()) {
+        Ok(urls) => urls.into_iter().filter(|url| {
+            let completed = manifest.is_completed(url);
+            if completed {
+                println!("Skipping completed source - {}", url);
+            }
+            !completed
+        }).collect(),
         Err(e) => panic!("Error: {}", e)
+    };
+
+    let mut pipeline_config = PipelineConfig::new(args.n_workers as usize);
+    pipeline_config.chunk_size = args.chunk_size.unwrap_or(pipeline_config.chunk_size);
+    pipeline_config.prefetch_sources = args.prefetch_sources.unwrap_or(pipeline_config.prefetch_sources);
+
+    let mut stats = GameStats::default();
+    // The source being written, every chunk of a source arrives before the next source starts
+    let mut current: Option<SourceState> = None;
+    // Fingerprints removed by rolling back a failed source. Workers of a non-deterministic build dedup while they
+    // parse, so a later source parsed before the rollback may have dropped games as duplicates of these. Such a
+    // source fails too and is left out of the manifest, --resume then parses it again
+    let mut rolled_back: HashSet<Fingerprint> = HashSet::new();
+    let result = pipeline::run(&urls, &pipeline_config, |_, data| {
+        let mut visitor = Visitor::new(&tokenizer, split_config.as_ref(), &filter, &dedup, options);
+        let result = pgn_parser::visit_games_from_bytes(data, &mut visitor);
+        visitor.counts.bytes_in = data.len() as u64;
+        visitor.into_output(result)
+    }, |event| {
+        match event {
+            Event::Chunk { source, offset, result: mut chunk } => {
+                let state = current.get_or_insert_with(|| SourceState::new(&output_files, report.as_ref()));
+                // Written again, so later duplicates of these games are duplicates of this chunk
+                for fingerprint in &chunk.new_hashes {
+                    rolled_back.remove(fingerprint);
+                }
+                if state.error.is_none() && chunk.duplicate_hashes.iter().any(|fingerprint| rolled_back.contains(fingerprint)) {
+                    state.error = Some(anyhow!("Games were dropped as duplicates of a rolled back source"));
+                }
+                if state.error.is_some() {
+                    // The source is rolled back at its end anyway
+                    for fingerprint in &chunk.new_hashes {
+                        dedup.remove(*fingerprint)?;
+                        rolled_back.insert(*fingerprint);
+                    }
+                    return Ok(());
+                }
+                if args.deterministic {
+                    chunk.resolve_duplicates(&dedup, args.context_length)?;
+                }
+                for ((output_files, pending), games) in output_files.iter_mut().zip(&chunk.outputs).zip(state.games.iter_mut()) {
+                    chunk.counts.bytes_out += output_files.append(pending)?;
+                    *games += pending.games;
+                }
+                if let Some(report) = &mut report {
+                    for mut rejection in chunk.rejections {
+                        rejection.url = urls[source].clone();
+                        rejection.game += state.n_games;
+                        rejection.offset += offset;
+                        report.append(&rejection)?;
+                    }
+                }
+                state.n_games += chunk.n_games;
+                state.new_hashes.extend(&chunk.new_hashes);
+                state.counts.merge(&chunk.counts);
+                state.error = chunk.result.err();
+            },
+            Event::SourceEnd { source, result } => {
+                let url = &urls[source];
+                let mut state = current.take().unwrap_or_else(|| SourceState::new(&output_files, report.as_ref()));
+                match state.error.map_or(result, Err) {
+                    Ok(_) => {
+                        let ranges: Vec<OutputRange> = output_files.iter().zip(&state.starts).zip(&state.games).map(|((output_files, (tokens_start, index_start)), games)| OutputRange {
+                            name: String::from(output_files.name),
+                            tokens_start: *tokens_start,
+                            tokens_end: output_files.global_game_idx,
+                            index_start: *index_start,
+                            index_end: output_files.index_len,
+                            games: *games,
+                        }).collect();
+
+                        dedup_log.append(&state.new_hashes)?;
+                        manifest.dedup_length = Some(dedup_log.len());
+                        if let Some(report) = &mut report {
+                            manifest.report_length = Some(report.commit()?);
+                        }
+                        manifest.commit(SourceEntry {
+                            url: url.clone(),
+                            outputs: ranges,
+                            games: state.counts.games,
+                            duplicate_games: state.counts.duplicate_games,
+                            ply: state.counts.ply,
+                        });
+                        manifest.save(&manifest_path)?;
+
+                        state.counts.elapsed_secs = state.started.elapsed().as_secs_f64();
+                        stats.merge(&state.counts);
+                        println!("Games: {:0width$}, Dup games: {:0width$}, Filtered games: {:0width$}, Ply: {:0width$} - {}", state.counts.games, state.counts.duplicate_games, state.counts.filtered_total(), state.counts.ply, url, width=15);
+                        if let Some(path) = &args.stats_output {
+                            stats_report.add_source(url, state.counts);
+                            stats_report.elapsed_secs = earlier_elapsed + started.elapsed().as_secs_f64();
+                            stats_report.save(path)?;
+                        }
+                    },
+                    Err(e) => {
+                        // Roll the source back so a --resume run can retry it from scratch
+                        for (output_files, (tokens_start, index_start)) in output_files.iter_mut().zip(&state.starts) {
+                            output_files.truncate(*tokens_start, *index_start)?;
+                        }
+                        for fingerprint in &state.new_hashes {
+                            dedup.remove(*fingerprint)?;
+                            rolled_back.insert(*fingerprint);
+                        }
+                        if let Some(report) = &mut report {
+                            report.truncate(state.report_start)?;
+                        }
+                        println!("Error: {} - {}", url, e);
+                    },
+                }
+            },
+        }
+        return Ok(());
+    });
+    if let Err(e) = result {
+        panic!("Build stopped, Error: {}", e);
     }
 
-    let stats = stats.lock().unwrap();
-    println!("Total white winning games - {}", stats.white_winning_games);
-    println!("Total black winning games - {}", stats.black_winning_games);
-    println!("Total drawn games         - {}", stats.draw_games);
+    if let Some(npy_output) = &args.npy_output {
+        for (name, path, index_path, _) in &output_paths {
+            let prefix = match split_config {
+                Some(_) => format!("{}.{}", npy_output, name),
+                None => npy_output.clone(),
+            };
+            match npy::export(path, index_path, &tokenizer, &prefix, args.npy_shard_tokens) {
+                Ok(npy_manifest) => println!("Exported {} npy shard(s) - {}.manifest.json", npy_manifest.shards.len(), prefix),
+                Err(e) => println!("Error: npy export failed - {}", e),
+            }
+        }
+    }
+
+    println!("Total white winning games - {}", stats.outcomes.white);
+    println!("Total black winning games - {}", stats.outcomes.black);
+    println!("Total drawn games         - {}", stats.outcomes.draw);
+    if args.keep_unknown_outcomes {
+        println!("Total unknown games       - {}", stats.outcomes.unknown);
+    }
     println!("Total duplicate games     - {}", stats.duplicate_games);
+    println!("Total dedup fingerprints  - {} ({} spilled runs)", dedup.len(), dedup.spilled_runs());
+    println!("Total unsupported setups  - {}", stats.unsupported_setup_games);
+    println!("Total skipped variants    - {}", stats.unsupported_variant_games);
+    for (reason, count) in &stats.filtered_games {
+        println!("Total filtered games      - {} ({})", count, reason);
+    }
     println!("Total plys                - {}", stats.ply);
+    println!("Total long games          - {} (over {} tokens)", stats.long_games, args.context_length);
 
     /*
 
@@ -0,0 +1,153 @@
+use std::fs;
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::split::SplitConfig;
+
+/*
+
+The build manifest records every source that was completely written to the
+output files, together with the byte ranges it occupies in the token and index
+data (relative to the end of the dataset headers) of every output. There is a
+single output when the build is not split, one per split otherwise.
+
+The committed `tokens_length` / `index_length` of every output are the data
+lengths after the last completed source, anything past them in the output
+files is a torn tail left by an interrupted build and is truncated on --resume.
+The dedup file is cut back to `dedup_length` fingerprints the same way, the
+rejection report to `report_length` bytes.
+
+A build saves its empty manifest before the first source is dispatched, so a
+build interrupted during its first source is resumed from empty outputs.
+
+*/
+
+pub const MANIFEST_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputRange {
+    pub name: String,
+    pub tokens_start: u64,
+    pub tokens_end: u64,
+    pub index_start: u64,
+    pub index_end: u64,
+    pub games: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceEntry {
+    pub url: String,
+    pub outputs: Vec<OutputRange>,
+    pub games: u64,
+    pub duplicate_games: u64,
+    pub ply: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputLength {
+    pub name: String,
+    pub tokens_length: u64,
+    pub index_length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BuildManifest {
+    pub version: u32,
+    pub split: Option<SplitConfig>,
+    pub outputs: Vec<OutputLength>,
+    pub sources: Vec<SourceEntry>,
+    /// Fingerprints in the dedup file after the last completed source, see dedup.rs. None keeps the whole file
+    #[serde(default)]
+    pub dedup_length: Option<u64>,
+    /// Bytes in the rejection report after the last completed source, see rejection.rs. None without a report
+    #[serde(default)]
+    pub report_length: Option<u64>,
+}
+
+impl BuildManifest {
+    pub fn new(output_names: &[&str], split: Option<SplitConfig>) -> BuildManifest {
+        return BuildManifest {
+            version: MANIFEST_VERSION,
+            split,
+            outputs: output_names.iter().map(|name| OutputLength { name: String::from(*name), tokens_length: 0, index_length: 0 }).collect(),
+            sources: Vec::new(),
+            dedup_length: Some(0),
+            report_length: None,
+        };
+    }
+
+    pub fn load(path: &str) -> Result<BuildManifest, Error> {
+        let manifest: BuildManifest = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(anyhow!("Unsupported manifest version {}", manifest.version));
+        }
+        return Ok(manifest);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        return write_atomically(path, serde_json::to_string_pretty(self)?.as_bytes());
+    }
+
+    pub fn is_completed(&self, url: &str) -> bool {
+        return self.sources.iter().any(|source| source.url == url);
+    }
+
+    pub fn output(&self, name: &str) -> Option<&OutputLength> {
+        return self.outputs.iter().find(|output| output.name == name);
+    }
+
+    pub fn commit(&mut self, source: SourceEntry) {
+        for range in &source.outputs {
+            if let Some(output) = self.outputs.iter_mut().find(|output| output.name == range.name) {
+                output.tokens_length = range.tokens_end;
+                output.index_length = range.index_end;
+            }
+        }
+        self.sources.push(source);
+    }
+}
+
+// Writes to a temporary file first so a crash never leaves a half written file behind
+pub fn write_atomically(path: &str, bytes: &[u8]) -> Result<(), Error> {
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, path)?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let dir = TempDir::new("manifest").unwrap();
+        let path = dir.path().join("out.manifest.json");
+        let path = path.to_str().unwrap();
+
+        let mut manifest = BuildManifest::new(&["train", "val", "test"], Some(SplitConfig::parse("8,1,1", 7).unwrap()));
+        manifest.commit(SourceEntry {
+            url: String::from("a.pgn"),
+            outputs: Vec::from([
+                OutputRange { name: String::from("train"), tokens_start: 0, tokens_end: 40, index_start: 0, index_end: 16, games: 2 },
+                OutputRange { name: String::from("test"), tokens_start: 0, tokens_end: 10, index_start: 0, index_end: 8, games: 1 },
+            ]),
+            games: 3,
+            duplicate_games: 1,
+            ply: 20,
+        });
+        manifest.save(path).unwrap();
+
+        let loaded = BuildManifest::load(path).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.output("train").unwrap().tokens_length, 40);
+        assert_eq!(loaded.output("train").unwrap().index_length, 16);
+        assert_eq!(loaded.output("val").unwrap().tokens_length, 0);
+        assert_eq!(loaded.output("test").unwrap().index_length, 8);
+        assert!(loaded.is_completed("a.pgn"));
+        assert!(!loaded.is_completed("b.pgn"));
+    }
+}
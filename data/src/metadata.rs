@@ -0,0 +1,191 @@
+use std::time::Duration;
+
+use shakmaty::Color;
+
+/*
+
+Game level and move level data found in a PGN besides the moves themselves.
+
+GameMetadata holds every header tag of a game in the order they appear, plus
+the comment placed before the first move. MoveAnnotation holds what follows a
+mainline move: the comment text (with the embedded [%...] commands removed),
+the NAGs (including the ones written as suffixes like "!?"), and the parsed
+[%clk h:mm:ss] and [%eval ...] commands as written by lichess and chessbase.
+
+*/
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameMetadata {
+    pub tags: Vec<(String, String)>,
+    pub comment: Option<String>,
+}
+
+impl GameMetadata {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+    }
+
+    /// The WhiteElo / BlackElo tag of `color`, None when missing or not a number (e.g. "?")
+    pub fn elo(&self, color: Color) -> Option<u32> {
+        let key = match color {
+            Color::White => "WhiteElo",
+            Color::Black => "BlackElo",
+        };
+        return self.get(key).and_then(|elo| elo.trim().parse::<u32>().ok());
+    }
+
+    pub fn insert(&mut self, key: &str, value: &str) {
+        match self.tags.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = String::from(value),
+            None => self.tags.push((String::from(key), String::from(value))),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tags.clear();
+        self.comment = None;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Eval {
+    // Evaluation from white's point of view
+    Centipawns(i32),
+    // Moves until mate, negative when black mates
+    Mate(i32),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MoveAnnotation {
+    pub comment: Option<String>,
+    pub nags: Vec<u8>,
+    pub clock: Option<Duration>,
+    pub eval: Option<Eval>,
+}
+
+impl MoveAnnotation {
+    pub fn is_empty(&self) -> bool {
+        return self.comment.is_none() && self.nags.is_empty() && self.clock.is_none() && self.eval.is_none();
+    }
+
+    /// Adds a raw PGN comment, extracting the [%clk] and [%eval] commands
+    pub fn add_comment(&mut self, comment: &str) {
+        let (text, commands) = split_commands(comment);
+        for (name, value) in commands {
+            match name {
+                "clk" => self.clock = parse_clock(value).or(self.clock),
+                "eval" => self.eval = parse_eval(value).or(self.eval),
+                _ => {},
+            }
+        }
+        append_comment(&mut self.comment, &text);
+    }
+}
+
+pub fn append_comment(comment: &mut Option<String>, text: &str) {
+    let text = text.trim();
+    if text.is_empty() {
+        return;
+    }
+    match comment {
+        Some(comment) => {
+            comment.push(' ');
+            comment.push_str(text);
+        },
+        None => *comment = Some(String::from(text)),
+    }
+}
+
+// Splits "text [%clk 0:01:00] more" into the remaining text and the (name, value) commands
+fn split_commands(comment: &str) -> (String, Vec<(&str, &str)>) {
+    let mut text = String::new();
+    let mut commands = Vec::new();
+    let mut rest = comment;
+    while let Some(start) = rest.find("[%") {
+        let end = match rest[start..].find(']') {
+            Some(end) => start + end,
+            None => break,
+        };
+        text.push_str(&rest[..start]);
+        let command = rest[start + 2..end].trim();
+        match command.split_once(char::is_whitespace) {
+            Some((name, value)) => commands.push((name, value.trim())),
+            None => commands.push((command, "")),
+        }
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    return (text.split_whitespace().collect::<Vec<_>>().join(" "), commands);
+}
+
+// "1:02:03", "2:03" or "0:00:05.3"
+pub fn parse_clock(value: &str) -> Option<Duration> {
+    let mut seconds = 0.0;
+    for part in value.trim().split(':') {
+        let part = part.parse::<f64>().ok()?;
+        if !part.is_finite() || part < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + part;
+    }
+    return Some(Duration::from_secs_f64(seconds));
+}
+
+// "0.17", "-1.5" in pawns or "#3", "#-2" for mates
+pub fn parse_eval(value: &str) -> Option<Eval> {
+    let value = value.trim();
+    // Depth may follow the eval, e.g. "0.17,20"
+    let value = value.split(',').next()?;
+    if let Some(mate) = value.strip_prefix('#') {
+        return mate.parse::<i32>().ok().map(Eval::Mate);
+    }
+    let pawns = value.parse::<f64>().ok()?;
+    if !pawns.is_finite() {
+        return None;
+    }
+    return Some(Eval::Centipawns((pawns * 100.0).round() as i32));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_metadata() {
+        let mut metadata = GameMetadata::default();
+        metadata.insert("Event", "A");
+        metadata.insert("WhiteElo", "2000");
+        metadata.insert("Event", "B");
+        assert_eq!(metadata.get("Event"), Some("B"));
+        assert_eq!(metadata.get("WhiteElo"), Some("2000"));
+        assert_eq!(metadata.get("BlackElo"), None);
+        assert_eq!(metadata.elo(Color::White), Some(2000));
+        assert_eq!(metadata.elo(Color::Black), None);
+        assert_eq!(metadata.tags.len(), 2);
+    }
+
+    #[test]
+    fn move_annotation() {
+        let mut annotation = MoveAnnotation::default();
+        assert!(annotation.is_empty());
+        annotation.add_comment(" [%eval 0.17] [%clk 0:03:00] ");
+        assert_eq!(annotation.eval, Some(Eval::Centipawns(17)));
+        assert_eq!(annotation.clock, Some(Duration::from_secs(180)));
+        assert_eq!(annotation.comment, None);
+
+        annotation.add_comment("Best  move [%eval #-3,25] here");
+        annotation.add_comment("really");
+        assert_eq!(annotation.eval, Some(Eval::Mate(-3)));
+        assert_eq!(annotation.comment.as_deref(), Some("Best move here really"));
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(parse_clock("1:02:03"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_clock("0:00:05.5"), Some(Duration::from_millis(5500)));
+        assert_eq!(parse_clock("x"), None);
+        assert_eq!(parse_eval("-1.505"), Some(Eval::Centipawns(-151)));
+        assert_eq!(parse_eval("#4"), Some(Eval::Mate(4)));
+        assert_eq!(parse_eval("mate"), None);
+    }
+}
@@ -0,0 +1,534 @@
+use std::{collections::BTreeSet, fs};
+
+use anyhow::{anyhow, Error};
+use safetensors::{Dtype, SafeTensors};
+
+use crate::tokenizer::{Token, Tokenizer};
+
+/*
+
+CPU inference for the decoder only Transformer of model.py, so a model can be
+run without Python / torch. Weights are read from a safetensors file holding
+the state dict of the torch model, written by export.py:
+
+embedding.weight                                   [n_vocab, n_embd]
+positional_embedding.weight                        [block_size, n_embd]
+blocks.{i}.ln1.{weight,bias}                       [n_embd]
+blocks.{i}.self_attention.heads.{h}.query.weight   [head_size, n_embd] (also key, value)
+blocks.{i}.self_attention.projection.{weight,bias} [n_embd, n_embd]
+blocks.{i}.ln2.{weight,bias}                       [n_embd]
+blocks.{i}.feedforward.net.0.{weight,bias}         [4 * n_embd, n_embd]
+blocks.{i}.feedforward.net.2.{weight,bias}         [n_embd, 4 * n_embd]
+ln.{weight,bias}                                   [n_embd]
+lm_head.{weight,bias}                              [n_vocab, n_embd]
+
+The sizes (ModelConfig) are taken from the shapes and the number of blocks /
+heads found in the file. f32, f16 and bf16 weights are accepted, everything is
+computed in f32. Dropout is skipped as in model.eval().
+
+Tokens are fed one at a time, the keys and values of every position are kept
+in a KvCache so a token costs one pass over the weights instead of a pass over
+the whole context. Like Transformer.generate, next_logits only looks at the
+last block_size tokens of the context: once the context outgrows the block,
+the cache is rebuilt from the last block_size tokens.
+
+*/
+
+const LAYER_NORM_EPS: f32 = 1e-5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelConfig {
+    pub block_size: usize,
+    pub n_vocab: usize,
+    pub n_embd: usize,
+    pub n_heads: usize,
+    pub n_blocks: usize,
+}
+
+impl ModelConfig {
+    pub fn head_size(&self) -> usize {
+        return self.n_embd / self.n_heads;
+    }
+}
+
+struct Linear {
+    // [n_out, n_in], row major as in torch
+    weight: Vec<f32>,
+    bias: Option<Vec<f32>>,
+    n_in: usize,
+}
+
+impl Linear {
+    fn forward(&self, x: &[f32]) -> Vec<f32> {
+        let rows = self.weight.chunks_exact(self.n_in);
+        return match &self.bias {
+            Some(bias) => rows.zip(bias).map(|(row, bias)| dot(row, x) + bias).collect(),
+            None => rows.map(|row| dot(row, x)).collect(),
+        };
+    }
+}
+
+struct LayerNorm {
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+}
+
+impl LayerNorm {
+    fn forward(&self, x: &[f32]) -> Vec<f32> {
+        let n = x.len() as f32;
+        let mean = x.iter().sum::<f32>() / n;
+        let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+        let scale = 1.0 / (var + LAYER_NORM_EPS).sqrt();
+        return x.iter().zip(self.weight.iter().zip(&self.bias)).map(|(v, (w, b))| (v - mean) * scale * w + b).collect();
+    }
+}
+
+struct Block {
+    ln1: LayerNorm,
+    // The heads stacked into one [n_embd, n_embd] matrix, head h owns rows h * head_size..(h + 1) * head_size
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    projection: Linear,
+    ln2: LayerNorm,
+    fc1: Linear,
+    fc2: Linear,
+}
+
+/// Keys and values of the tokens fed so far, one buffer of [len, n_embd] per block
+pub struct KvCache {
+    tokens: Vec<Token>,
+    keys: Vec<Vec<f32>>,
+    values: Vec<Vec<f32>>,
+}
+
+impl KvCache {
+    pub fn len(&self) -> usize {
+        return self.tokens.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.tokens.is_empty();
+    }
+
+    pub fn tokens(&self) -> &[Token] {
+        return &self.tokens;
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.keys.iter_mut().for_each(|keys| keys.clear());
+        self.values.iter_mut().for_each(|values| values.clear());
+    }
+}
+
+pub struct Transformer {
+    config: ModelConfig,
+    embedding: Vec<f32>,
+    positional_embedding: Vec<f32>,
+    blocks: Vec<Block>,
+    ln: LayerNorm,
+    lm_head: Linear,
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    return a.iter().zip(b).map(|(a, b)| a * b).sum();
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as f32;
+    return match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    };
+}
+
+struct Weights<'a> {
+    tensors: SafeTensors<'a>,
+}
+
+impl<'a> Weights<'a> {
+    fn shape(&self, name: &str) -> Result<Vec<usize>, Error> {
+        return match self.tensors.tensor(name) {
+            Ok(tensor) => Ok(tensor.shape().to_vec()),
+            Err(_) => Err(anyhow!("Missing tensor {}", name)),
+        };
+    }
+
+    fn get(&self, name: &str, shape: &[usize]) -> Result<Vec<f32>, Error> {
+        let tensor = match self.tensors.tensor(name) {
+            Ok(tensor) => tensor,
+            Err(_) => return Err(anyhow!("Missing tensor {}", name)),
+        };
+        if tensor.shape() != shape {
+            return Err(anyhow!("Tensor {} has shape {:?}, expected {:?}", name, tensor.shape(), shape));
+        }
+        let data = tensor.data();
+        return match tensor.dtype() {
+            Dtype::F32 => Ok(data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()),
+            Dtype::F16 => Ok(data.chunks_exact(2).map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]]))).collect()),
+            Dtype::BF16 => Ok(data.chunks_exact(2).map(|b| f32::from_bits((u16::from_le_bytes([b[0], b[1]]) as u32) << 16)).collect()),
+            dtype => Err(anyhow!("Tensor {} has unsupported dtype {:?}", name, dtype)),
+        };
+    }
+
+    fn linear(&self, name: &str, n_out: usize, n_in: usize, bias: bool) -> Result<Linear, Error> {
+        let weight = self.get(&format!("{}.weight", name), &[n_out, n_in])?;
+        let bias = match bias {
+            true => Some(self.get(&format!("{}.bias", name), &[n_out])?),
+            false => None,
+        };
+        return Ok(Linear { weight, bias, n_in });
+    }
+
+    // The `kind` (query, key or value) weights of every head of a block, stacked in head order
+    fn heads(&self, prefix: &str, kind: &str, config: &ModelConfig) -> Result<Linear, Error> {
+        let mut weight = Vec::new();
+        for h in 0..config.n_heads {
+            weight.extend(self.get(&format!("{}.self_attention.heads.{}.{}.weight", prefix, h, kind), &[config.head_size(), config.n_embd])?);
+        }
+        return Ok(Linear { weight, bias: None, n_in: config.n_embd });
+    }
+
+    fn layer_norm(&self, name: &str, n: usize) -> Result<LayerNorm, Error> {
+        return Ok(LayerNorm {
+            weight: self.get(&format!("{}.weight", name), &[n])?,
+            bias: self.get(&format!("{}.bias", name), &[n])?,
+        });
+    }
+
+    // Number of distinct {i} in names starting with `prefix`{i}.
+    fn count(&self, prefix: &str) -> usize {
+        let indexes: BTreeSet<usize> = self.tensors.names().iter()
+            .filter_map(|name| name.strip_prefix(prefix))
+            .filter_map(|rest| rest.split('.').next())
+            .filter_map(|index| index.parse().ok())
+            .collect();
+        return indexes.len();
+    }
+
+    fn config(&self) -> Result<ModelConfig, Error> {
+        let embedding = self.shape("embedding.weight")?;
+        let positional_embedding = self.shape("positional_embedding.weight")?;
+        if embedding.len() != 2 || positional_embedding.len() != 2 {
+            return Err(anyhow!("Embeddings must be matrices, got {:?} and {:?}", embedding, positional_embedding));
+        }
+        let config = ModelConfig {
+            block_size: positional_embedding[0],
+            n_vocab: embedding[0],
+            n_embd: embedding[1],
+            n_heads: self.count("blocks.0.self_attention.heads."),
+            n_blocks: self.count("blocks."),
+        };
+        if config.n_blocks == 0 || config.n_heads == 0 {
+            return Err(anyhow!("No transformer blocks found"));
+        }
+        if !config.n_embd.is_multiple_of(config.n_heads) {
+            return Err(anyhow!("Embedding size {} is not a multiple of the {} heads", config.n_embd, config.n_heads));
+        }
+        return Ok(config);
+    }
+}
+
+impl Transformer {
+    pub fn load(path: &str) -> Result<Transformer, Error> {
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(anyhow!("Unable to read {}: {}", path, e)),
+        };
+        return Transformer::from_bytes(&bytes);
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Transformer, Error> {
+        let weights = match SafeTensors::deserialize(bytes) {
+            Ok(tensors) => Weights { tensors },
+            Err(e) => return Err(anyhow!("Invalid safetensors file: {}", e)),
+        };
+        let config = weights.config()?;
+        let n_embd = config.n_embd;
+
+        let mut blocks = Vec::new();
+        for i in 0..config.n_blocks {
+            let prefix = format!("blocks.{}", i);
+            blocks.push(Block {
+                ln1: weights.layer_norm(&format!("{}.ln1", prefix), n_embd)?,
+                query: weights.heads(&prefix, "query", &config)?,
+                key: weights.heads(&prefix, "key", &config)?,
+                value: weights.heads(&prefix, "value", &config)?,
+                projection: weights.linear(&format!("{}.self_attention.projection", prefix), n_embd, n_embd, true)?,
+                ln2: weights.layer_norm(&format!("{}.ln2", prefix), n_embd)?,
+                fc1: weights.linear(&format!("{}.feedforward.net.0", prefix), 4 * n_embd, n_embd, true)?,
+                fc2: weights.linear(&format!("{}.feedforward.net.2", prefix), n_embd, 4 * n_embd, true)?,
+            });
+        }
+
+        return Ok(Transformer {
+            config,
+            embedding: weights.get("embedding.weight", &[config.n_vocab, n_embd])?,
+            positional_embedding: weights.get("positional_embedding.weight", &[config.block_size, n_embd])?,
+            blocks,
+            ln: weights.layer_norm("ln", n_embd)?,
+            lm_head: weights.linear("lm_head", config.n_vocab, n_embd, true)?,
+        });
+    }
+
+    pub fn config(&self) -> &ModelConfig {
+        return &self.config;
+    }
+
+    /// Checks that the model was trained with the vocabulary of `tokenizer`
+    pub fn check_tokenizer(&self, tokenizer: &Tokenizer) -> Result<(), Error> {
+        if tokenizer.vocab_size() != self.config.n_vocab {
+            return Err(anyhow!("The model has {} tokens, the vocabulary {}", self.config.n_vocab, tokenizer.vocab_size()));
+        }
+        return Ok(());
+    }
+
+    pub fn new_cache(&self) -> KvCache {
+        return KvCache {
+            tokens: Vec::new(),
+            keys: vec![Vec::new(); self.config.n_blocks],
+            values: vec![Vec::new(); self.config.n_blocks],
+        };
+    }
+
+    /// Feeds `token` after the tokens of `cache`, returns the logits of the token that follows it
+    pub fn forward(&self, cache: &mut KvCache, token: Token) -> Result<Vec<f32>, Error> {
+        let n_embd = self.config.n_embd;
+        let head_size = self.config.head_size();
+        let position = cache.len();
+        if position >= self.config.block_size {
+            return Err(anyhow!("The context is full ({} tokens)", self.config.block_size));
+        }
+        let id = token.value as usize;
+        if id >= self.config.n_vocab {
+            return Err(anyhow!("Token {} is outside of the {} token vocabulary", id, self.config.n_vocab));
+        }
+
+        let mut x: Vec<f32> = self.embedding[id * n_embd..(id + 1) * n_embd].iter()
+            .zip(&self.positional_embedding[position * n_embd..(position + 1) * n_embd])
+            .map(|(a, b)| a + b)
+            .collect();
+        // model.py scales by the embedding size, not the head size
+        let scale = (n_embd as f32).powf(-0.5);
+
+        for (i, block) in self.blocks.iter().enumerate() {
+            let h = block.ln1.forward(&x);
+            let q = block.query.forward(&h);
+            cache.keys[i].extend(block.key.forward(&h));
+            cache.values[i].extend(block.value.forward(&h));
+            let keys = &cache.keys[i];
+            let values = &cache.values[i];
+
+            let mut attention = vec![0.0; n_embd];
+            for head in 0..self.config.n_heads {
+                let range = head * head_size..(head + 1) * head_size;
+                let mut weights: Vec<f32> = (0..=position)
+                    .map(|t| dot(&q[range.clone()], &keys[t * n_embd + range.start..t * n_embd + range.end]) * scale)
+                    .collect();
+                let max = weights.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                weights.iter_mut().for_each(|w| *w = (*w - max).exp());
+                let total: f32 = weights.iter().sum();
+                for (t, w) in weights.iter().enumerate() {
+                    let value = &values[t * n_embd + range.start..t * n_embd + range.end];
+                    for (out, v) in attention[range.clone()].iter_mut().zip(value) {
+                        *out += w / total * v;
+                    }
+                }
+            }
+            for (x, a) in x.iter_mut().zip(block.projection.forward(&attention)) {
+                *x += a;
+            }
+
+            let mut hidden = block.fc1.forward(&block.ln2.forward(&x));
+            hidden.iter_mut().for_each(|v| *v = v.max(0.0));
+            for (x, f) in x.iter_mut().zip(block.fc2.forward(&hidden)) {
+                *x += f;
+            }
+        }
+
+        cache.tokens.push(token);
+        return Ok(self.lm_head.forward(&self.ln.forward(&x)));
+    }
+
+    /// Logits of the token following `context`, computed over its last block_size tokens.
+    /// The cache is reused when it holds a prefix of that window, and rebuilt otherwise
+    pub fn next_logits(&self, cache: &mut KvCache, context: &[Token]) -> Result<Vec<f32>, Error> {
+        if context.is_empty() {
+            return Err(anyhow!("The context is empty"));
+        }
+        let window = &context[context.len().saturating_sub(self.config.block_size)..];
+        if !window.starts_with(cache.tokens()) || cache.len() == window.len() {
+            cache.clear();
+        }
+        let mut logits = Vec::new();
+        for token in &window[cache.len()..] {
+            logits = self.forward(cache, *token)?;
+        }
+        return Ok(logits);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+
+    use safetensors::tensor::TensorView;
+
+    use crate::sampler::Rng;
+
+    use super::*;
+
+    const CONFIG: ModelConfig = ModelConfig { block_size: 6, n_vocab: 11, n_embd: 8, n_heads: 2, n_blocks: 2 };
+
+    // A random state dict with the names and shapes of model.py
+    pub(crate) fn random_state_dict(config: &ModelConfig) -> HashMap<String, (Vec<usize>, Vec<f32>)> {
+        let (n_embd, head_size) = (config.n_embd, config.head_size());
+        let mut shapes = vec![
+            ("embedding.weight".to_string(), vec![config.n_vocab, n_embd]),
+            ("positional_embedding.weight".to_string(), vec![config.block_size, n_embd]),
+            ("ln.weight".to_string(), vec![n_embd]),
+            ("ln.bias".to_string(), vec![n_embd]),
+            ("lm_head.weight".to_string(), vec![config.n_vocab, n_embd]),
+            ("lm_head.bias".to_string(), vec![config.n_vocab]),
+        ];
+        for i in 0..config.n_blocks {
+            for h in 0..config.n_heads {
+                for kind in ["query", "key", "value"] {
+                    shapes.push((format!("blocks.{}.self_attention.heads.{}.{}.weight", i, h, kind), vec![head_size, n_embd]));
+                }
+                shapes.push((format!("blocks.{}.self_attention.heads.{}.tril", i, h), vec![config.block_size, config.block_size]));
+            }
+            for (name, shape) in [
+                ("ln1.weight", vec![n_embd]),
+                ("ln1.bias", vec![n_embd]),
+                ("ln2.weight", vec![n_embd]),
+                ("ln2.bias", vec![n_embd]),
+                ("self_attention.projection.weight", vec![n_embd, n_embd]),
+                ("self_attention.projection.bias", vec![n_embd]),
+                ("feedforward.net.0.weight", vec![4 * n_embd, n_embd]),
+                ("feedforward.net.0.bias", vec![4 * n_embd]),
+                ("feedforward.net.2.weight", vec![n_embd, 4 * n_embd]),
+                ("feedforward.net.2.bias", vec![n_embd]),
+            ] {
+                shapes.push((format!("blocks.{}.{}", i, name), shape));
+            }
+        }
+
+        let mut rng = Rng::new(42);
+        return shapes.into_iter().map(|(name, shape)| {
+            let values = (0..shape.iter().product()).map(|_| rng.next_f64() as f32 * 2.0 - 1.0).collect();
+            (name, (shape, values))
+        }).collect();
+    }
+
+    pub(crate) fn serialize(state_dict: &HashMap<String, (Vec<usize>, Vec<f32>)>) -> Vec<u8> {
+        let bytes: Vec<(String, Vec<usize>, Vec<u8>)> = state_dict.iter()
+            .map(|(name, (shape, values))| (name.clone(), shape.clone(), values.iter().flat_map(|v| v.to_le_bytes()).collect()))
+            .collect();
+        let views = bytes.iter().map(|(name, shape, data)| (name.clone(), TensorView::new(Dtype::F32, shape.clone(), data).unwrap()));
+        return safetensors::serialize(views, &None).unwrap();
+    }
+
+    // Straight port of Transformer.forward over a whole sequence, returns the logits of the last position
+    fn reference(state_dict: &HashMap<String, (Vec<usize>, Vec<f32>)>, tokens: &[usize]) -> Vec<f32> {
+        let w = |name: &str| &state_dict[name].1;
+        let linear = |name: &str, x: &[f32], bias: bool| -> Vec<f32> {
+            let weight = w(&format!("{}.weight", name));
+            let out: Vec<f32> = weight.chunks(x.len()).map(|row| dot(row, x)).collect();
+            return match bias {
+                true => out.iter().zip(w(&format!("{}.bias", name))).map(|(a, b)| a + b).collect(),
+                false => out,
+            };
+        };
+        let layer_norm = |name: &str, x: &[f32]| LayerNorm { weight: w(&format!("{}.weight", name)).clone(), bias: w(&format!("{}.bias", name)).clone() }.forward(x);
+        let n_embd = CONFIG.n_embd;
+
+        let mut xs: Vec<Vec<f32>> = tokens.iter().enumerate()
+            .map(|(t, id)| (0..n_embd).map(|c| w("embedding.weight")[id * n_embd + c] + w("positional_embedding.weight")[t * n_embd + c]).collect())
+            .collect();
+        for i in 0..CONFIG.n_blocks {
+            let hs: Vec<Vec<f32>> = xs.iter().map(|x| layer_norm(&format!("blocks.{}.ln1", i), x)).collect();
+            let mut concat: Vec<Vec<f32>> = vec![Vec::new(); tokens.len()];
+            for h in 0..CONFIG.n_heads {
+                let head = format!("blocks.{}.self_attention.heads.{}", i, h);
+                let q: Vec<Vec<f32>> = hs.iter().map(|x| linear(&format!("{}.query", head), x, false)).collect();
+                let k: Vec<Vec<f32>> = hs.iter().map(|x| linear(&format!("{}.key", head), x, false)).collect();
+                let v: Vec<Vec<f32>> = hs.iter().map(|x| linear(&format!("{}.value", head), x, false)).collect();
+                for t in 0..tokens.len() {
+                    let scores: Vec<f32> = (0..=t).map(|s| (dot(&q[t], &k[s]) * (n_embd as f32).powf(-0.5)).exp()).collect();
+                    let total: f32 = scores.iter().sum();
+                    concat[t].extend((0..CONFIG.head_size()).map(|c| (0..=t).map(|s| scores[s] / total * v[s][c]).sum::<f32>()));
+                }
+            }
+            for (x, a) in xs.iter_mut().zip(&concat) {
+                let projected = linear(&format!("blocks.{}.self_attention.projection", i), a, true);
+                x.iter_mut().zip(projected).for_each(|(x, p)| *x += p);
+                let hidden: Vec<f32> = linear(&format!("blocks.{}.feedforward.net.0", i), &layer_norm(&format!("blocks.{}.ln2", i), x), true).iter().map(|v| v.max(0.0)).collect();
+                let out = linear(&format!("blocks.{}.feedforward.net.2", i), &hidden, true);
+                x.iter_mut().zip(out).for_each(|(x, f)| *x += f);
+            }
+        }
+        return linear("lm_head", &layer_norm("ln", xs.last().unwrap()), true);
+    }
+
+    fn state_dict() -> HashMap<String, (Vec<usize>, Vec<f32>)> {
+        return random_state_dict(&CONFIG);
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn load() {
+        let model = Transformer::from_bytes(&serialize(&state_dict())).unwrap();
+        assert_eq!(*model.config(), CONFIG);
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        assert!(model.check_tokenizer(&tokenizer).is_err());
+
+        let mut missing = state_dict();
+        missing.remove("blocks.1.ln2.bias");
+        assert!(Transformer::from_bytes(&serialize(&missing)).is_err());
+        assert!(Transformer::from_bytes(b"not a model").is_err());
+
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+    }
+
+    #[test]
+    fn kv_cache() {
+        let state_dict = state_dict();
+        let model = Transformer::from_bytes(&serialize(&state_dict)).unwrap();
+        let ids = [3, 1, 4, 1, 5, 9, 2, 6, 5];
+        let tokens: Vec<Token> = ids.iter().map(|id| Token { value: *id as u16 }).collect();
+
+        // Token by token through the cache
+        let mut cache = model.new_cache();
+        for t in 0..CONFIG.block_size {
+            let logits = model.forward(&mut cache, tokens[t]).unwrap();
+            assert_close(&logits, &reference(&state_dict, &ids[..=t]));
+        }
+        assert!(model.forward(&mut cache, tokens[0]).is_err());
+
+        // Past the block only the last block_size tokens are looked at
+        let mut cache = model.new_cache();
+        for t in 1..=tokens.len() {
+            let logits = model.next_logits(&mut cache, &tokens[..t]).unwrap();
+            let start = t.saturating_sub(CONFIG.block_size);
+            assert_close(&logits, &reference(&state_dict, &ids[start..t]));
+            assert_eq!(cache.tokens(), &tokens[start..t]);
+        }
+        assert!(model.next_logits(&mut cache, &[]).is_err());
+        assert!(model.next_logits(&mut cache, &[Token { value: 11 }]).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::{fs, io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write}, path::Path};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::{dataset::{self, DatasetHeader, FileKind}, manifest, tokenizer::Tokenizer};
+
+/*
+
+Exports a built dataset as native little endian NumPy arrays, so train.py can
+np.load / np.memmap them without any conversion step.
+
+<prefix>.tokens[.NNNNN].npy - uint16 tokens ('<u2')
+<prefix>.index[.NNNNN].npy  - uint64 offset of every game, in tokens, relative to the start of its shard ('<u8')
+<prefix>.manifest.json      - shard list for memmap based loaders
+
+Games are never split across shards, a shard is closed before a game would
+push it past the requested number of tokens.
+
+*/
+
+// Fixed header size, so the shape can be patched in place once the array length is known
+const NPY_HEADER_SIZE: usize = 128;
+
+pub const NPY_MANIFEST_FORMAT: &str = "chessgpt-npy";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NpyShard {
+    pub tokens: String,
+    pub index: String,
+    pub n_tokens: u64,
+    pub n_games: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NpyManifest {
+    pub format: String,
+    pub version: u32,
+    pub dtype: String,
+    pub index_dtype: String,
+    pub vocab_size: u32,
+    pub fingerprint: String,
+    pub shards: Vec<NpyShard>,
+}
+
+pub fn npy_header(descr: &str, len: u64) -> Result<Vec<u8>, Error> {
+    let dict = format!("{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}", descr, len);
+    let mut header = Vec::from(*b"\x93NUMPY\x01\x00");
+    header.extend(((NPY_HEADER_SIZE - 10) as u16).to_le_bytes());
+    header.extend(dict.as_bytes());
+    if header.len() >= NPY_HEADER_SIZE {
+        return Err(anyhow!("npy header does not fit in {} bytes", NPY_HEADER_SIZE));
+    }
+    header.resize(NPY_HEADER_SIZE - 1, b' ');
+    header.push(b'\n');
+    return Ok(header);
+}
+
+pub struct NpyWriter {
+    file: BufWriter<fs::File>,
+    descr: &'static str,
+    len: u64,
+}
+
+impl NpyWriter {
+    pub fn create(path: &str, descr: &'static str) -> Result<NpyWriter, Error> {
+        let mut file = BufWriter::new(fs::File::create(path)?);
+        file.write_all(&npy_header(descr, 0)?)?;
+        return Ok(NpyWriter { file, descr, len: 0 });
+    }
+
+    pub fn write_u16(&mut self, values: &[u16]) -> Result<(), Error> {
+        for value in values {
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.len += values.len() as u64;
+        return Ok(());
+    }
+
+    pub fn write_u64(&mut self, values: &[u64]) -> Result<(), Error> {
+        for value in values {
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.len += values.len() as u64;
+        return Ok(());
+    }
+
+    pub fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+
+    pub fn finish(self) -> Result<u64, Error> {
+        let mut file = self.file.into_inner().map_err(|e| anyhow!("Unable to flush npy file: {}", e))?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&npy_header(self.descr, self.len)?)?;
+        file.sync_all()?;
+        return Ok(self.len);
+    }
+}
+
+struct ShardWriter {
+    prefix: String,
+    shard_tokens: Option<u64>,
+    tokens: Option<NpyWriter>,
+    index: Option<NpyWriter>,
+    shards: Vec<NpyShard>,
+}
+
+impl ShardWriter {
+    fn shard_paths(&self, shard: usize) -> (String, String) {
+        return match self.shard_tokens {
+            Some(_) => (format!("{}.tokens.{:05}.npy", self.prefix, shard), format!("{}.index.{:05}.npy", self.prefix, shard)),
+            None => (format!("{}.tokens.npy", self.prefix), format!("{}.index.npy", self.prefix)),
+        };
+    }
+
+    fn open_shard(&mut self) -> Result<(), Error> {
+        let (tokens_path, index_path) = self.shard_paths(self.shards.len());
+        self.tokens = Some(NpyWriter::create(&tokens_path, "<u2")?);
+        self.index = Some(NpyWriter::create(&index_path, "<u8")?);
+        self.shards.push(NpyShard { tokens: file_name(&tokens_path), index: file_name(&index_path), n_tokens: 0, n_games: 0 });
+        return Ok(());
+    }
+
+    fn close_shard(&mut self) -> Result<(), Error> {
+        if let (Some(tokens), Some(index), Some(shard)) = (self.tokens.take(), self.index.take(), self.shards.last_mut()) {
+            shard.n_tokens = tokens.finish()?;
+            shard.n_games = index.finish()?;
+        }
+        return Ok(());
+    }
+
+    fn write_game(&mut self, game: &[u16]) -> Result<(), Error> {
+        let full = match (&self.tokens, self.shard_tokens) {
+            (Some(tokens), Some(shard_tokens)) => !tokens.is_empty() && tokens.len() + game.len() as u64 > shard_tokens,
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if full {
+            self.close_shard()?;
+            self.open_shard()?;
+        }
+
+        if let (Some(tokens), Some(index)) = (self.tokens.as_mut(), self.index.as_mut()) {
+            index.write_u64(&[tokens.len()])?;
+            tokens.write_u16(game)?;
+        }
+        return Ok(());
+    }
+}
+
+fn file_name(path: &str) -> String {
+    return Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or(String::from(path));
+}
+
+pub fn export(tokens_path: &str, index_path: &str, tokenizer: &Tokenizer, prefix: &str, shard_tokens: Option<u64>) -> Result<NpyManifest, Error> {
+    let mut tokens_file = fs::File::open(tokens_path)?;
+    DatasetHeader::read(&mut tokens_file)?.validate(FileKind::Tokens, tokenizer)?;
+    let tokens_len = tokens_file.metadata()?.len() - dataset::HEADER_SIZE as u64;
+    let mut tokens_file = BufReader::new(tokens_file);
+
+    let mut index_file = fs::File::open(index_path)?;
+    DatasetHeader::read(&mut index_file)?.validate(FileKind::Index, tokenizer)?;
+    let n_games = (index_file.metadata()?.len() - dataset::HEADER_SIZE as u64) / 8;
+    let mut index_file = BufReader::new(index_file);
+
+    let mut writer = ShardWriter { prefix: String::from(prefix), shard_tokens, tokens: None, index: None, shards: Vec::new() };
+    writer.open_shard()?;
+
+    let mut offset = [0u8; 8];
+    let mut next_offset = match n_games {
+        0 => tokens_len,
+        _ => {
+            index_file.read_exact(&mut offset)?;
+            u64::from_be_bytes(offset)
+        },
+    };
+    let mut position = 0;
+    let mut game_bytes = Vec::new();
+    for game in 0..n_games {
+        let start = next_offset;
+        next_offset = if game + 1 < n_games {
+            index_file.read_exact(&mut offset)?;
+            u64::from_be_bytes(offset)
+        } else {
+            tokens_len
+        };
+
+        if start != position || next_offset < start || next_offset > tokens_len {
+            return Err(anyhow!("Index entry {} does not match the token data", game));
+        }
+
+        game_bytes.resize((next_offset - start) as usize, 0);
+        tokens_file.read_exact(&mut game_bytes)?;
+        position = next_offset;
+
+        let game: Vec<u16> = game_bytes.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect();
+        writer.write_game(&game)?;
+    }
+    writer.close_shard()?;
+
+    let npy_manifest = NpyManifest {
+        format: String::from(NPY_MANIFEST_FORMAT),
+        version: 1,
+        dtype: String::from("<u2"),
+        index_dtype: String::from("<u8"),
+        vocab_size: tokenizer.vocab_size() as u32,
+        fingerprint: format!("{:016x}", tokenizer.fingerprint()),
+        shards: writer.shards,
+    };
+    manifest::write_atomically(&format!("{}.manifest.json", prefix), serde_json::to_string_pretty(&npy_manifest)?.as_bytes())?;
+    return Ok(npy_manifest);
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn read_npy(path: &str) -> (String, Vec<u8>) {
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(&bytes[0..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        return (String::from_utf8(bytes[10..10 + header_len].to_vec()).unwrap(), bytes[10 + header_len..].to_vec());
+    }
+
+    fn build(dir: &TempDir, tokenizer: &Tokenizer, games: &[&[u16]]) -> (String, String) {
+        let tokens_path = dir.path().join("out.games").to_str().unwrap().to_string();
+        let index_path = dir.path().join("out.index").to_str().unwrap().to_string();
+        let (mut tokens_file, _) = dataset::open_for_append(&tokens_path, FileKind::Tokens, tokenizer).unwrap();
+        let (mut index_file, _) = dataset::open_for_append(&index_path, FileKind::Index, tokenizer).unwrap();
+        let mut offset = 0u64;
+        for game in games {
+            index_file.write_all(&offset.to_be_bytes()).unwrap();
+            for tok in *game {
+                tokens_file.write_all(&tok.to_be_bytes()).unwrap();
+            }
+            offset += 2 * game.len() as u64;
+        }
+        return (tokens_path, index_path);
+    }
+
+    #[test]
+    fn header() {
+        let header = npy_header("<u2", 1234).unwrap();
+        assert_eq!(header.len(), NPY_HEADER_SIZE);
+        assert!(String::from_utf8_lossy(&header[10..]).contains("'descr': '<u2', 'fortran_order': False, 'shape': (1234,)"));
+    }
+
+    #[test]
+    fn export_single() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        let dir = TempDir::new("npy").unwrap();
+        let (tokens_path, index_path) = build(&dir, &tokenizer, &[&[0, 100, 200, 3], &[2, 300, 3]]);
+        let prefix = dir.path().join("out").to_str().unwrap().to_string();
+
+        let manifest = export(&tokens_path, &index_path, &tokenizer, &prefix, None).unwrap();
+        assert_eq!(manifest.shards, [NpyShard { tokens: String::from("out.tokens.npy"), index: String::from("out.index.npy"), n_tokens: 7, n_games: 2 }]);
+
+        let (header, data) = read_npy(&format!("{}.tokens.npy", prefix));
+        assert!(header.contains("'descr': '<u2'") && header.contains("'shape': (7,)"));
+        assert_eq!(data, [0, 0, 100, 0, 200, 0, 3, 0, 2, 0, 44, 1, 3, 0]);
+
+        let (header, data) = read_npy(&format!("{}.index.npy", prefix));
+        assert!(header.contains("'descr': '<u8'") && header.contains("'shape': (2,)"));
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+
+        let loaded: NpyManifest = serde_json::from_str(&fs::read_to_string(format!("{}.manifest.json", prefix)).unwrap()).unwrap();
+        assert_eq!(loaded, manifest);
+    }
+
+    #[test]
+    fn export_sharded() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        let dir = TempDir::new("npy").unwrap();
+        let (tokens_path, index_path) = build(&dir, &tokenizer, &[&[0, 100, 200, 3], &[2, 300, 3], &[1, 3], &[0, 5, 6, 7, 8, 3]]);
+        let prefix = dir.path().join("out").to_str().unwrap().to_string();
+
+        let manifest = export(&tokens_path, &index_path, &tokenizer, &prefix, Some(5)).unwrap();
+        let shards: Vec<(u64, u64)> = manifest.shards.iter().map(|shard| (shard.n_tokens, shard.n_games)).collect();
+        assert_eq!(shards, [(4, 1), (5, 2), (6, 1)]);
+        assert_eq!(manifest.shards[1].tokens, "out.tokens.00001.npy");
+
+        let (_, data) = read_npy(&format!("{}.index.00001.npy", prefix));
+        assert_eq!(data, [0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
@@ -1,72 +1,173 @@
+use std::io::Read;
+
 use anyhow::{anyhow, Error};
-use pgn_reader::{BufferedReader, SanPlus, Skip, Visitor};
-use shakmaty::{Chess, Move, Outcome, Position};
+use pgn_reader::{BufferedReader, Nag, RawComment, RawHeader, SanPlus, Skip, Visitor};
+use shakmaty::{variant::VariantPosition, Chess, Move, Outcome, Position};
+
+use crate::{metadata::{self, GameMetadata, MoveAnnotation}, rejection::RejectReason, tokenizer::Token, variant::GameVariant};
+
+/*
+
+Games are played on the position type of their Variant tag (standard chess
+without one), from the FEN tag when there is one. A game whose start position
+can not be set up (unknown variant, malformed or illegal FEN) is skipped and
+reported through PgnVisitor::invalid_setup.
+
+Games dropped by the parser itself (illegal SAN, no result, a visitor error
+while tokenizing or saving) are reported through PgnVisitor::reject_game, once
+per game.
 
-use crate::tokenizer::Token;
+visit_games_from_bytes parses all games of the data with one reader. The
+reader does not expose how far it got, so the byte offset of every game is
+found by scanning the data for game boundaries with the rules of the reader
+(see game_start and game_end), once per game.
+
+*/
 
 pub trait PgnVisitor {
+    // Called once all headers of a game were seen, returning false skips the game
+    fn accept_game(&mut self, _metadata: &GameMetadata) -> bool { true }
+    // Called for a skipped game whose Variant / FEN tags could not be set up, `_fen` is empty without a FEN tag
+    fn invalid_setup(&mut self, _fen: &str, _error: &Error) { }
+
     fn begin_game(&mut self, _outcome: Outcome) -> Result<Vec<Token>, Error> { return Err(anyhow!("Not implemented")); }
     fn game_move(&mut self, _move: Move) -> Result<Vec<Token>, Error> { return Err(anyhow!("Not implemented")); }
+
+    // Games with an unknown result ("*") are skipped unless this returns true
+    fn keep_unknown_outcomes(&self) -> bool { false }
+
+    // Called instead of begin_game / game_move, visitors that need the headers, the start position or the move annotations
+    // override these. The outcome is None only for games with an unknown result, see keep_unknown_outcomes
+    fn begin_game_with_metadata(&mut self, _outcome: Option<Outcome>, _metadata: &GameMetadata, _start: &VariantPosition) -> Result<Vec<Token>, Error> {
+        return match _outcome {
+            Some(outcome) => self.begin_game(outcome),
+            None => Err(anyhow!("Outcome not specified")),
+        };
+    }
+    fn annotated_move(&mut self, _move: Move, _annotation: &MoveAnnotation) -> Result<Vec<Token>, Error> { return self.game_move(_move); }
     fn end_game(&mut self) -> Result<Vec<Token>, Error> { return Err(anyhow!("Not implemented")); }
 
     fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> { return Err(anyhow!("Not implemented")); } 
+
+    // Called before every game by visit_games_from_bytes with the byte offset of the game in the data
+    fn game_offset(&mut self, _offset: u64) { }
+    // Called for a game the parser dropped after accept_game, with the headers of the game
+    fn reject_game(&mut self, _metadata: &GameMetadata, _reason: RejectReason, _detail: &str) { }
 }
 
 struct OrigPgnVisitor<'a, V: PgnVisitor> {
     visitor: &'a mut V,
+    // The parsed data when game offsets are reported, see visit_games_from_bytes
+    data: Option<&'a [u8]>,
+    next_offset: usize,
     skip_current_game: bool,
     current_game_moves: Vec<Move>,
-    current_game_outcome: Outcome,
-    current_pos: Chess,
+    current_game_annotations: Vec<MoveAnnotation>,
+    current_game_metadata: GameMetadata,
+    current_game_outcome: Option<Outcome>,
+    rejection: Option<(RejectReason, String)>,
+    start_pos: VariantPosition,
+    current_pos: VariantPosition,
 }
 
 impl<V: PgnVisitor> OrigPgnVisitor<'_, V> {
-    fn new(visitor: &mut V) -> OrigPgnVisitor<V> {
+    fn new(visitor: &mut V) -> OrigPgnVisitor<'_, V> {
         return OrigPgnVisitor { 
+            data: None,
+            next_offset: 0,
             skip_current_game: false,
             current_game_moves: Vec::new(),
-            current_game_outcome: Outcome::Draw, 
-            visitor: visitor, 
-            current_pos: Chess::default(),
+            current_game_annotations: Vec::new(),
+            current_game_metadata: GameMetadata::default(),
+            current_game_outcome: None,
+            rejection: None,
+            visitor, 
+            start_pos: Chess::default().into(),
+            current_pos: Chess::default().into(),
         };
 
     }
+
+    // Keeps the first reason a game is dropped for, it is reported in end_game
+    fn reject(&mut self, reason: RejectReason, detail: String) {
+        if self.rejection.is_none() {
+            self.rejection = Some((reason, detail));
+        }
+        self.skip_current_game = true;
+    }
 }
 
 impl<V: PgnVisitor> Visitor for OrigPgnVisitor<'_, V> {
     type Result = bool;
 
-    fn begin_game(&mut self) { }
+    fn begin_game(&mut self) {
+        if let Some(data) = self.data {
+            let start = game_start(data, self.next_offset);
+            self.next_offset = game_end(data, start);
+            self.visitor.game_offset(start as u64);
+        }
+    }
+
+    fn header(&mut self, key: &[u8], value: RawHeader<'_>) {
+        self.current_game_metadata.insert(&String::from_utf8_lossy(key), &value.decode_utf8_lossy());
+    }
+
+    fn end_headers(&mut self) -> Skip {
+        if !self.visitor.accept_game(&self.current_game_metadata) {
+            self.skip_current_game = true;
+        } else {
+            let fen = self.current_game_metadata.get("FEN");
+            match GameVariant::from_tag(self.current_game_metadata.get("Variant")).and_then(|variant| variant.start_position(fen)) {
+                Ok(pos) => {
+                    self.start_pos = pos.clone();
+                    self.current_pos = pos;
+                },
+                Err(e) => {
+                    self.visitor.invalid_setup(fen.unwrap_or(""), &e);
+                    self.visitor.reject_game(&self.current_game_metadata, RejectReason::InvalidSetup, &e.to_string());
+                    self.skip_current_game = true;
+                },
+            }
+        }
+        Skip(self.skip_current_game)
+    }
 
     fn san(&mut self, san_plus: SanPlus) {
+        if self.skip_current_game {
+            return;
+        }
         match san_plus.san.to_move(&self.current_pos) {
             Ok(_move) => {
                 match self.current_pos.clone().play(&_move) {
                     Ok(pos) => {
                         self.current_pos = pos;
                         self.current_game_moves.push(_move);
+                        self.current_game_annotations.push(MoveAnnotation::default());
                     },
-                    Err(e) => {
-                        self.skip_current_game = true;
-                        println!("Error: {}", e);
-                    },
+                    Err(e) => self.reject(RejectReason::IllegalSan, format!("{}: {}", san_plus, e)),
                 }
             },
-            Err(e) => {
-                self.skip_current_game = true;
-                //println!("Error: {}", e);
-            },
+            Err(e) => self.reject(RejectReason::IllegalSan, format!("{}: {}", san_plus, e)),
+        }
+    }
+
+    fn nag(&mut self, nag: Nag) {
+        if let Some(annotation) = self.current_game_annotations.last_mut() {
+            annotation.nags.push(nag.0);
+        }
+    }
+
+    fn comment(&mut self, comment: RawComment<'_>) {
+        let comment = String::from_utf8_lossy(comment.as_bytes());
+        match self.current_game_annotations.last_mut() {
+            Some(annotation) => annotation.add_comment(&comment),
+            // Comments before the first move describe the whole game
+            None => metadata::append_comment(&mut self.current_game_metadata.comment, &comment),
         }
     }
 
     fn outcome(&mut self, outcome: Option<Outcome>) {
-        self.current_game_outcome = match outcome {
-            Some(outcome) => outcome,
-            None => {
-                self.skip_current_game = true;
-                Outcome::Draw
-            },
-        };
+        self.current_game_outcome = outcome;
     }
 
     fn begin_variation(&mut self) -> Skip {
@@ -75,25 +176,30 @@ impl<V: PgnVisitor> Visitor for OrigPgnVisitor<'_, V> {
 
     fn end_game(&mut self) -> Self::Result {
         let mut error = false;
+        // Also covers games without a result marker at all
+        if self.current_game_outcome.is_none() && !self.visitor.keep_unknown_outcomes() && !self.skip_current_game {
+            self.reject(RejectReason::MissingResult, String::from("No result"));
+        }
         if !self.skip_current_game {
             let mut current_game: Vec<Token> = Vec::new();
             if !error {
-                match self.visitor.begin_game(self.current_game_outcome) {
+                match self.visitor.begin_game_with_metadata(self.current_game_outcome, &self.current_game_metadata, &self.start_pos) {
                     Ok(pgn) => current_game.extend(pgn),
                     Err(e) => {
                         error = true;
-                        println!("Error: {}", e);
+                        self.rejection = Some((RejectReason::UnknownToken, e.to_string()));
                     }
                 }
             }
 
             if !error {
-                for _move in self.current_game_moves.clone() {
-                    match self.visitor.game_move(_move) {
+                for (_move, annotation) in self.current_game_moves.clone().into_iter().zip(&self.current_game_annotations) {
+                    match self.visitor.annotated_move(_move, annotation) {
                         Ok(pgn) => current_game.extend(pgn),
                         Err(e) => {
                             error = true;
-                            println!("Error: {}", e);
+                            self.rejection = Some((RejectReason::UnknownToken, e.to_string()));
+                            break;
                         }
                     }
                 }
@@ -104,7 +210,7 @@ impl<V: PgnVisitor> Visitor for OrigPgnVisitor<'_, V> {
                     Ok(pgn) => current_game.extend(pgn),
                     Err(e) => {
                         error = true;
-                        println!("Error: {}", e);
+                        self.rejection = Some((RejectReason::UnknownToken, e.to_string()));
                     }
                 }
             }
@@ -114,20 +220,120 @@ impl<V: PgnVisitor> Visitor for OrigPgnVisitor<'_, V> {
                     Ok(_) => {},
                     Err(e) => {
                         error = true;
-                        println!("Error: {}", e);
+                        self.rejection = Some((RejectReason::SaveFailed, e.to_string()));
                     }
                 }
             }
         }
 
+        if let Some((reason, detail)) = self.rejection.take() {
+            self.visitor.reject_game(&self.current_game_metadata, reason, &detail);
+        }
+
         self.skip_current_game = false;
-        self.current_game_outcome = Outcome::Draw;
+        self.current_game_outcome = None;
         self.current_game_moves = Vec::new();
-        self.current_pos = Chess::default();
+        self.current_game_annotations = Vec::new();
+        self.current_game_metadata.clear();
+        self.start_pos = Chess::default().into();
+        self.current_pos = Chess::default().into();
         return !(error || self.skip_current_game);
     }
 }
 
+// Past the end of the line at `pos`
+fn skip_line(data: &[u8], pos: usize) -> usize {
+    return data[pos..].iter().position(|b| *b == b'\n').map_or(data.len(), |i| pos + i + 1);
+}
+
+// The offset of the game after `pos`, past a byte order mark, whitespace and escaped (%) lines
+fn game_start(data: &[u8], mut pos: usize) -> usize {
+    if data[pos..].starts_with(b"\xef\xbb\xbf") {
+        pos += 3;
+    }
+    while pos < data.len() {
+        match data[pos] {
+            b' ' | b'\t' | b'\r' | b'\n' => pos += 1,
+            b'%' => pos = skip_line(data, pos),
+            _ => break,
+        }
+    }
+    return pos;
+}
+
+// The end of the game at `start`: its header lines, then movetext up to a blank line or a line starting with [
+fn game_end(data: &[u8], mut pos: usize) -> usize {
+    let find = |from: usize, needles: &[u8]| data[from..].iter().position(|b| needles.contains(b)).map(|i| from + i);
+
+    while pos < data.len() {
+        match data[pos] {
+            b'[' => {
+                pos = match find(pos + 1, b"\"\n]") {
+                    Some(quote) if data[quote] == b'"' => {
+                        let mut end = quote + 1;
+                        loop {
+                            match find(end, b"\\\"\n") {
+                                Some(i) if data[i] == b'"' => break i + 1,
+                                Some(i) if data[i] == b'\n' => break i,
+                                Some(i) => end = (i + 2).min(data.len()),
+                                None => return data.len(),
+                            }
+                        }
+                    },
+                    Some(eol) => eol + 1,
+                    None => return data.len(),
+                };
+                // The rest of the header line
+                while pos < data.len() {
+                    match data[pos] {
+                        b' ' | b'\t' | b'\r' | b']' => pos += 1,
+                        b'%' => {
+                            pos = skip_line(data, pos);
+                            break;
+                        },
+                        b'\n' => {
+                            pos += 1;
+                            break;
+                        },
+                        _ => break,
+                    }
+                }
+            },
+            b'%' => pos = skip_line(data, pos),
+            _ => break,
+        }
+    }
+
+    while pos < data.len() {
+        match data[pos] {
+            b'{' => pos = find(pos, b"}").map_or(data.len(), |i| i + 1),
+            b';' => pos = find(pos, b"\n").unwrap_or(data.len()),
+            b'\n' => {
+                pos += 1;
+                match data.get(pos) {
+                    Some(b'%') => pos = skip_line(data, pos),
+                    Some(b'[') | Some(b'\n') => return pos,
+                    Some(b'\r') if data.get(pos + 1) == Some(&b'\n') => return pos + 1,
+                    _ => {},
+                }
+            },
+            _ => pos += 1,
+        }
+    }
+    return pos;
+}
+
+/// Parses the games of `data`, passing the byte offset of every game to PgnVisitor::game_offset first
+pub fn visit_games_from_bytes<V: PgnVisitor>(data: &[u8], visitor: &mut V) -> Result<(), Error> {
+    let mut pgn_buffer = BufferedReader::new_cursor(data);
+    let mut orig_visitor = OrigPgnVisitor::new(visitor);
+    orig_visitor.data = Some(data);
+    return match pgn_buffer.read_all(&mut orig_visitor) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Error parsing PGN: {}", e)),
+    };
+}
+
 pub fn visit_games_from_pgn_string<V: PgnVisitor>(pgn_string: String, visitor: &mut V) -> Result<(), Error> {
     let mut pgn_buffer = BufferedReader::new_cursor(pgn_string);
     let mut orig_visitor = OrigPgnVisitor::new(visitor);
@@ -136,3 +342,221 @@ pub fn visit_games_from_pgn_string<V: PgnVisitor>(pgn_string: String, visitor: &
         Err(e) => Err(anyhow!("Error parsing PGN: {}", e)),
     };
 }
+
+pub fn visit_games_from_reader<R: Read, V: PgnVisitor>(reader: R, visitor: &mut V) -> Result<(), Error> {
+    let mut pgn_buffer = BufferedReader::new(reader);
+    let mut orig_visitor = OrigPgnVisitor::new(visitor);
+    return match pgn_buffer.read_all(&mut orig_visitor) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow!("Error parsing PGN: {}", e)),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use shakmaty::fen::Fen;
+
+    use crate::metadata::Eval;
+
+    use super::*;
+
+    struct CountingVisitor {
+        games: Vec<usize>,
+    }
+
+    impl PgnVisitor for CountingVisitor {
+        fn begin_game(&mut self, _outcome: Outcome) -> Result<Vec<Token>, Error> { Ok(Vec::from([Token { value: 0 }])) }
+        fn game_move(&mut self, _move: Move) -> Result<Vec<Token>, Error> { Ok(Vec::from([Token { value: 4 }])) }
+        fn end_game(&mut self) -> Result<Vec<Token>, Error> { Ok(Vec::from([Token { value: 3 }])) }
+        fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> {
+            self.games.push(_game.len());
+            Ok(())
+        }
+    }
+
+    const PGN: &str = "[Event \"A\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n\n[Event \"B\"]\n[Result \"*\"]\n\n1. d4 *\n\n[Event \"C\"]\n[Result \"1/2-1/2\"]\n\n1. d4 d5 1/2-1/2\n";
+
+    #[test]
+    fn visit_games_from_reader() {
+        let mut visitor = CountingVisitor { games: Vec::new() };
+        super::visit_games_from_reader(PGN.as_bytes(), &mut visitor).unwrap();
+        assert_eq!(visitor.games, [9, 4]);
+    }
+
+    #[test]
+    fn visit_games_from_pgn_string() {
+        let mut visitor = CountingVisitor { games: Vec::new() };
+        super::visit_games_from_pgn_string(String::from(PGN), &mut visitor).unwrap();
+        assert_eq!(visitor.games, [9, 4]);
+    }
+
+    #[derive(Default)]
+    struct RejectingVisitor {
+        offsets: Vec<u64>,
+        rejections: Vec<(String, RejectReason)>,
+        games: usize,
+        fail_saves: bool,
+    }
+
+    impl PgnVisitor for RejectingVisitor {
+        fn begin_game(&mut self, _outcome: Outcome) -> Result<Vec<Token>, Error> { Ok(Vec::new()) }
+        fn game_move(&mut self, _move: Move) -> Result<Vec<Token>, Error> {
+            match _move.is_castle() {
+                true => Err(anyhow!("No castling token")),
+                false => Ok(Vec::new()),
+            }
+        }
+        fn end_game(&mut self) -> Result<Vec<Token>, Error> { Ok(Vec::new()) }
+        fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> {
+            if self.fail_saves {
+                return Err(anyhow!("Disk full"));
+            }
+            self.games += 1;
+            Ok(())
+        }
+        fn game_offset(&mut self, _offset: u64) {
+            self.offsets.push(_offset);
+        }
+        fn reject_game(&mut self, _metadata: &GameMetadata, _reason: RejectReason, _detail: &str) {
+            self.rejections.push((String::from(_metadata.get("Event").unwrap_or("")), _reason));
+        }
+    }
+
+    #[test]
+    fn visit_games_from_bytes() {
+        let pgn = format!("{}\n[Event \"D\"]\n[Result \"0-1\"]\n\n1. e4 e5 2. Ke3 0-1\n\n[Event \"E\"]\n[FEN \"k7/8/8/8/8/8/8/4K2R w K - 0 1\"]\n[Result \"1-0\"]\n\n1. O-O 1-0", PGN);
+        let mut visitor = RejectingVisitor::default();
+        super::visit_games_from_bytes(pgn.as_bytes(), &mut visitor).unwrap();
+        assert_eq!(visitor.games, 2);
+        assert_eq!(visitor.offsets.len(), 5);
+        for (offset, event) in visitor.offsets.iter().zip(["A", "B", "C", "D", "E"]) {
+            assert!(pgn[*offset as usize..].starts_with(&format!("[Event \"{}\"]", event)));
+        }
+        assert_eq!(visitor.rejections, [
+            (String::from("B"), RejectReason::MissingResult),
+            (String::from("D"), RejectReason::IllegalSan),
+            (String::from("E"), RejectReason::UnknownToken),
+        ]);
+    }
+
+    #[test]
+    fn visit_games_from_bytes_offsets() {
+        // A header line inside a comment belongs to the game, a game without headers starts at its movetext
+        let pgn = "\u{feff}[Event \"A\"]\n[Result \"1-0\"]\n\n1. e4 {\n[Event \"B\"]} e5 1-0\n\n[Event \"B\"]\n[Result \"0-1\"]\n\n1. d4 0-1\n\n1. c4 0-1\n";
+        let mut visitor = RejectingVisitor::default();
+        super::visit_games_from_bytes(pgn.as_bytes(), &mut visitor).unwrap();
+        assert_eq!(visitor.games, 3);
+        assert_eq!(visitor.offsets, [3, 60, 99]);
+        assert!(pgn[60..].starts_with("[Event \"B\"]\n[Result"));
+        assert!(pgn[99..].starts_with("1. c4"));
+    }
+
+    #[test]
+    fn save_error() {
+        let mut visitor = RejectingVisitor { fail_saves: true, ..Default::default() };
+        super::visit_games_from_bytes(PGN.as_bytes(), &mut visitor).unwrap();
+        assert_eq!(visitor.games, 0);
+        assert_eq!(visitor.rejections, [
+            (String::from("A"), RejectReason::SaveFailed),
+            (String::from("B"), RejectReason::MissingResult),
+            (String::from("C"), RejectReason::SaveFailed),
+        ]);
+    }
+
+    struct FilteringVisitor {
+        inner: CountingVisitor,
+        events: Vec<String>,
+    }
+
+    impl PgnVisitor for FilteringVisitor {
+        fn accept_game(&mut self, _metadata: &GameMetadata) -> bool {
+            let event = _metadata.get("Event").unwrap_or("");
+            self.events.push(String::from(event));
+            event != "A"
+        }
+        fn begin_game(&mut self, _outcome: Outcome) -> Result<Vec<Token>, Error> { self.inner.begin_game(_outcome) }
+        fn game_move(&mut self, _move: Move) -> Result<Vec<Token>, Error> { self.inner.game_move(_move) }
+        fn end_game(&mut self) -> Result<Vec<Token>, Error> { self.inner.end_game() }
+        fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> { self.inner.save_game(_game) }
+    }
+
+    #[test]
+    fn accept_game() {
+        let mut visitor = FilteringVisitor { inner: CountingVisitor { games: Vec::new() }, events: Vec::new() };
+        super::visit_games_from_reader(PGN.as_bytes(), &mut visitor).unwrap();
+        assert_eq!(visitor.events, ["A", "B", "C"]);
+        assert_eq!(visitor.inner.games, [4]);
+    }
+
+    #[derive(Default)]
+    struct AnnotationVisitor {
+        metadata: Vec<GameMetadata>,
+        starts: Vec<String>,
+        invalid_setups: Vec<String>,
+        annotations: Vec<MoveAnnotation>,
+    }
+
+    impl PgnVisitor for AnnotationVisitor {
+        fn keep_unknown_outcomes(&self) -> bool { true }
+        fn invalid_setup(&mut self, _fen: &str, _error: &Error) {
+            self.invalid_setups.push(String::from(_fen));
+        }
+        fn begin_game_with_metadata(&mut self, _outcome: Option<Outcome>, _metadata: &GameMetadata, _start: &VariantPosition) -> Result<Vec<Token>, Error> {
+            self.metadata.push(_metadata.clone());
+            self.starts.push(Fen::from_position(_start.clone(), shakmaty::EnPassantMode::Legal).to_string());
+            Ok(Vec::new())
+        }
+        fn annotated_move(&mut self, _move: Move, _annotation: &MoveAnnotation) -> Result<Vec<Token>, Error> {
+            self.annotations.push(_annotation.clone());
+            Ok(Vec::new())
+        }
+        fn end_game(&mut self) -> Result<Vec<Token>, Error> { Ok(Vec::new()) }
+        fn save_game(&mut self, _game: Vec<Token>) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn annotations() {
+        let pgn = "[Event \"A\"]\n[WhiteElo \"2100\"]\n[Result \"0-1\"]\n\n{ Opening trap } 1. e4 { [%eval 0.2] [%clk 0:05:00] } 1... e5 $1 2. Ke2?! { Bongcloud (2. Nf3 Nc6) } ( 2. Nf3 { not here } ) 2... Qh4 0-1\n\n[Event \"B\"]\n[Result \"*\"]\n\n1. e4 *\n";
+        let mut visitor = AnnotationVisitor::default();
+        super::visit_games_from_reader(pgn.as_bytes(), &mut visitor).unwrap();
+
+        assert_eq!(visitor.metadata.len(), 2);
+        assert_eq!(visitor.metadata[0].get("WhiteElo"), Some("2100"));
+        assert_eq!(visitor.metadata[0].comment.as_deref(), Some("Opening trap"));
+        assert_eq!(visitor.metadata[1].get("WhiteElo"), None);
+        assert_eq!(visitor.metadata[1].comment, None);
+
+        assert_eq!(visitor.annotations.len(), 5);
+        assert_eq!(visitor.annotations[0].eval, Some(Eval::Centipawns(20)));
+        assert_eq!(visitor.annotations[0].clock, Some(Duration::from_secs(300)));
+        assert_eq!(visitor.annotations[1].nags, [1]);
+        assert_eq!(visitor.annotations[2].nags, [6]);
+        assert_eq!(visitor.annotations[2].comment.as_deref(), Some("Bongcloud (2. Nf3 Nc6)"));
+        assert!(visitor.annotations[3].is_empty());
+        assert!(visitor.annotations[4].is_empty());
+    }
+
+    #[test]
+    fn setup() {
+        let pgn = "[Event \"A\"]\n[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 1\"]\n[Result \"1/2-1/2\"]\n\n1... Kd7 2. e4 1/2-1/2\n\n[Event \"B\"]\n[FEN \"8/8/8/8/8/8/8/8 w - - 0 1\"]\n[Result \"1-0\"]\n\n1. e4 1-0\n\n[Event \"C\"]\n[Result \"1-0\"]\n\n1. e4 1-0\n";
+        let mut visitor = AnnotationVisitor::default();
+        super::visit_games_from_reader(pgn.as_bytes(), &mut visitor).unwrap();
+
+        assert_eq!(visitor.starts, ["4k3/8/8/8/8/8/4P3/4K3 b - - 0 1", "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"]);
+        assert_eq!(visitor.annotations.len(), 3);
+        assert_eq!(visitor.invalid_setups, ["8/8/8/8/8/8/8/8 w - - 0 1"]);
+    }
+
+    #[test]
+    fn variants() {
+        let pgn = "[Event \"A\"]\n[Variant \"Chess960\"]\n[FEN \"r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1\"]\n[Result \"1-0\"]\n\n1. O-O O-O-O 1-0\n\n[Event \"B\"]\n[Variant \"Crazyhouse\"]\n[Result \"0-1\"]\n\n1. e4 d5 2. exd5 Qxd5 3. P@e4 0-1\n\n[Event \"C\"]\n[Variant \"Bughouse\"]\n[Result \"1-0\"]\n\n1. e4 1-0\n";
+        let mut visitor = AnnotationVisitor::default();
+        super::visit_games_from_reader(pgn.as_bytes(), &mut visitor).unwrap();
+
+        assert_eq!(visitor.starts, ["r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR[] w KQkq - 0 1"]);
+        assert_eq!(visitor.annotations.len(), 2 + 5);
+        assert_eq!(visitor.invalid_setups, [""]);
+    }
+}
@@ -31,13 +31,115 @@ pub fn download_bytes_from_url(url: String) -> Result<Bytes, Error> {
     }
 }
 
-pub fn pgn_string_from_bytes(url: String, bytes: Bytes) -> Result<String, Error> {
-    return if url.ends_with(".zip") {
-        pgn_string_from_bytes_zip(bytes)
-    } else if url.ends_with(".pgn") {
-        pgn_string_from_bytes_pgn(bytes)
+pub fn open_url(url: String) -> Result<Box<dyn Read + Send>, Error> {
+    if Path::new(&url).exists() {
+        return match fs::OpenOptions::new().read(true).open(&url) {
+            Ok(file) => Ok(Box::new(file)),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    let client = reqwest::blocking::ClientBuilder::new().timeout(Duration::from_secs(600)).build()?;
+    return match client.get(url).send() {
+        Ok(response) => match response.error_for_status() {
+            Ok(response) => Ok(Box::new(response)),
+            Err(err) => Err(err.into()),
+        },
+        Err(err) => Err(err.into()),
+    };
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Zip,
+    Zstd,
+    Bzip2,
+    Gzip,
+}
+
+pub fn detect_compression(magic: &[u8]) -> Compression {
+    return if magic.starts_with(b"PK\x03\x04") {
+        Compression::Zip
+    } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        Compression::Zstd
+    } else if magic.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if magic.starts_with(&[0x1F, 0x8B]) {
+        Compression::Gzip
     } else {
-        Err(anyhow!("Unsupported file type"))
+        Compression::None
+    };
+}
+
+/*
+
+Streams every PGN found in `reader` into `visit` without buffering the whole
+source in memory. The container format is picked from the magic bytes, not
+from the url, zip archives are decompressed entry by entry as they come off
+the stream and every `.pgn` entry is visited.
+
+*/
+pub fn visit_pgn_streams<R, F>(mut reader: R, mut visit: F) -> Result<(), Error>
+where
+    R: Read,
+    F: FnMut(&mut dyn Read) -> Result<(), Error>,
+{
+    let mut magic = Vec::new();
+    (&mut reader).take(4).read_to_end(&mut magic)?;
+    let mut reader = Read::chain(magic.as_slice(), reader);
+
+    return match detect_compression(&magic) {
+        Compression::Zip => {
+            let mut n_entries = 0;
+            loop {
+                match zip::read::read_zipfile_from_stream(&mut reader) {
+                    Ok(Some(mut file)) => {
+                        if file.is_file() && file.name().to_lowercase().ends_with(".pgn") {
+                            n_entries += 1;
+                            visit(&mut file)?;
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            match n_entries {
+                0 => Err(anyhow!("No pgn files found in zip archive")),
+                _ => Ok(()),
+            }
+        },
+        Compression::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::new(reader)?;
+            // Lichess dumps are compressed with long windows
+            decoder.window_log_max(31)?;
+            visit(&mut decoder)
+        },
+        Compression::Bzip2 => visit(&mut bzip2::read::MultiBzDecoder::new(reader)),
+        Compression::Gzip => visit(&mut flate2::read::MultiGzDecoder::new(reader)),
+        Compression::None => visit(&mut reader),
+    };
+}
+
+pub fn visit_pgn_streams_from_url<F>(url: String, visit: F) -> Result<(), Error>
+where
+    F: FnMut(&mut dyn Read) -> Result<(), Error>,
+{
+    return visit_pgn_streams(open_url(url)?, visit);
+}
+
+pub fn pgn_string_from_bytes(url: String, bytes: Bytes) -> Result<String, Error> {
+    let mut pgn_bytes = Vec::<u8>::new();
+    let result = visit_pgn_streams(bytes.reader(), |reader| {
+        reader.read_to_end(&mut pgn_bytes)?;
+        pgn_bytes.push(b'\n');
+        Ok(())
+    });
+
+    return match result {
+        Ok(_) => pgn_string_from_bytes_pgn(Bytes::from(pgn_bytes)),
+        Err(e) => Err(anyhow!("{} - {}", url, e)),
     };
 }
 
@@ -45,7 +147,7 @@ pub fn pgn_string_from_bytes_pgn(bytes: Bytes) -> Result<String, Error> {
     let pgn_string = String::from_utf8(bytes.to_vec());
     return match pgn_string {
         Ok(pgn_string) => Ok(pgn_string),
-        Err(e) => {
+        Err(_e) => {
             let pgn_string = unsafe{String::from_utf8_unchecked(bytes.to_vec())};
             Ok(pgn_string)
             //Err(e.into())
@@ -54,20 +156,73 @@ pub fn pgn_string_from_bytes_pgn(bytes: Bytes) -> Result<String, Error> {
 }
 
 pub fn pgn_string_from_bytes_zip(bytes: Bytes) -> Result<String, Error> {
-    let mut zip_reader: Box<dyn Read> = Box::new(bytes.reader()) as Box<dyn Read>; 
-    return match zip::read::read_zipfile_from_stream(&mut zip_reader) {
-        Ok(Some(mut file)) => {
-            /*match file.read_to_string(&mut pgn_data) {
-                Ok(_) => Ok(pgn_data),
-                Err(e) => Err(e.into()),
-            }*/
-
-            let mut bytes = Vec::<u8>::new();
-            file.read_to_end(&mut bytes)?;
-
-            pgn_string_from_bytes_pgn(bytes::Bytes::from(bytes))
+    if detect_compression(&bytes) != Compression::Zip {
+        return Err(anyhow!("Not a zip archive"));
+    }
+
+    return pgn_string_from_bytes(String::from("zip archive"), bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const PGN: &str = "[Event \"A\"]\n[Result \"1-0\"]\n\n1. e4 e5 2. Qh5 Nc6 3. Bc4 Nf6 4. Qxf7# 1-0\n";
+
+    fn read_streams(bytes: Vec<u8>) -> Vec<String> {
+        let mut streams = Vec::new();
+        visit_pgn_streams(bytes.as_slice(), |reader| {
+            let mut stream = String::new();
+            reader.read_to_string(&mut stream)?;
+            streams.push(stream);
+            Ok(())
+        }).unwrap();
+        return streams;
+    }
+
+    #[test]
+    fn plain() {
+        assert_eq!(detect_compression(PGN.as_bytes()), Compression::None);
+        assert_eq!(read_streams(Vec::from(PGN.as_bytes())), [PGN]);
+    }
+
+    #[test]
+    fn zstd() {
+        let bytes = zstd::stream::encode_all(PGN.as_bytes(), 3).unwrap();
+        assert_eq!(detect_compression(&bytes), Compression::Zstd);
+        assert_eq!(read_streams(bytes), [PGN]);
+    }
+
+    #[test]
+    fn bzip2() {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(PGN.as_bytes()).unwrap();
+        let bytes = encoder.finish().unwrap();
+        assert_eq!(detect_compression(&bytes), Compression::Bzip2);
+        assert_eq!(read_streams(bytes), [PGN]);
+    }
+
+    #[test]
+    fn gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(PGN.as_bytes()).unwrap();
+        let bytes = encoder.finish().unwrap();
+        assert_eq!(detect_compression(&bytes), Compression::Gzip);
+        assert_eq!(read_streams(bytes), [PGN]);
+    }
+
+    #[test]
+    fn zip() {
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for name in ["a.pgn", "readme.txt", "b.PGN"] {
+            writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(PGN.as_bytes()).unwrap();
         }
-        Ok(None) => Err(anyhow!("No files found in zip archive")),
-        Err(e) => Err(e.into())
-    };
+        let bytes = writer.finish().unwrap().into_inner();
+        assert_eq!(detect_compression(&bytes), Compression::Zip);
+        assert_eq!(read_streams(bytes.clone()), [PGN, PGN]);
+        assert_eq!(pgn_string_from_bytes_zip(Bytes::from(bytes)).unwrap(), format!("{}\n{}\n", PGN, PGN));
+    }
 }
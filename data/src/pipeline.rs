@@ -0,0 +1,461 @@
+use std::{collections::{BTreeMap, VecDeque}, io::{self, Read}, sync::{mpsc::{self, Receiver}, Arc, Mutex}, thread};
+
+use anyhow::{anyhow, Error};
+
+use crate::pgn_reader;
+
+/*
+
+Staged build pipeline, every stage connected to the next by a bounded channel:
+
+download    - one thread per source reads the raw bytes (file or http)
+decompress  - one thread per source decompresses them and cuts the PGN text
+              into chunks of whole games, about `chunk_size` bytes each
+dispatch    - hands the chunks out in source order, then file order
+work        - `n_workers` threads turn chunks into results (parse, tokenize)
+write       - the calling thread receives the results back in dispatch order
+
+Up to `prefetch_sources` sources are downloaded and decompressed ahead of the
+one being dispatched, so slow downloads overlap with parsing. Every channel is
+bounded and the dispatcher takes one of `window` permits for every chunk it
+hands out, returned when the writer received its result. A worker stuck on a
+chunk therefore stops the dispatch once `window` chunks are in flight, instead
+of the results of the other workers piling up in the reorder buffer. Memory
+stays around (prefetch_sources * SOURCE_QUEUE + window) chunks however large
+the sources are.
+
+Chunks are cut before a tag line following a blank line. Text without such a
+boundary is cut before the next [Event tag once a chunk reaches
+`max_chunk_size`, a source without either (e.g. a single huge game or bare
+movetext) fails instead of being buffered whole.
+
+The writer sees every chunk of a source, then a SourceEnd carrying the error
+that stopped the source, if any, before anything of the next source.
+
+*/
+
+// Raw blocks read from a source at once
+const DOWNLOAD_BLOCK: usize = 1024 * 1024;
+// Decompressed chunks buffered per source ahead of the dispatcher
+const SOURCE_QUEUE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineConfig {
+    pub n_workers: usize,
+    pub chunk_size: usize,
+    // Hard limit of a chunk, see above
+    pub max_chunk_size: usize,
+    pub prefetch_sources: usize,
+    pub queue_size: usize,
+    // Chunks dispatched but not written yet, at least 1
+    pub window: usize,
+}
+
+impl PipelineConfig {
+    pub fn new(n_workers: usize) -> PipelineConfig {
+        let n_workers = n_workers.max(1);
+        return PipelineConfig { n_workers, chunk_size: 4 * 1024 * 1024, max_chunk_size: 64 * 1024 * 1024, prefetch_sources: 2, queue_size: 2 * n_workers, window: 8 * n_workers };
+    }
+}
+
+pub enum Event<R> {
+    // `offset` is the position of the chunk in the decompressed text of the source
+    Chunk { source: usize, offset: u64, result: R },
+    SourceEnd { source: usize, result: Result<(), Error> },
+}
+
+enum SourceItem {
+    Chunk(u64, Vec<u8>),
+    End(Result<(), Error>),
+}
+
+enum Job {
+    Chunk { seq: u64, source: usize, offset: u64, data: Vec<u8> },
+    End { seq: u64, source: usize, result: Result<(), Error> },
+}
+
+/// Read over the blocks of a download thread
+pub struct ChannelReader {
+    rx: Receiver<Result<Vec<u8>, Error>>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(rx: Receiver<Result<Vec<u8>, Error>>) -> ChannelReader {
+        return ChannelReader { rx, block: Vec::new(), pos: 0 };
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            match self.rx.recv() {
+                Ok(Ok(block)) => {
+                    self.block = block;
+                    self.pos = 0;
+                },
+                Ok(Err(e)) => return Err(io::Error::other(e.to_string())),
+                // The download finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        return Ok(n);
+    }
+}
+
+/// Start of the first game at or after `from`: a tag opening a line that follows a blank line
+pub fn find_game_start(data: &[u8], from: usize) -> Option<usize> {
+    let mut i = from.max(1);
+    while i < data.len() {
+        let newline = data[i..].windows(2).position(|w| w == b"\n[")? + i;
+        let line_start = match data[..newline].iter().rposition(|c| *c == b'\n') {
+            Some(pos) => pos + 1,
+            None => 0,
+        };
+        // A tag name follows, unlike the [%clk ...] commands of comments
+        let tag = data.get(newline + 2).is_some_and(|c| c.is_ascii_alphabetic());
+        if tag && data[line_start..newline].iter().all(|c| c.is_ascii_whitespace()) {
+            return Some(newline + 1);
+        }
+        i = newline + 1;
+    }
+    return None;
+}
+
+/// Cuts the PGN text of `reader` into chunks of whole games of at least `chunk_size` bytes (except the last one).
+/// Fails when no game boundary is found within `max_chunk_size` bytes
+pub fn chunk_pgn<F>(reader: &mut dyn Read, chunk_size: usize, max_chunk_size: usize, mut f: F) -> Result<(), Error>
+where
+    F: FnMut(Vec<u8>) -> Result<(), Error>,
+{
+    let max_chunk_size = max_chunk_size.max(chunk_size);
+    let mut data = Vec::new();
+    let mut block = vec![0u8; 64 * 1024];
+    // Everything before this was searched for a game start already
+    let mut searched = 0;
+    loop {
+        let n = reader.read(&mut block)?;
+        if n == 0 {
+            break;
+        }
+        data.extend_from_slice(&block[..n]);
+        while data.len() > chunk_size {
+            let cut = match find_game_start(&data, searched.max(chunk_size)) {
+                Some(cut) => Some(cut),
+                // Games not separated by blank lines
+                None if data.len() >= max_chunk_size => match data[chunk_size..].windows(7).position(|w| w == b"\n[Event") {
+                    Some(pos) => Some(chunk_size + pos + 1),
+                    None => return Err(anyhow!("No game boundary in {} bytes of PGN text", data.len())),
+                },
+                None => None,
+            };
+            match cut {
+                Some(cut) => {
+                    let rest = data.split_off(cut);
+                    f(std::mem::replace(&mut data, rest))?;
+                    searched = 0;
+                },
+                None => {
+                    // The last bytes may be the start of a "\n[" pair
+                    searched = data.len() - 1;
+                    break;
+                },
+            }
+        }
+    }
+    if !data.is_empty() {
+        f(data)?;
+    }
+    return Ok(());
+}
+
+// Starts the download and decompress threads of a source, they stop early when the receiver is dropped
+fn spawn_source(url: String, chunk_size: usize, max_chunk_size: usize) -> Receiver<SourceItem> {
+    let (block_tx, block_rx) = mpsc::sync_channel::<Result<Vec<u8>, Error>>(SOURCE_QUEUE);
+    let (chunk_tx, chunk_rx) = mpsc::sync_channel(SOURCE_QUEUE);
+
+    thread::spawn(move || {
+        let mut reader = match pgn_reader::open_url(url) {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = block_tx.send(Err(e));
+                return;
+            },
+        };
+        loop {
+            let mut block = vec![0u8; DOWNLOAD_BLOCK];
+            let block = match reader.read(&mut block) {
+                Ok(0) => return,
+                Ok(n) => {
+                    block.truncate(n);
+                    Ok(block)
+                },
+                Err(e) => Err(e.into()),
+            };
+            let failed = block.is_err();
+            if block_tx.send(block).is_err() || failed {
+                return;
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        // Offsets continue over the entries of zip archives
+        let mut offset = 0;
+        let result = pgn_reader::visit_pgn_streams(ChannelReader::new(block_rx), |reader| {
+            chunk_pgn(reader, chunk_size, max_chunk_size, |chunk| {
+                let len = chunk.len() as u64;
+                chunk_tx.send(SourceItem::Chunk(offset, chunk)).map_err(|_| anyhow!("Build stopped"))?;
+                offset += len;
+                return Ok(());
+            })
+        });
+        let _ = chunk_tx.send(SourceItem::End(result));
+    });
+
+    return chunk_rx;
+}
+
+/// Keeps results that arrive out of order until the ones before them came in
+pub struct ReorderBuffer<T> {
+    next: u64,
+    pending: BTreeMap<u64, T>,
+}
+
+impl<T> Default for ReorderBuffer<T> {
+    fn default() -> Self {
+        return ReorderBuffer { next: 0, pending: BTreeMap::new() };
+    }
+}
+
+impl<T> ReorderBuffer<T> {
+    /// Adds item `seq`, returns the items that are now next in order
+    pub fn push(&mut self, seq: u64, item: T) -> Vec<T> {
+        self.pending.insert(seq, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next += 1;
+        }
+        return ready;
+    }
+}
+
+/// Runs `work` on every chunk of every source in `urls` and passes the results to `write` in order, see above.
+/// Stops at the first error returned by `write`
+pub fn run<R, W, F>(urls: &[String], config: &PipelineConfig, work: W, mut write: F) -> Result<(), Error>
+where
+    R: Send,
+    W: Fn(usize, &[u8]) -> R + Sync,
+    F: FnMut(Event<R>) -> Result<(), Error>,
+{
+    let (job_tx, job_rx) = mpsc::sync_channel::<Job>(config.queue_size);
+    // Shared by the workers only, so the dispatcher stops once they all stopped
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (result_tx, result_rx) = mpsc::sync_channel::<(u64, Event<R>)>(config.queue_size);
+
+    return thread::scope(|scope| {
+        // Dropped with the writer, which stops a dispatcher waiting for a permit
+        let (permit_tx, permit_rx) = mpsc::sync_channel::<()>(config.window.max(1));
+        scope.spawn(move || {
+            let mut sources: VecDeque<Receiver<SourceItem>> = VecDeque::new();
+            let mut next_source = 0;
+            let mut seq = 0;
+            for source in 0..urls.len() {
+                while next_source < urls.len() && next_source <= source + config.prefetch_sources {
+                    sources.push_back(spawn_source(urls[next_source].clone(), config.chunk_size, config.max_chunk_size));
+                    next_source += 1;
+                }
+                let items = sources.pop_front().unwrap();
+                loop {
+                    let (job, end) = match items.recv() {
+                        Ok(SourceItem::Chunk(offset, data)) => (Job::Chunk { seq, source, offset, data }, false),
+                        Ok(SourceItem::End(result)) => (Job::End { seq, source, result }, true),
+                        Err(_) => (Job::End { seq, source, result: Err(anyhow!("Source reader stopped")) }, true),
+                    };
+                    seq += 1;
+                    if permit_tx.send(()).is_err() || job_tx.send(job).is_err() {
+                        return;
+                    }
+                    if end {
+                        break;
+                    }
+                }
+            }
+        });
+
+        for _ in 0..config.n_workers {
+            let (job_rx, result_tx, work) = (Arc::clone(&job_rx), result_tx.clone(), &work);
+            scope.spawn(move || {
+                loop {
+                    let job = match job_rx.lock() {
+                        Ok(job_rx) => job_rx.recv(),
+                        Err(_) => return,
+                    };
+                    let result = match job {
+                        Ok(Job::Chunk { seq, source, offset, data }) => (seq, Event::Chunk { source, offset, result: work(source, &data) }),
+                        Ok(Job::End { seq, source, result }) => (seq, Event::SourceEnd { source, result }),
+                        Err(_) => return,
+                    };
+                    if result_tx.send(result).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(result_tx);
+        drop(job_rx);
+
+        let mut reorder = ReorderBuffer::default();
+        for (seq, event) in result_rx {
+            for event in reorder.push(seq, event) {
+                // Returning drops the receivers, which stops the other stages
+                write(event)?;
+                // The permit of this chunk was taken before it was dispatched
+                let _ = permit_rx.recv();
+            }
+        }
+        return Ok(());
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::atomic::{AtomicUsize, Ordering}};
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn games(n: usize) -> String {
+        return (0..n).map(|i| format!("[Event \"{}\"]\n[Result \"1-0\"]\n\n{{ a comment\n\n[%clk 0:01:00] }} 1. e4 e5 1-0\n\n", i)).collect();
+    }
+
+    #[test]
+    fn chunks() {
+        let pgn = games(50);
+        let mut chunks = Vec::new();
+        chunk_pgn(&mut pgn.as_bytes(), 200, 200, |chunk| {
+            chunks.push(String::from_utf8(chunk).unwrap());
+            Ok(())
+        }).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), pgn);
+        for chunk in &chunks {
+            assert!(chunk.starts_with("[Event"));
+            assert_eq!(chunk.matches("[Event").count(), chunk.matches("1-0\n\n").count());
+        }
+        assert_eq!(find_game_start(b"[Event \"a\"]\n\n1. e4 *\n[Event", 1), None);
+
+        // Without blank lines between the games the chunks are cut at [Event once they reach the limit
+        let pgn = games(3000).replace("\n\n", "\n");
+        let mut chunks = Vec::new();
+        chunk_pgn(&mut pgn.as_bytes(), 1000, 100_000, |chunk| {
+            chunks.push(String::from_utf8(chunk).unwrap());
+            Ok(())
+        }).unwrap();
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), pgn);
+        assert!(chunks.iter().all(|chunk| chunk.starts_with("[Event") && chunk.len() < 100_000 + 64 * 1024));
+
+        // Nothing to cut at
+        let movetext = "1. e4 e5 2. Nf3 Nc6 ".repeat(20_000);
+        let result = chunk_pgn(&mut movetext.as_bytes(), 1000, 100_000, |_| Ok(()));
+        assert!(result.unwrap_err().to_string().starts_with("No game boundary"));
+        assert_eq!(find_game_start(b"1. e4 *\n\r\n[Event", 1), Some(10));
+    }
+
+    #[test]
+    fn ordered_results() {
+        let dir = TempDir::new("pipeline").unwrap();
+        let mut urls = Vec::new();
+        for (i, n) in [30, 0, 80].iter().enumerate() {
+            let path = dir.path().join(format!("{}.pgn", i));
+            fs::write(&path, games(*n)).unwrap();
+            urls.push(path.to_str().unwrap().to_string());
+        }
+        urls.push(dir.path().join("missing.pgn").to_str().unwrap().to_string());
+
+        let config = PipelineConfig { n_workers: 4, chunk_size: 300, max_chunk_size: 3000, prefetch_sources: 1, queue_size: 2, window: 8 };
+        let worked = AtomicUsize::new(0);
+        let mut events = Vec::new();
+        run(&urls, &config, |_, data| {
+            worked.fetch_add(1, Ordering::Relaxed);
+            let text = String::from_utf8(data.to_vec()).unwrap();
+            (text.len() as u64, text.matches("[Event \"").count(), text[8..].split('"').next().unwrap().parse::<usize>().unwrap())
+        }, |event| {
+            events.push(match event {
+                Event::Chunk { source, offset, result } => (source, Some((offset, result)), true),
+                Event::SourceEnd { source, result } => (source, None, result.is_ok()),
+            });
+            Ok(())
+        }).unwrap();
+
+        // Every game once, in order, each source closed by its end
+        let mut next_game = 0;
+        let mut next_offset = 0;
+        let mut source = 0;
+        for (event_source, result, ok) in &events {
+            assert_eq!(*event_source, source);
+            match result {
+                Some((offset, (len, n_games, first))) => {
+                    assert_eq!(*first, next_game);
+                    assert_eq!(*offset, next_offset);
+                    next_game += n_games;
+                    next_offset += len;
+                },
+                None => {
+                    assert_eq!(*ok, source != 3);
+                    next_game = 0;
+                    next_offset = 0;
+                    source += 1;
+                },
+            }
+        }
+        assert_eq!(source, 4);
+        assert_eq!(worked.load(Ordering::Relaxed), events.len() - 4);
+
+        // A writer error stops the build
+        let mut written = 0;
+        let result = run(&urls, &config, |_, data| data.len(), |_| {
+            written += 1;
+            match written {
+                3 => Err(anyhow!("Disk full")),
+                _ => Ok(()),
+            }
+        });
+        assert_eq!(result.unwrap_err().to_string(), "Disk full");
+    }
+
+    #[test]
+    fn bounded_window() {
+        let dir = TempDir::new("pipeline").unwrap();
+        let path = dir.path().join("a.pgn");
+        fs::write(&path, games(200)).unwrap();
+        let urls = Vec::from([path.to_str().unwrap().to_string()]);
+
+        // The first chunk is slow, the other workers must not run ahead by more than the window
+        let config = PipelineConfig { n_workers: 4, chunk_size: 100, max_chunk_size: 1000, prefetch_sources: 1, queue_size: 2, window: 5 };
+        let started = AtomicUsize::new(0);
+        let mut written = 0;
+        let mut max_in_flight = 0;
+        run(&urls, &config, |_, data| {
+            started.fetch_add(1, Ordering::SeqCst);
+            if data.starts_with(b"[Event \"0\"]") {
+                thread::sleep(std::time::Duration::from_millis(300));
+            }
+        }, |event| {
+            if let Event::Chunk { .. } = event {
+                max_in_flight = max_in_flight.max(started.load(Ordering::SeqCst) - written);
+                written += 1;
+            }
+            Ok(())
+        }).unwrap();
+        assert!(written > 50);
+        assert!(max_in_flight <= config.window, "{} chunks in flight", max_in_flight);
+    }
+}
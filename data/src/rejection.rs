@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, fs, io::{BufWriter, Seek, SeekFrom, Write}};
+
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::GameMetadata;
+
+/*
+
+The rejection report lists every game of a source that did not make it into
+the dataset, one JSON object per line:
+
+{"url":"...","game":41,"offset":81234,"reason":"illegal_san","detail":"Qxf7: illegal san","tags":{"Event":"...",...}}
+
+`game` is the 0 based number of the game in the source counting every game the
+parser saw, `offset` the byte offset of the game in the decompressed PGN text
+of the source (the .pgn entries of a zip archive follow each other). Games of
+sources that fail are not reported, the source is rolled back and retried as a
+whole, and the report is cut back to the committed length on --resume like
+the output files.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectReason {
+    IllegalSan,
+    MissingResult,
+    // The tokenizer has no token for a move or for the start of the game
+    UnknownToken,
+    Duplicate,
+    Filter,
+    UnsupportedVariant,
+    UnsupportedSetup,
+    InvalidSetup,
+    // PgnVisitor::save_game failed
+    SaveFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rejection {
+    pub url: String,
+    pub game: u64,
+    pub offset: u64,
+    pub reason: RejectReason,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Rejection {
+    /// A rejection of the game with `metadata`, the url, game number and offset are filled in later
+    pub fn new(reason: RejectReason, detail: Option<String>, metadata: &GameMetadata) -> Rejection {
+        return Rejection { url: String::new(), game: 0, offset: 0, reason, detail, tags: metadata.tags.iter().cloned().collect() };
+    }
+}
+
+pub struct RejectionLog {
+    file: BufWriter<fs::File>,
+    len: u64,
+}
+
+impl RejectionLog {
+    /// Opens the report at `path`, keeping its first `length` bytes (all when None). A missing file is created
+    pub fn open(path: &str, length: Option<u64>) -> Result<RejectionLog, Error> {
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let available = file.metadata()?.len();
+        let len = match length {
+            Some(length) if length > available => return Err(anyhow!("{} holds {} bytes, expected at least {}", path, available, length)),
+            Some(length) => length,
+            None => available,
+        };
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len))?;
+        return Ok(RejectionLog { file: BufWriter::new(file), len });
+    }
+
+    pub fn append(&mut self, rejection: &Rejection) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(rejection)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.len += line.len() as u64;
+        return Ok(());
+    }
+
+    /// Writes the buffered records to disk and returns the length to record in the build manifest
+    pub fn commit(&mut self) -> Result<u64, Error> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        return Ok(self.len);
+    }
+
+    /// Drops everything after the first `len` bytes, the records of a source that is rolled back
+    pub fn truncate(&mut self, len: u64) -> Result<(), Error> {
+        self.file.flush()?;
+        self.file.get_ref().set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        self.len = len;
+        return Ok(());
+    }
+
+    /// Bytes in the report
+    pub fn len(&self) -> u64 {
+        return self.len;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len == 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn report() {
+        let dir = TempDir::new("rejection").unwrap();
+        let path = dir.path().join("rejected.jsonl");
+        let path = path.to_str().unwrap();
+
+        let mut metadata = GameMetadata::default();
+        metadata.insert("Event", "A");
+        metadata.insert("Result", "*");
+        let mut rejection = Rejection::new(RejectReason::IllegalSan, Some(String::from("Ke4")), &metadata);
+        rejection.url = String::from("a.pgn");
+        rejection.game = 3;
+        rejection.offset = 120;
+
+        let mut log = RejectionLog::open(path, Some(0)).unwrap();
+        log.append(&rejection).unwrap();
+        let committed = log.commit().unwrap();
+        let line = fs::read_to_string(path).unwrap();
+        assert_eq!(line, "{\"url\":\"a.pgn\",\"game\":3,\"offset\":120,\"reason\":\"illegal_san\",\"detail\":\"Ke4\",\"tags\":{\"Event\":\"A\",\"Result\":\"*\"}}\n");
+        assert_eq!(serde_json::from_str::<Rejection>(&line).unwrap(), rejection);
+
+        // A rolled back source, then records of an interrupted one
+        log.append(&rejection).unwrap();
+        log.truncate(committed).unwrap();
+        log.append(&Rejection::new(RejectReason::Duplicate, None, &metadata)).unwrap();
+        drop(log);
+        assert_eq!(fs::read_to_string(path).unwrap().lines().count(), 2);
+
+        let log = RejectionLog::open(path, Some(committed)).unwrap();
+        assert_eq!(log.len(), committed);
+        assert_eq!(fs::read_to_string(path).unwrap(), line);
+        assert!(RejectionLog::open(path, Some(committed + 1)).is_err());
+    }
+}
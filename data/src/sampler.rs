@@ -0,0 +1,266 @@
+use anyhow::{anyhow, Error};
+use shakmaty::{uci::Uci, variant::VariantPosition, Move, Position};
+
+use crate::tokenizer::{Token, Tokenizer};
+
+/*
+
+Constrained sampling from model outputs. The logits cover the whole vocabulary
+(Tokenizer::vocab_size values), every token that is not legal in the position
+is dropped before anything else, so the result is always a legal move.
+
+temperature - the logits are divided by it, 0 always picks the most likely token
+top_k       - only the k most likely tokens are kept, 0 keeps all of them
+top_p       - only the most likely tokens whose probabilities add up to top_p
+              are kept (nucleus sampling), 1 keeps all of them
+
+The probability returned with a sample is the one of the chosen token in the
+final distribution it was drawn from, i.e. after masking, temperature, top-k
+and top-p and renormalizing.
+
+Random numbers come from a SplitMix64 generator seeded by the caller, so a run
+can be repeated exactly. See ffi.rs for the C interface used from Python.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SamplingConfig {
+    pub temperature: f32,
+    pub top_k: usize,
+    pub top_p: f32,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        return SamplingConfig { temperature: 1.0, top_k: 0, top_p: 1.0 };
+    }
+}
+
+impl SamplingConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        if !self.temperature.is_finite() || self.temperature < 0.0 {
+            return Err(anyhow!("Temperature must be a non negative number, got {}", self.temperature));
+        }
+        if !(self.top_p > 0.0 && self.top_p <= 1.0) {
+            return Err(anyhow!("top_p must be in (0, 1], got {}", self.top_p));
+        }
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledToken {
+    pub token: Token,
+    pub probability: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampledMove {
+    pub _move: Move,
+    pub uci: Uci,
+    pub token: Token,
+    pub probability: f32,
+}
+
+// SplitMix64, small and good enough for sampling
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        return Rng { state: seed };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        return z ^ (z >> 31);
+    }
+
+    /// Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+}
+
+pub struct Sampler {
+    config: SamplingConfig,
+    rng: Rng,
+}
+
+impl Sampler {
+    pub fn new(config: SamplingConfig, seed: u64) -> Result<Sampler, Error> {
+        config.validate()?;
+        return Ok(Sampler { config, rng: Rng::new(seed) });
+    }
+
+    pub fn config(&self) -> &SamplingConfig {
+        return &self.config;
+    }
+
+    /// The next move of `pos`, for schemes that write every move as a single token (uci)
+    pub fn sample_move(&mut self, tokenizer: &Tokenizer, pos: &VariantPosition, logits: &[f32]) -> Result<SampledMove, Error> {
+        let mut candidates: Vec<(Token, Move)> = Vec::new();
+        for _move in pos.legal_moves() {
+            // Moves that need disabled variant tokens can not be written
+            let tokens = match tokenizer.encode_move(pos, &_move) {
+                Ok(tokens) => tokens,
+                Err(_) => continue,
+            };
+            if tokens.len() != 1 {
+                return Err(anyhow!("The {} scheme writes moves as several tokens, sample them one at a time with sample_token", tokenizer.scheme().name()));
+            }
+            candidates.push((tokens[0], _move));
+        }
+
+        let tokens: Vec<Token> = candidates.iter().map(|(token, _)| *token).collect();
+        let sampled = self.sample_among(tokenizer, &tokens, logits)?;
+        let _move = candidates.into_iter().find(|(token, _)| *token == sampled.token).unwrap().1;
+        return Ok(SampledMove { uci: _move.to_uci(pos.castles().mode()), _move, token: sampled.token, probability: sampled.probability });
+    }
+
+    /// The next token of a move of `pos` starting with `prefix`, or <end> when `prefix` is empty,
+    /// see Tokenizer::legal_next_tokens
+    pub fn sample_token(&mut self, tokenizer: &Tokenizer, pos: &VariantPosition, prefix: &[Token], logits: &[f32]) -> Result<SampledToken, Error> {
+        let tokens = tokenizer.legal_next_tokens(pos, prefix);
+        return self.sample_among(tokenizer, &tokens, logits);
+    }
+
+    /// Samples one of `tokens`, for callers that restrict the legal tokens further
+    pub fn sample_among(&mut self, tokenizer: &Tokenizer, tokens: &[Token], logits: &[f32]) -> Result<SampledToken, Error> {
+        if logits.len() != tokenizer.vocab_size() {
+            return Err(anyhow!("Expected {} logits, got {}", tokenizer.vocab_size(), logits.len()));
+        }
+        if tokens.is_empty() {
+            return Err(anyhow!("No legal tokens to sample from"));
+        }
+
+        let mut candidates: Vec<(Token, f32)> = Vec::new();
+        for token in tokens {
+            let logit = logits[token.value as usize];
+            if logit.is_nan() {
+                return Err(anyhow!("Logit of token {} is NaN", token.value));
+            }
+            candidates.push((*token, logit));
+        }
+        // Most likely first, ties broken by token id
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.value.cmp(&b.0.value)));
+
+        if self.config.temperature == 0.0 {
+            return Ok(SampledToken { token: candidates[0].0, probability: 1.0 });
+        }
+        if self.config.top_k > 0 {
+            candidates.truncate(self.config.top_k);
+        }
+
+        let max = candidates[0].1 as f64;
+        let mut probabilities: Vec<f64> = candidates.iter().map(|(_, logit)| ((*logit as f64 - max) / self.config.temperature as f64).exp()).collect();
+        let total: f64 = probabilities.iter().sum();
+        probabilities.iter_mut().for_each(|p| *p /= total);
+
+        if self.config.top_p < 1.0 {
+            let mut cumulative = 0.0;
+            let mut keep = 0;
+            while keep < probabilities.len() && cumulative < self.config.top_p as f64 {
+                cumulative += probabilities[keep];
+                keep += 1;
+            }
+            probabilities.truncate(keep);
+            probabilities.iter_mut().for_each(|p| *p /= cumulative);
+        }
+
+        let point = self.rng.next_f64();
+        let mut cumulative = 0.0;
+        for (idx, probability) in probabilities.iter().enumerate() {
+            cumulative += probability;
+            if point < cumulative || idx + 1 == probabilities.len() {
+                return Ok(SampledToken { token: candidates[idx].0, probability: *probability as f32 });
+            }
+        }
+        unreachable!("probabilities are never empty");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{scheme::SchemeKind, variant::GameVariant};
+
+    use super::*;
+
+    fn tokenizer() -> Tokenizer {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        return tokenizer;
+    }
+
+    // Logits that strongly prefer an illegal move, then e2e4, then d2d4
+    fn logits(tokenizer: &Tokenizer) -> Vec<f32> {
+        let mut logits = vec![0.0; tokenizer.vocab_size()];
+        for (uci, logit) in [("e2e5", 20.0), ("e2e4", 10.0), ("d2d4", 9.0)] {
+            logits[tokenizer.uci_to_token(Uci::from_str(uci).unwrap()).unwrap().value as usize] = logit;
+        }
+        return logits;
+    }
+
+    #[test]
+    fn sample_move() {
+        let tokenizer = tokenizer();
+        let pos = GameVariant::Standard.start_position(None).unwrap();
+        let logits = logits(&tokenizer);
+
+        let mut greedy = Sampler::new(SamplingConfig { temperature: 0.0, ..Default::default() }, 0).unwrap();
+        let sampled = greedy.sample_move(&tokenizer, &pos, &logits).unwrap();
+        assert_eq!(sampled.uci.to_string(), "e2e4");
+        assert_eq!(sampled.probability, 1.0);
+
+        let mut top_k = Sampler::new(SamplingConfig { top_k: 2, ..Default::default() }, 7).unwrap();
+        for _ in 0..20 {
+            let sampled = top_k.sample_move(&tokenizer, &pos, &logits).unwrap();
+            assert!(["e2e4", "d2d4"].contains(&sampled.uci.to_string().as_str()));
+            assert!(sampled.probability > 0.25 && sampled.probability < 0.75);
+        }
+
+        // e2e4 alone holds more than half of the probability mass
+        let mut top_p = Sampler::new(SamplingConfig { top_p: 0.5, ..Default::default() }, 7).unwrap();
+        let sampled = top_p.sample_move(&tokenizer, &pos, &logits).unwrap();
+        assert_eq!(sampled.uci.to_string(), "e2e4");
+        assert_eq!(sampled.probability, 1.0);
+
+        // The same seed gives the same moves
+        let mut a = Sampler::new(SamplingConfig::default(), 3).unwrap();
+        let mut b = Sampler::new(SamplingConfig::default(), 3).unwrap();
+        let zeros = vec![0.0; tokenizer.vocab_size()];
+        for _ in 0..10 {
+            let sampled = a.sample_move(&tokenizer, &pos, &zeros).unwrap();
+            assert_eq!(sampled, b.sample_move(&tokenizer, &pos, &zeros).unwrap());
+            assert!((sampled.probability - 1.0 / 20.0).abs() < 1e-6);
+        }
+
+        assert!(a.sample_move(&tokenizer, &pos, &logits[1..]).is_err());
+        assert!(Sampler::new(SamplingConfig { top_p: 0.0, ..Default::default() }, 0).is_err());
+        let mate = GameVariant::Standard.start_position(Some("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1")).unwrap();
+        assert!(a.sample_move(&tokenizer, &mate, &zeros).is_err());
+    }
+
+    #[test]
+    fn sample_token() {
+        let mut tokenizer = Tokenizer::with_scheme(SchemeKind::Factored.create());
+        tokenizer.create_token_map();
+        let pos = GameVariant::Standard.start_position(None).unwrap();
+        let mut sampler = Sampler::new(SamplingConfig::default(), 0).unwrap();
+        let logits = vec![0.0; tokenizer.vocab_size()];
+        assert!(sampler.sample_move(&tokenizer, &pos, &logits).is_err());
+
+        let from = sampler.sample_token(&tokenizer, &pos, &[], &logits).unwrap();
+        assert!(tokenizer.legal_move_tokens(&pos).contains(&from.token));
+        if tokenizer.is_move_token(&from.token) {
+            let to = sampler.sample_token(&tokenizer, &pos, &[from.token], &logits).unwrap();
+            assert!(tokenizer.decode_move(&pos, &[from.token, to.token]).is_ok());
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, Error};
+use shakmaty::{san::SanPlus, uci::Uci, variant::VariantPosition, Bitboard, Board, Color, Move, Piece, Position, Rank, Role, Square};
+
+/*
+
+Move tokenization schemes. A scheme owns the move part of the vocabulary, the
+Tokenizer places its tokens right after the four fixed special tokens and adds
+the optional tokens after them, see tokenizer.rs.
+
+uci      - one token per possible uci move (e2e4, e7e8q, ...), the original scheme
+factored - <from:e2> <to:e4> [<promo:q>], two or three tokens per move
+san      - the characters of the SAN move (N, f, 3, x, +, O, -, ...) followed by a space
+
+Castling is written as in uci notation for the castling mode of the position:
+the king move to its target square (e1g1) in standard chess and the king taking
+its own rook (e1h1) in chess960.
+
+Moves only some variants have, piece drops (crazyhouse, N@f3) and promotions to
+a king (antichess), are kept out of the base vocabulary. A scheme lists their
+tokens separately in variant_vocabulary, indexed after the base vocabulary, and
+the Tokenizer only adds them when such a variant is enabled.
+
+*/
+
+pub trait MoveTokenizer: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Names of the move tokens, in id order
+    fn vocabulary(&self) -> Vec<String>;
+
+    /// Names of the drop and king promotion tokens, in id order after `vocabulary`
+    fn variant_vocabulary(&self) -> Vec<String>;
+
+    /// Encodes a legal move of `pos` as indexes into `vocabulary` followed by `variant_vocabulary`
+    fn encode_move(&self, pos: &VariantPosition, _move: &Move) -> Result<Vec<u16>, Error>;
+
+    /// Decodes the move at the start of `tokens` (indexes into `vocabulary` followed by `variant_vocabulary`),
+    /// returning it and the number of tokens it used
+    fn decode_move(&self, pos: &VariantPosition, tokens: &[u16]) -> Result<(Move, usize), Error>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum SchemeKind {
+    Uci,
+    Factored,
+    San,
+}
+
+impl SchemeKind {
+    pub const ALL: [SchemeKind; 3] = [SchemeKind::Uci, SchemeKind::Factored, SchemeKind::San];
+
+    pub fn create(&self) -> Box<dyn MoveTokenizer> {
+        return match self {
+            SchemeKind::Uci => Box::new(UciScheme::new()),
+            SchemeKind::Factored => Box::new(FactoredScheme::new()),
+            SchemeKind::San => Box::new(SanScheme::new()),
+        };
+    }
+}
+
+fn uci_to_move(uci: &Uci, pos: &VariantPosition) -> Result<Move, Error> {
+    return match uci.to_move(pos) {
+        Ok(_move) => Ok(_move),
+        Err(e) => Err(anyhow!("Illegal move {}: {}", uci, e)),
+    };
+}
+
+// Pieces that can be dropped in crazyhouse
+const DROPS: [Role; 5] = [Role::Pawn, Role::Knight, Role::Bishop, Role::Rook, Role::Queen];
+
+// Promotion squares of a pawn, white pawns on the 7th rank and black pawns on the 2nd rank
+fn promotion_squares(from_square: Square) -> Bitboard {
+    let backrank = match from_square.rank() {
+        Rank::Seventh => Bitboard::NORTH,
+        Rank::Second => Bitboard::SOUTH,
+        _ => return Bitboard::EMPTY,
+    };
+
+    let mut possible_moves = Bitboard::EMPTY;
+    for role in [Role::Rook, Role::Bishop] {
+        let mut board = Board::empty();
+        board.set_piece_at(from_square, Piece { color: Color::White, role });
+        possible_moves |= board.attacks_from(from_square);
+    }
+    return possible_moves & Bitboard::BACKRANKS & backrank;
+}
+
+pub struct UciScheme {
+    token_map: HashMap<Uci, u16>,
+    // The base vocabulary followed by the variant moves
+    ucis: Vec<Uci>,
+    n_base: usize,
+}
+
+impl Default for UciScheme {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UciScheme {
+    pub fn new() -> UciScheme {
+        let mut scheme = UciScheme { token_map: HashMap::new(), ucis: Vec::new(), n_base: 0 };
+        for from_square in Square::ALL {
+            let mut possible_moves = Bitboard::EMPTY;
+            for role in Role::ALL {
+                let mut board = Board::empty();
+                board.set_piece_at(from_square, Piece { color: Color::White, role });
+                possible_moves |= board.attacks_from(from_square);
+            }
+
+            for to_square in possible_moves {
+                scheme.insert(Uci::Normal { from: from_square, to: to_square, promotion: None });
+            }
+
+            for to_square in promotion_squares(from_square) {
+                for promotion in [Role::Bishop, Role::Knight, Role::Rook, Role::Queen] {
+                    scheme.insert(Uci::Normal { from: from_square, to: to_square, promotion: Some(promotion) });
+                }
+            }
+        }
+        scheme.n_base = scheme.ucis.len();
+
+        for from_square in Square::ALL {
+            for to_square in promotion_squares(from_square) {
+                scheme.insert(Uci::Normal { from: from_square, to: to_square, promotion: Some(Role::King) });
+            }
+        }
+        for role in DROPS {
+            for to_square in Square::ALL {
+                if role == Role::Pawn && Bitboard::BACKRANKS.contains(to_square) {
+                    continue;
+                }
+                scheme.insert(Uci::Put { role, to: to_square });
+            }
+        }
+        return scheme;
+    }
+
+    fn insert(&mut self, uci: Uci) {
+        self.token_map.insert(uci.clone(), self.ucis.len() as u16);
+        self.ucis.push(uci);
+    }
+}
+
+impl MoveTokenizer for UciScheme {
+    fn name(&self) -> &'static str {
+        return "uci";
+    }
+
+    fn vocabulary(&self) -> Vec<String> {
+        return self.ucis[..self.n_base].iter().map(|uci| uci.to_string()).collect();
+    }
+
+    fn variant_vocabulary(&self) -> Vec<String> {
+        return self.ucis[self.n_base..].iter().map(|uci| uci.to_string()).collect();
+    }
+
+    fn encode_move(&self, pos: &VariantPosition, _move: &Move) -> Result<Vec<u16>, Error> {
+        let uci = _move.to_uci(pos.castles().mode());
+        return match self.token_map.get(&uci) {
+            Some(idx) => Ok(Vec::from([*idx])),
+            None => Err(anyhow!("Invalid uci string found")),
+        };
+    }
+
+    fn decode_move(&self, pos: &VariantPosition, tokens: &[u16]) -> Result<(Move, usize), Error> {
+        let uci = match tokens.first().and_then(|idx| self.ucis.get(*idx as usize)) {
+            Some(uci) => uci,
+            None => return Err(anyhow!("Expected a move token")),
+        };
+        return Ok((uci_to_move(uci, pos)?, 1));
+    }
+}
+
+// 64 from squares, 64 to squares, 4 promotion roles, then the variant moves: 5 drop roles and the king promotion
+const PROMOTIONS: [Role; 4] = [Role::Knight, Role::Bishop, Role::Rook, Role::Queen];
+const DROP_BASE: u16 = 132;
+const KING_PROMOTION: u16 = 137;
+
+#[derive(Default)]
+pub struct FactoredScheme {}
+
+impl FactoredScheme {
+    pub fn new() -> FactoredScheme {
+        return FactoredScheme {};
+    }
+}
+
+impl MoveTokenizer for FactoredScheme {
+    fn name(&self) -> &'static str {
+        return "factored";
+    }
+
+    fn vocabulary(&self) -> Vec<String> {
+        let mut vocabulary: Vec<String> = Vec::new();
+        vocabulary.extend(Square::ALL.iter().map(|square| format!("<from:{}>", square)));
+        vocabulary.extend(Square::ALL.iter().map(|square| format!("<to:{}>", square)));
+        vocabulary.extend(PROMOTIONS.iter().map(|role| format!("<promo:{}>", role.char())));
+        return vocabulary;
+    }
+
+    fn variant_vocabulary(&self) -> Vec<String> {
+        let mut vocabulary: Vec<String> = DROPS.iter().map(|role| format!("<drop:{}>", role.upper_char())).collect();
+        vocabulary.push(format!("<promo:{}>", Role::King.char()));
+        return vocabulary;
+    }
+
+    fn encode_move(&self, pos: &VariantPosition, _move: &Move) -> Result<Vec<u16>, Error> {
+        return match _move.to_uci(pos.castles().mode()) {
+            Uci::Normal { from, to, promotion } => {
+                let mut tokens = Vec::from([u32::from(from) as u16, 64 + u32::from(to) as u16]);
+                match promotion {
+                    Some(Role::King) => tokens.push(KING_PROMOTION),
+                    Some(promotion) => match PROMOTIONS.iter().position(|role| *role == promotion) {
+                        Some(idx) => tokens.push(128 + idx as u16),
+                        None => return Err(anyhow!("Invalid promotion {:?}", promotion)),
+                    },
+                    None => {},
+                }
+                Ok(tokens)
+            },
+            Uci::Put { role, to } => match DROPS.iter().position(|r| *r == role) {
+                Some(idx) => Ok(Vec::from([DROP_BASE + idx as u16, 64 + u32::from(to) as u16])),
+                None => Err(anyhow!("Invalid drop {:?}", role)),
+            },
+            uci => Err(anyhow!("Unsupported move {}", uci)),
+        };
+    }
+
+    fn decode_move(&self, pos: &VariantPosition, tokens: &[u16]) -> Result<(Move, usize), Error> {
+        if let [drop, to, ..] = tokens {
+            if (DROP_BASE..KING_PROMOTION).contains(drop) && (64..128).contains(to) {
+                let uci = Uci::Put { role: DROPS[(*drop - DROP_BASE) as usize], to: Square::new((*to - 64) as u32) };
+                return Ok((uci_to_move(&uci, pos)?, 2));
+            }
+        }
+
+        let (from, to) = match tokens {
+            [from, to, ..] if *from < 64 && (64..128).contains(to) => (Square::new(*from as u32), Square::new((*to - 64) as u32)),
+            _ => return Err(anyhow!("Expected a <from:..> <to:..> token pair")),
+        };
+        let promotion = match tokens.get(2) {
+            Some(idx) if (128..132).contains(idx) => Some(PROMOTIONS[(*idx - 128) as usize]),
+            Some(&KING_PROMOTION) => Some(Role::King),
+            _ => None,
+        };
+        let uci = Uci::Normal { from, to, promotion };
+        return Ok((uci_to_move(&uci, pos)?, 2 + promotion.is_some() as usize));
+    }
+}
+
+// Every character that can appear in a SAN move, the last token ends the move
+const SAN_CHARS: &str = "abcdefgh12345678NBRQKxO-=+# ";
+// Drops, e.g. N@f3 (king promotions are written e8=K)
+const VARIANT_SAN_CHARS: &str = "@";
+
+pub struct SanScheme {
+    chars: Vec<char>,
+}
+
+impl Default for SanScheme {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SanScheme {
+    pub fn new() -> SanScheme {
+        return SanScheme { chars: SAN_CHARS.chars().chain(VARIANT_SAN_CHARS.chars()).collect() };
+    }
+}
+
+impl MoveTokenizer for SanScheme {
+    fn name(&self) -> &'static str {
+        return "san";
+    }
+
+    fn vocabulary(&self) -> Vec<String> {
+        return SAN_CHARS.chars().map(|c| c.to_string()).collect();
+    }
+
+    fn variant_vocabulary(&self) -> Vec<String> {
+        return VARIANT_SAN_CHARS.chars().map(|c| c.to_string()).collect();
+    }
+
+    fn encode_move(&self, pos: &VariantPosition, _move: &Move) -> Result<Vec<u16>, Error> {
+        let mut tokens = Vec::new();
+        for c in format!("{} ", SanPlus::from_move(pos.clone(), _move)).chars() {
+            match self.chars.iter().position(|v| *v == c) {
+                Some(idx) => tokens.push(idx as u16),
+                None => return Err(anyhow!("Unexpected SAN character {:?}", c)),
+            }
+        }
+        return Ok(tokens);
+    }
+
+    fn decode_move(&self, pos: &VariantPosition, tokens: &[u16]) -> Result<(Move, usize), Error> {
+        let mut san = String::new();
+        for (i, idx) in tokens.iter().enumerate() {
+            let c = match self.chars.get(*idx as usize) {
+                Some(c) => *c,
+                None => return Err(anyhow!("Expected a SAN character token")),
+            };
+            if c != ' ' {
+                san.push(c);
+                continue;
+            }
+
+            let san_plus = match SanPlus::from_str(&san) {
+                Ok(san_plus) => san_plus,
+                Err(e) => return Err(anyhow!("Invalid SAN {:?}: {}", san, e)),
+            };
+            return match san_plus.san.to_move(pos) {
+                Ok(_move) => Ok((_move, i + 1)),
+                Err(e) => Err(anyhow!("Illegal move {}: {}", san, e)),
+            };
+        }
+        return Err(anyhow!("Unterminated SAN move {:?}", san));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::variant::GameVariant;
+
+    use super::*;
+
+    const MOVES: [&str; 9] = ["e2e4", "d7d5", "e4d5", "d8d5", "g1f3", "d5e4", "f1e2", "c8g4", "e1g1"];
+
+    // Every scheme has to round trip a game with a capture, a check and castling
+    fn round_trip(scheme: &dyn MoveTokenizer) -> usize {
+        let mut pos = GameVariant::Standard.start_position(None).unwrap();
+        let mut n_tokens = 0;
+        for uci in MOVES {
+            let _move = Uci::from_str(uci).unwrap().to_move(&pos).unwrap();
+            let tokens = scheme.encode_move(&pos, &_move).unwrap();
+            assert!(tokens.iter().all(|idx| (*idx as usize) < scheme.vocabulary().len()));
+
+            let mut padded = tokens.clone();
+            padded.push(0);
+            assert_eq!(scheme.decode_move(&pos, &padded).unwrap(), (_move.clone(), tokens.len()));
+            n_tokens += tokens.len();
+            pos.play_unchecked(&_move);
+        }
+        return n_tokens;
+    }
+
+    #[test]
+    fn uci_scheme() {
+        let scheme = UciScheme::new();
+        assert_eq!(scheme.vocabulary().len(), 1968);
+        assert_eq!(round_trip(&scheme), MOVES.len());
+    }
+
+    #[test]
+    fn factored_scheme() {
+        let scheme = FactoredScheme::new();
+        assert_eq!(scheme.vocabulary().len(), 132);
+        assert_eq!(round_trip(&scheme), 2 * MOVES.len());
+
+        let pos = GameVariant::Standard.start_position(Some("8/4P3/8/8/8/8/k7/7K w - - 0 1")).unwrap();
+        let _move = Uci::from_str("e7e8n").unwrap().to_move(&pos).unwrap();
+        let tokens = scheme.encode_move(&pos, &_move).unwrap();
+        assert_eq!(tokens.iter().map(|idx| scheme.vocabulary()[*idx as usize].clone()).collect::<Vec<String>>(), ["<from:e7>", "<to:e8>", "<promo:n>"]);
+        assert_eq!(scheme.decode_move(&pos, &tokens).unwrap(), (_move, 3));
+    }
+
+    #[test]
+    fn san_scheme() {
+        let scheme = SanScheme::new();
+        assert!(round_trip(&scheme) > MOVES.len());
+
+        let pos = GameVariant::Standard.start_position(None).unwrap();
+        let _move = Uci::from_str("g1f3").unwrap().to_move(&pos).unwrap();
+        let tokens = scheme.encode_move(&pos, &_move).unwrap();
+        assert_eq!(tokens.iter().map(|idx| scheme.vocabulary()[*idx as usize].clone()).collect::<String>(), "Nf3 ");
+        assert!(scheme.decode_move(&pos, &tokens[..3]).is_err());
+    }
+
+    // Encodes a single move of a variant position, returning the token names
+    fn variant_round_trip(scheme: &dyn MoveTokenizer, variant: GameVariant, fen: &str, uci: &str) -> Vec<String> {
+        let pos = variant.start_position(Some(fen)).unwrap();
+        let _move = Uci::from_str(uci).unwrap().to_move(&pos).unwrap();
+        let tokens = scheme.encode_move(&pos, &_move).unwrap();
+        assert_eq!(scheme.decode_move(&pos, &tokens).unwrap(), (_move, tokens.len()));
+
+        let vocabulary: Vec<String> = scheme.vocabulary().into_iter().chain(scheme.variant_vocabulary()).collect();
+        return tokens.iter().map(|idx| vocabulary[*idx as usize].clone()).collect();
+    }
+
+    #[test]
+    fn variant_moves() {
+        let uci = UciScheme::new();
+        assert_eq!(uci.variant_vocabulary().len(), 44 + 48 + 4 * 64);
+        assert_eq!(variant_round_trip(&uci, GameVariant::Chess960, "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1h1"), ["e1h1"]);
+        assert_eq!(variant_round_trip(&uci, GameVariant::Crazyhouse, "4k3/8/8/8/8/8/8/4K3[N] w - - 0 1", "N@f3"), ["N@f3"]);
+        assert_eq!(variant_round_trip(&uci, GameVariant::Antichess, "8/4P3/8/8/8/8/8/k7 w - - 0 1", "e7e8k"), ["e7e8k"]);
+
+        let factored = FactoredScheme::new();
+        assert_eq!(variant_round_trip(&factored, GameVariant::Chess960, "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1a1"), ["<from:e1>", "<to:a1>"]);
+        assert_eq!(variant_round_trip(&factored, GameVariant::Crazyhouse, "4k3/8/8/8/8/8/8/4K3[P] w - - 0 1", "P@e4"), ["<drop:P>", "<to:e4>"]);
+        assert_eq!(variant_round_trip(&factored, GameVariant::Antichess, "8/4P3/8/8/8/8/8/k7 w - - 0 1", "e7e8k"), ["<from:e7>", "<to:e8>", "<promo:k>"]);
+
+        let san = SanScheme::new();
+        assert_eq!(variant_round_trip(&san, GameVariant::Crazyhouse, "4k3/8/8/8/8/8/8/4K3[N] w - - 0 1", "N@f3").concat(), "N@f3 ");
+        assert_eq!(variant_round_trip(&san, GameVariant::Chess960, "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1", "e1h1").concat(), "O-O ");
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+
+/*
+
+Games are assigned to the train / validation / test splits by hashing a stable
+key of the game (its result and move list) together with the split seed. The
+assignment depends on nothing else, so a game always lands in the same split
+no matter which source it came from, in which order it was read or how it is
+tokenized.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Split {
+    Train,
+    Validation,
+    Test,
+}
+
+impl Split {
+    pub const ALL: [Split; 3] = [Split::Train, Split::Validation, Split::Test];
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Split::Train => "train",
+            Split::Validation => "val",
+            Split::Test => "test",
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SplitConfig {
+    pub ratios: [f64; 3],
+    pub seed: u64,
+}
+
+impl SplitConfig {
+    /// Parses "train,val,test" ratios, e.g. "0.9,0.05,0.05"
+    pub fn parse(ratios: &str, seed: u64) -> Result<SplitConfig, Error> {
+        let values: Vec<f64> = match ratios.split(',').map(|v| v.trim().parse::<f64>()).collect() {
+            Ok(values) => values,
+            Err(e) => return Err(anyhow!("Invalid split ratios \"{}\": {}", ratios, e)),
+        };
+        if values.len() != 3 || values.iter().any(|v| !v.is_finite() || *v < 0.0) {
+            return Err(anyhow!("Expected three non negative split ratios, got \"{}\"", ratios));
+        }
+
+        let total: f64 = values.iter().sum();
+        if total <= 0.0 {
+            return Err(anyhow!("Split ratios must not all be zero"));
+        }
+        return Ok(SplitConfig { ratios: [values[0] / total, values[1] / total, values[2] / total], seed });
+    }
+
+    pub fn assign(&self, game_key: &str) -> Split {
+        let digest = sha256::digest(format!("{}:{}", self.seed, game_key));
+        let value = u64::from_str_radix(&digest[..16], 16).expect("sha256 digest is not hex");
+        let point = value as f64 / u64::MAX as f64;
+
+        let mut cumulative = 0.0;
+        let mut last = Split::Train;
+        for (split, ratio) in Split::ALL.iter().zip(self.ratios) {
+            if ratio > 0.0 {
+                cumulative += ratio;
+                last = *split;
+                if point < cumulative {
+                    return *split;
+                }
+            }
+        }
+        // Only reachable through rounding when point is ~1.0
+        return last;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(SplitConfig::parse("0.9,0.05,0.05", 1).unwrap().ratios, [0.9, 0.05, 0.05]);
+        assert_eq!(SplitConfig::parse("8, 1, 1", 1).unwrap().ratios, [0.8, 0.1, 0.1]);
+        assert!(SplitConfig::parse("0.9,0.1", 1).is_err());
+        assert!(SplitConfig::parse("0.9,-0.1,0.2", 1).is_err());
+        assert!(SplitConfig::parse("0,0,0", 1).is_err());
+        assert!(SplitConfig::parse("a,b,c", 1).is_err());
+    }
+
+    #[test]
+    fn assign() {
+        let config = SplitConfig::parse("0.8,0.1,0.1", 42).unwrap();
+        let mut counts = [0; 3];
+        for i in 0..10000 {
+            let key = format!("game {}", i);
+            let split = config.assign(&key);
+            assert_eq!(split, config.assign(&key));
+            counts[Split::ALL.iter().position(|s| *s == split).unwrap()] += 1;
+        }
+        assert!(counts[0] > 7600 && counts[0] < 8400, "{:?}", counts);
+        assert!(counts[1] > 800 && counts[1] < 1200, "{:?}", counts);
+        assert!(counts[2] > 800 && counts[2] < 1200, "{:?}", counts);
+
+        let other = SplitConfig::parse("0.8,0.1,0.1", 43).unwrap();
+        assert!((0..100).any(|i| config.assign(&format!("game {}", i)) != other.assign(&format!("game {}", i))));
+
+        let train_only = SplitConfig::parse("1,0,0", 42).unwrap();
+        assert!((0..1000).all(|i| train_only.assign(&format!("game {}", i)) == Split::Train));
+        let test_only = SplitConfig::parse("0,0,1", 42).unwrap();
+        assert!((0..1000).all(|i| test_only.assign(&format!("game {}", i)) == Split::Test));
+    }
+}
@@ -0,0 +1,254 @@
+use std::{collections::BTreeMap, fs};
+
+use anyhow::Error;
+use serde::{Deserialize, Serialize};
+use shakmaty::{Color, Outcome};
+
+use crate::{filter::FilterReason, manifest::write_atomically, rejection::RejectReason, tokenizer::{Token, Tokenizer}};
+
+/*
+
+Build statistics, per source and for the whole build, written as a JSON report
+that is rewritten after every completed source:
+
+{
+  "context_length": 256,      games longer than this many tokens are "long_games"
+  "length_bucket": 10,        plies per length_histogram entry
+  "vocabulary": [...],        token names, indexing the token_counts of every stats
+  "elapsed_secs": ...,
+  "total": { stats },
+  "sources": [{ "url": ..., "stats": { stats } }, ...]
+}
+
+Games, outcomes, plies, lengths and token counts cover the games written to
+the outputs. bytes_in is the decompressed PGN text read, bytes_out the token,
+index and mask data written. A resumed build keeps the sources of the report
+it finds and adds its own.
+
+*/
+
+pub const LENGTH_BUCKET: u64 = 10;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutcomeCounts {
+    pub white: u64,
+    pub black: u64,
+    pub draw: u64,
+    pub unknown: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GameStats {
+    pub games: u64,
+    pub outcomes: OutcomeCounts,
+    pub duplicate_games: u64,
+    pub unsupported_setup_games: u64,
+    pub unsupported_variant_games: u64,
+    pub filtered_games: BTreeMap<FilterReason, u64>,
+    // Every rejected game by reason, including the duplicates and filtered ones above
+    pub rejected_games: BTreeMap<RejectReason, u64>,
+    pub ply: u64,
+    pub tokens: u64,
+    pub long_games: u64,
+    pub long_game_fraction: f64,
+    pub length_histogram: Vec<u64>,
+    pub token_counts: Vec<u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub elapsed_secs: f64,
+}
+
+impl GameStats {
+    fn outcome_count(&mut self, outcome: Option<Outcome>) -> &mut u64 {
+        return match outcome {
+            Some(Outcome::Decisive { winner: Color::White }) => &mut self.outcomes.white,
+            Some(Outcome::Decisive { winner: Color::Black }) => &mut self.outcomes.black,
+            Some(Outcome::Draw) => &mut self.outcomes.draw,
+            None => &mut self.outcomes.unknown,
+        };
+    }
+
+    /// Counts a game written to the outputs, `context_length` decides whether it is a long game
+    pub fn add_game(&mut self, outcome: Option<Outcome>, ply: u64, tokens: &[Token], context_length: usize) {
+        self.games += 1;
+        *self.outcome_count(outcome) += 1;
+        self.ply += ply;
+        self.tokens += tokens.len() as u64;
+        if tokens.len() > context_length {
+            self.long_games += 1;
+        }
+        let bucket = (ply / LENGTH_BUCKET) as usize;
+        if self.length_histogram.len() <= bucket {
+            self.length_histogram.resize(bucket + 1, 0);
+        }
+        self.length_histogram[bucket] += 1;
+        for token in tokens {
+            let idx = token.value as usize;
+            if self.token_counts.len() <= idx {
+                self.token_counts.resize(idx + 1, 0);
+            }
+            self.token_counts[idx] += 1;
+        }
+    }
+
+    /// Undoes add_game, for a game that turned out to be a duplicate
+    pub fn remove_game(&mut self, outcome: Option<Outcome>, ply: u64, tokens: &[Token], context_length: usize) {
+        self.games -= 1;
+        *self.outcome_count(outcome) -= 1;
+        self.ply -= ply;
+        self.tokens -= tokens.len() as u64;
+        if tokens.len() > context_length {
+            self.long_games -= 1;
+        }
+        self.length_histogram[(ply / LENGTH_BUCKET) as usize] -= 1;
+        for token in tokens {
+            self.token_counts[token.value as usize] -= 1;
+        }
+    }
+
+    pub fn reject(&mut self, reason: RejectReason) {
+        *self.rejected_games.entry(reason).or_insert(0) += 1;
+    }
+
+    pub fn filtered_total(&self) -> u64 {
+        return self.filtered_games.values().sum();
+    }
+
+    pub fn merge(&mut self, other: &GameStats) {
+        self.games += other.games;
+        self.outcomes.white += other.outcomes.white;
+        self.outcomes.black += other.outcomes.black;
+        self.outcomes.draw += other.outcomes.draw;
+        self.outcomes.unknown += other.outcomes.unknown;
+        self.duplicate_games += other.duplicate_games;
+        self.unsupported_setup_games += other.unsupported_setup_games;
+        self.unsupported_variant_games += other.unsupported_variant_games;
+        for (reason, count) in &other.filtered_games {
+            *self.filtered_games.entry(*reason).or_insert(0) += count;
+        }
+        for (reason, count) in &other.rejected_games {
+            *self.rejected_games.entry(*reason).or_insert(0) += count;
+        }
+        self.ply += other.ply;
+        self.tokens += other.tokens;
+        self.long_games += other.long_games;
+        for (counts, other_counts) in [(&mut self.length_histogram, &other.length_histogram), (&mut self.token_counts, &other.token_counts)] {
+            if counts.len() < other_counts.len() {
+                counts.resize(other_counts.len(), 0);
+            }
+            for (count, other_count) in counts.iter_mut().zip(other_counts) {
+                *count += other_count;
+            }
+        }
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.elapsed_secs += other.elapsed_secs;
+        self.update_fraction();
+    }
+
+    pub fn update_fraction(&mut self) {
+        self.long_game_fraction = match self.games {
+            0 => 0.0,
+            games => self.long_games as f64 / games as f64,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceStats {
+    pub url: String,
+    pub stats: GameStats,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsReport {
+    pub context_length: usize,
+    pub length_bucket: u64,
+    pub vocabulary: Vec<String>,
+    pub elapsed_secs: f64,
+    pub total: GameStats,
+    pub sources: Vec<SourceStats>,
+}
+
+impl StatsReport {
+    pub fn new(tokenizer: &Tokenizer, context_length: usize) -> StatsReport {
+        return StatsReport {
+            context_length,
+            length_bucket: LENGTH_BUCKET,
+            vocabulary: tokenizer.vocabulary().into_iter().map(|(name, _)| name).collect(),
+            elapsed_secs: 0.0,
+            total: GameStats::default(),
+            sources: Vec::new(),
+        };
+    }
+
+    pub fn load(path: &str) -> Result<StatsReport, Error> {
+        return Ok(serde_json::from_str(&fs::read_to_string(path)?)?);
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        return write_atomically(path, serde_json::to_string_pretty(self)?.as_bytes());
+    }
+
+    pub fn add_source(&mut self, url: &str, mut stats: GameStats) {
+        stats.update_fraction();
+        self.total.merge(&stats);
+        self.sources.push(SourceStats { url: String::from(url), stats });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    fn tokens(values: &[u16]) -> Vec<Token> {
+        return values.iter().map(|value| Token { value: *value }).collect();
+    }
+
+    #[test]
+    fn stats() {
+        let mut a = GameStats::default();
+        a.add_game(Some(Outcome::Decisive { winner: Color::White }), 3, &tokens(&[0, 10, 11, 12, 3]), 4);
+        a.add_game(Some(Outcome::Draw), 25, &tokens(&[2, 10, 3]), 4);
+        a.add_game(None, 1, &tokens(&[2, 12, 3]), 4);
+        a.remove_game(None, 1, &tokens(&[2, 12, 3]), 4);
+        a.duplicate_games += 1;
+        a.reject(RejectReason::Duplicate);
+        assert_eq!(a.games, 2);
+        assert_eq!(a.outcomes, OutcomeCounts { white: 1, black: 0, draw: 1, unknown: 0 });
+        assert_eq!(a.tokens, 8);
+        assert_eq!(a.long_games, 1);
+        assert_eq!(a.length_histogram, [1, 0, 1]);
+        assert_eq!(a.token_counts, [1, 0, 1, 2, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+
+        let mut b = GameStats::default();
+        b.add_game(Some(Outcome::Decisive { winner: Color::Black }), 4, &tokens(&[1, 13, 3]), 4);
+        *b.filtered_games.entry(FilterReason::MinElo).or_insert(0) += 2;
+        b.reject(RejectReason::Filter);
+        b.reject(RejectReason::Filter);
+
+        let mut report = StatsReport { context_length: 4, length_bucket: LENGTH_BUCKET, vocabulary: Vec::new(), elapsed_secs: 1.5, total: GameStats::default(), sources: Vec::new() };
+        report.add_source("a.pgn", a);
+        report.add_source("b.pgn", b);
+        assert_eq!(report.sources[0].stats.long_game_fraction, 0.5);
+        assert_eq!(report.total.games, 3);
+        assert_eq!(report.total.long_game_fraction, 1.0 / 3.0);
+        assert_eq!(report.total.length_histogram, [2, 0, 1]);
+        assert_eq!(report.total.token_counts[3], 3);
+        assert_eq!(report.total.token_counts.len(), 14);
+        assert_eq!(report.total.filtered_total(), 2);
+        assert_eq!(report.total.rejected_games, BTreeMap::from([(RejectReason::Duplicate, 1), (RejectReason::Filter, 2)]));
+
+        let dir = TempDir::new("stats").unwrap();
+        let path = dir.path().join("stats.json");
+        report.save(path.to_str().unwrap()).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.contains("\"min_elo\": 2"));
+        assert!(json.contains("\"duplicate\": 1"));
+        let loaded = StatsReport::load(path.to_str().unwrap()).unwrap();
+        assert_eq!(loaded.sources, report.sources);
+        assert_eq!(loaded.total.token_counts, report.total.token_counts);
+    }
+}
@@ -1,8 +1,10 @@
 use anyhow::{anyhow, Ok};
 use serde::ser::{Serialize, Serializer};
 use serde_json::{json, Value};
-use shakmaty::{uci::Uci, Bitboard, Board, Color, Outcome, Piece, Role, Square};
-use std::collections::HashMap;
+use shakmaty::{fen::Epd, uci::Uci, variant::VariantPosition, Color, EnPassantMode, Move, Outcome, Position};
+use std::{collections::HashMap, fmt, fs, str::FromStr};
+
+use crate::{scheme::{MoveTokenizer, SchemeKind, UciScheme}, variant::GameVariant};
 
 /*
 
@@ -11,9 +13,40 @@ use std::collections::HashMap;
 2 - <start:DRAW> - Start of the game (draw)
 3 - <end> - End of the game
 
+4.. - move tokens of the tokenization scheme, by default one per possible uci
+      move, see scheme.rs
+
+Optionally followed by <start:UNKNOWN>, the start of a game with an unknown
+result ("*"), see Tokenizer::set_unknown_outcome_token.
+
+Optionally followed by Elo bucket tokens, see Tokenizer::set_elo_buckets. With
+bucket edges [1800, 2000] the white tokens are <elo_w:0-1800>, <elo_w:1800-2000>,
+<elo_w:2000+> and <elo_w:?> (rating unknown), then the same for black (elo_b).
+A game then starts with <start:..> <elo_w:..> <elo_b:..>.
+
+Optionally followed by the setup tokens <setup>, <setup_end> and one <fen:c>
+token per FEN character, see Tokenizer::set_setup_tokens. A game that does not
+start from the standard position then carries its start position (the FEN
+without the move counters) as <setup> <fen:..>... <setup_end> right before the
+first move.
+
+Optionally followed by the variant tokens, see Tokenizer::set_variants. Every
+enabled variant gets its own family of start tokens, e.g. <start:chess960:WHITE>,
+<start:chess960:BLACK>, <start:chess960:DRAW> (and <start:chess960:UNKNOWN> with
+the unknown result token), standard games keep the start tokens above. Then the
+drop and king promotion tokens of the scheme when crazyhouse or antichess is
+enabled, and with setup tokens the <fen:c> tokens of the characters only
+variant FENs contain. A game that is not set up starts from the default
+position of its variant.
+
+The uci move token ids follow the iteration order of Square::ALL and Bitboard
+in shakmaty, assets/vocab.json is the frozen copy of that map. The tokenizer tests
+fail when create_token_map diverges from it, and the builder can be made to
+refuse to run on a mismatch (--frozen-vocab).
+
 */
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Token {
     pub value: u16,
 }
@@ -33,74 +66,72 @@ impl Serialize for Token {
     }
 }
 
+// Ratings in [min, max), max is None for the top bucket
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EloBucket {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+impl fmt::Display for EloBucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self.max {
+            Some(max) => write!(f, "{}-{}", self.min, max),
+            None => write!(f, "{}+", self.min),
+        };
+    }
+}
+
 pub struct Tokenizer {
-    token_map: HashMap<Uci, Token>,
+    scheme: Box<dyn MoveTokenizer>,
+    move_tokens: Vec<String>,
+    move_ids: HashMap<String, u16>,
+    unknown_outcome: bool,
+    elo_buckets: Vec<u32>,
+    setup_tokens: bool,
+    variants: Vec<GameVariant>,
+    variant_move_tokens: Vec<String>,
+}
+
+// Every character of the first four FEN fields
+const FEN_CHARS: &str = "PNBRQKpnbrqkacdefghw12345678/- ";
+// Chess960 castling files (B is already a piece), crazyhouse pockets and promoted pieces, three-check counters
+const VARIANT_FEN_CHARS: &str = "ACDEFGH[]~+";
+// Outcome part of the variant start tokens, UNKNOWN only with the unknown result token
+const VARIANT_START_OUTCOMES: [&str; 4] = ["WHITE", "BLACK", "DRAW", "UNKNOWN"];
+
+impl Default for Tokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tokenizer {
     pub fn new() -> Tokenizer {
-        let tokenizer = Tokenizer { token_map: HashMap::new() };
-        return tokenizer;
+        return Tokenizer::with_scheme(Box::new(UciScheme::new()));
     }
 
-    pub fn create_token_map(&mut self) {
-        let mut idx = 4;
-        for from_square in Square::ALL {
-            let mut possible_moves = Bitboard::EMPTY;
-            for role in Role::ALL {
-                let mut board = Board::empty();
-                board.set_piece_at(from_square, Piece { color: Color::White, role: role });
-                possible_moves = possible_moves | board.attacks_from(from_square);
-            }
-
-            for to_square in possible_moves {
-                let uci = Uci::Normal { from: from_square, to: to_square, promotion: None };
-                self.token_map.insert(uci, Token { value: idx });
-                idx += 1;
-            }
+    pub fn with_scheme(scheme: Box<dyn MoveTokenizer>) -> Tokenizer {
+        let tokenizer = Tokenizer {
+            scheme,
+            move_tokens: Vec::new(),
+            move_ids: HashMap::new(),
+            unknown_outcome: false,
+            elo_buckets: Vec::new(),
+            setup_tokens: false,
+            variants: Vec::new(),
+            variant_move_tokens: Vec::new(),
+        };
+        return tokenizer;
+    }
 
-            match from_square {
-                Square::A7 | Square::B7 | Square::C7 | Square::D7 | Square::E7 | Square::F7 | Square::G7 | Square::H7 => {
-                    let mut possible_moves = Bitboard::EMPTY;
-                    for role in [Role::Rook, Role::Bishop] {
-                        let mut board = Board::empty();
-                        board.set_piece_at(from_square, Piece { color: Color::White, role: role });
-                        possible_moves = possible_moves | board.attacks_from(from_square);
-                    }
-                    possible_moves = possible_moves & Bitboard::BACKRANKS & Bitboard::NORTH;
-                    
-                    for to_square in possible_moves {
-                        for promotion in [Role::Bishop, Role::Knight, Role::Rook, Role::Queen] {
-                            let uci = Uci::Normal { from: from_square, to: to_square, promotion: Some(promotion) };
-                            self.token_map.insert(uci, Token { value: idx });
-                            idx += 1;
-                        }
-                    }
-                },
-                _ => {},
-            }
+    pub fn scheme(&self) -> &dyn MoveTokenizer {
+        return self.scheme.as_ref();
+    }
 
-            match from_square {
-                Square::A2 | Square::B2 | Square::C2 | Square::D2 | Square::E2 | Square::F2 | Square::G2 | Square::H2 => {
-                    let mut possible_moves = Bitboard::EMPTY;
-                    for role in [Role::Rook, Role::Bishop] {
-                        let mut board = Board::empty();
-                        board.set_piece_at(from_square, Piece { color: Color::White, role: role });
-                        possible_moves = possible_moves | board.attacks_from(from_square);
-                    }
-                    possible_moves = possible_moves & Bitboard::BACKRANKS & Bitboard::SOUTH;
-                    
-                    for to_square in possible_moves {
-                        for promotion in [Role::Bishop, Role::Knight, Role::Rook, Role::Queen] {
-                            let uci = Uci::Normal { from: from_square, to: to_square, promotion: Some(promotion) };
-                            self.token_map.insert(uci, Token { value: idx });
-                            idx += 1;
-                        }
-                    }
-                },
-                _ => {},
-            }
-        }
+    pub fn create_token_map(&mut self) {
+        self.move_tokens = self.scheme.vocabulary();
+        self.move_ids = self.move_tokens.iter().enumerate().map(|(idx, name)| (name.clone(), 4 + idx as u16)).collect();
     }
 
     pub fn game_start_token(&self, outcome: Option<Outcome>) -> Result<Token, anyhow::Error> {
@@ -112,39 +143,625 @@ impl Tokenizer {
                 },
                 Outcome::Draw => Ok(Token { value: 2 }),
             },
-            None => Err(anyhow!("Outcome not specified")),
+            None => match self.unknown_outcome {
+                true => Ok(self.unknown_outcome_token()),
+                false => Err(anyhow!("Outcome not specified")),
+            },
         };
     }
 
+    /// Enables the <start:UNKNOWN> token, used by game_start_token(None)
+    pub fn set_unknown_outcome_token(&mut self, enabled: bool) {
+        self.unknown_outcome = enabled;
+    }
+
+    pub fn has_unknown_outcome_token(&self) -> bool {
+        return self.unknown_outcome;
+    }
+
+    fn unknown_outcome_token(&self) -> Token {
+        return Token { value: (4 + self.move_tokens.len()) as u16 };
+    }
+
+    // uci_to_token / token_to_uci only work with the uci scheme, where every move token is named by its uci string
     pub fn uci_to_token(&self, uci: Uci) -> Result<Token, anyhow::Error> {
-        if self.token_map.contains_key(&uci) {
-            return Ok(Token { value: self.token_map[&uci].value });
+        let name = uci.to_string();
+        if let Some(value) = self.move_ids.get(&name) {
+            return Ok(Token { value: *value });
+        }
+        return match self.variant_move_tokens.iter().position(|variant_name| *variant_name == name) {
+            Some(idx) => Ok(Token { value: self.variant_move_base() + idx as u16 }),
+            None => Err(anyhow!("Invalid uci string found")),
+        };
+    }
+
+    pub fn token_to_uci(&self, token: &Token) -> Result<Uci, anyhow::Error> {
+        let name = match self.move_index(token) {
+            Some(idx) => self.move_tokens.iter().chain(&self.variant_move_tokens).nth(idx as usize).unwrap(),
+            None => return Err(anyhow!("Token {} is not a move token", token.value)),
+        };
+        return match Uci::from_str(name) {
+            Result::Ok(uci) => Ok(uci),
+            Err(_) => Err(anyhow!("Token {} is not a uci move token", token.value)),
+        };
+    }
+
+    pub fn is_move_token(&self, token: &Token) -> bool {
+        return self.move_index(token).is_some();
+    }
+
+    // Index of a move token into the scheme vocabulary followed by its variant vocabulary
+    fn move_index(&self, token: &Token) -> Option<u16> {
+        let value = token.value as usize;
+        if value >= 4 && value - 4 < self.move_tokens.len() {
+            return Some(token.value - 4);
         }
+        let base = self.variant_move_base() as usize;
+        if value >= base && value - base < self.variant_move_tokens.len() {
+            return Some((self.move_tokens.len() + value - base) as u16);
+        }
+        return None;
+    }
 
-        return Err(anyhow!("Invalid uci string found"));
+    fn move_token(&self, idx: u16) -> Result<Token, anyhow::Error> {
+        let idx = idx as usize;
+        if idx < self.move_tokens.len() {
+            return Ok(Token { value: 4 + idx as u16 });
+        }
+        if idx - self.move_tokens.len() < self.variant_move_tokens.len() {
+            return Ok(Token { value: self.variant_move_base() + (idx - self.move_tokens.len()) as u16 });
+        }
+        return Err(anyhow!("Move needs the variant move tokens, enable crazyhouse or antichess"));
+    }
+
+    /// The tokens of a legal move of `pos`
+    pub fn encode_move(&self, pos: &VariantPosition, _move: &Move) -> Result<Vec<Token>, anyhow::Error> {
+        return self.scheme.encode_move(pos, _move)?.into_iter().map(|idx| self.move_token(idx)).collect();
+    }
+
+    /// Decodes the move at the start of `tokens`, returning it and the number of tokens it used
+    pub fn decode_move(&self, pos: &VariantPosition, tokens: &[Token]) -> Result<(Move, usize), anyhow::Error> {
+        let indexes: Vec<u16> = tokens.iter().map_while(|token| self.move_index(token)).collect();
+        if indexes.is_empty() {
+            return match tokens.first() {
+                Some(token) => Err(anyhow!("Token {} is not a move token", token.value)),
+                None => Err(anyhow!("Expected a move token")),
+            };
+        }
+        return self.scheme.decode_move(pos, &indexes);
+    }
+
+    /// Every token that can follow `prefix` (the first tokens of a move) in `pos`, sorted by id. With an empty prefix
+    /// these are the first tokens of every legal move and <end>, a game may end in any position
+    pub fn legal_next_tokens(&self, pos: &VariantPosition, prefix: &[Token]) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        if prefix.is_empty() {
+            tokens.push(self.game_end_token());
+        }
+        for _move in pos.legal_moves() {
+            // Moves that need disabled variant tokens can not be written
+            if let Result::Ok(move_tokens) = self.encode_move(pos, &_move) {
+                if move_tokens.len() > prefix.len() && move_tokens.starts_with(prefix) {
+                    tokens.push(move_tokens[prefix.len()]);
+                }
+            }
+        }
+        tokens.sort_by_key(|token| token.value);
+        tokens.dedup();
+        return tokens;
+    }
+
+    pub fn legal_move_tokens(&self, pos: &VariantPosition) -> Vec<Token> {
+        return self.legal_next_tokens(pos, &[]);
+    }
+
+    /// legal_next_tokens as a vocab_size long mask
+    pub fn legal_move_mask(&self, pos: &VariantPosition, prefix: &[Token]) -> Vec<bool> {
+        let mut mask = vec![false; self.vocab_size()];
+        for token in self.legal_next_tokens(pos, prefix) {
+            mask[token.value as usize] = true;
+        }
+        return mask;
+    }
+
+    /// Bytes of a packed token mask, see pack_mask
+    pub fn mask_size(&self) -> usize {
+        return self.vocab_size().div_ceil(8);
+    }
+
+    /// Packs a set of tokens into mask_size bytes, bit (id % 8) of byte (id / 8) is set for every token
+    pub fn pack_mask(&self, tokens: &[Token]) -> Vec<u8> {
+        let mut mask = vec![0u8; self.mask_size()];
+        for token in tokens {
+            mask[token.value as usize / 8] |= 1 << (token.value % 8);
+        }
+        return mask;
     }
 
     pub fn game_end_token(&self) -> Token {
         return Token { value: 3 };
     }
 
+    pub fn is_game_start_token(&self, token: &Token) -> bool {
+        return token.value <= 2 || (self.unknown_outcome && *token == self.unknown_outcome_token()) || self.variant_start_offset(token).is_some();
+    }
+
+    pub fn is_game_end_token(&self, token: &Token) -> bool {
+        return *token == self.game_end_token();
+    }
+
+    pub fn token_to_outcome(&self, token: &Token) -> Result<Outcome, anyhow::Error> {
+        return match self.token_to_game_outcome(token)? {
+            Some(outcome) => Ok(outcome),
+            None => Err(anyhow!("Game outcome is unknown")),
+        };
+    }
+
+    /// Like token_to_outcome, but maps <start:UNKNOWN> (and <start:variant:UNKNOWN>) to None
+    pub fn token_to_game_outcome(&self, token: &Token) -> Result<Option<Outcome>, anyhow::Error> {
+        let offset = match self.variant_start_offset(token) {
+            Some((_, offset)) => offset,
+            None if self.unknown_outcome && *token == self.unknown_outcome_token() => 3,
+            None => token.value as usize,
+        };
+        return match offset {
+            0 => Ok(Some(Outcome::Decisive { winner: Color::White })),
+            1 => Ok(Some(Outcome::Decisive { winner: Color::Black })),
+            2 => Ok(Some(Outcome::Draw)),
+            3 if self.is_game_start_token(token) => Ok(None),
+            _ => Err(anyhow!("Token {} is not a game start token", token.value)),
+        };
+    }
+
+    /// Enables Elo conditioning tokens with the given bucket edges, e.g. [1200, 1400, ..., 2800]
+    pub fn set_elo_buckets(&mut self, edges: &[u32]) -> Result<(), anyhow::Error> {
+        if edges.windows(2).any(|w| w[0] >= w[1]) || edges.first() == Some(&0) {
+            return Err(anyhow!("Elo bucket edges must be positive and strictly increasing"));
+        }
+        self.elo_buckets = Vec::from(edges);
+        return Ok(());
+    }
+
+    pub fn elo_buckets(&self) -> &[u32] {
+        return &self.elo_buckets;
+    }
+
+    // Buckets of one side, including the unknown rating bucket
+    fn elo_tokens_per_side(&self) -> usize {
+        return match self.elo_buckets.is_empty() {
+            true => 0,
+            false => self.elo_buckets.len() + 2,
+        };
+    }
+
+    fn elo_token_base(&self) -> u16 {
+        return (4 + self.move_tokens.len() + self.unknown_outcome as usize) as u16;
+    }
+
+    fn elo_bucket(&self, idx: usize) -> Option<EloBucket> {
+        if idx > self.elo_buckets.len() {
+            return None;
+        }
+        let min = match idx {
+            0 => 0,
+            _ => self.elo_buckets[idx - 1],
+        };
+        return Some(EloBucket { min, max: self.elo_buckets.get(idx).copied() });
+    }
+
+    /// The rating bucket token of one side, None when Elo tokens are disabled
+    pub fn elo_token(&self, color: Color, elo: Option<u32>) -> Option<Token> {
+        if self.elo_buckets.is_empty() {
+            return None;
+        }
+        let idx = match elo {
+            Some(elo) => self.elo_buckets.iter().take_while(|edge| elo >= **edge).count(),
+            None => self.elo_buckets.len() + 1,
+        };
+        let side = match color {
+            Color::White => 0,
+            Color::Black => self.elo_tokens_per_side(),
+        };
+        return Some(Token { value: self.elo_token_base() + (side + idx) as u16 });
+    }
+
+    pub fn is_elo_token(&self, token: &Token) -> bool {
+        let base = self.elo_token_base() as usize;
+        return (token.value as usize) >= base && (token.value as usize) < base + 2 * self.elo_tokens_per_side();
+    }
+
+    /// The side and rating bucket of an Elo token, the bucket is None for an unknown rating
+    pub fn token_to_elo(&self, token: &Token) -> Result<(Color, Option<EloBucket>), anyhow::Error> {
+        if !self.is_elo_token(token) {
+            return Err(anyhow!("Token {} is not an Elo token", token.value));
+        }
+        let offset = (token.value - self.elo_token_base()) as usize;
+        let per_side = self.elo_tokens_per_side();
+        let color = match offset < per_side {
+            true => Color::White,
+            false => Color::Black,
+        };
+        return Ok((color, self.elo_bucket(offset % per_side)));
+    }
+
+    /// Enables the <setup> prefix for games that do not start from the standard position
+    pub fn set_setup_tokens(&mut self, enabled: bool) {
+        self.setup_tokens = enabled;
+    }
+
+    pub fn has_setup_tokens(&self) -> bool {
+        return self.setup_tokens;
+    }
+
+    fn setup_token_base(&self) -> u16 {
+        return self.elo_token_base() + 2 * self.elo_tokens_per_side() as u16;
+    }
+
+    fn setup_token_count(&self) -> usize {
+        return match self.setup_tokens {
+            true => 2 + FEN_CHARS.len(),
+            false => 0,
+        };
+    }
+
+    pub fn is_setup_token(&self, token: &Token) -> bool {
+        let base = self.setup_token_base() as usize;
+        return ((token.value as usize) >= base && (token.value as usize) < base + self.setup_token_count()) || self.token_to_fen_char(token).is_some();
+    }
+
+    fn fen_char_token(&self, c: char) -> Option<Token> {
+        if let Some(idx) = FEN_CHARS.find(c) {
+            return Some(Token { value: self.setup_token_base() + 2 + idx as u16 });
+        }
+        return match VARIANT_FEN_CHARS.find(c) {
+            Some(idx) if self.variant_fen_count() > 0 => Some(Token { value: self.variant_fen_base() + idx as u16 }),
+            _ => None,
+        };
+    }
+
+    fn token_to_fen_char(&self, token: &Token) -> Option<char> {
+        let base = self.setup_token_base() as usize + 2;
+        let value = token.value as usize;
+        if self.setup_tokens && value >= base && value - base < FEN_CHARS.len() {
+            return Some(FEN_CHARS.as_bytes()[value - base] as char);
+        }
+        let base = self.variant_fen_base() as usize;
+        if value >= base && value - base < self.variant_fen_count() {
+            return Some(VARIANT_FEN_CHARS.as_bytes()[value - base] as char);
+        }
+        return None;
+    }
+
+    /// The setup prefix of a game starting from `pos`, empty for the default start position of its variant
+    pub fn setup_tokens(&self, pos: &VariantPosition) -> Result<Vec<Token>, anyhow::Error> {
+        let epd = Epd::from_position(pos.clone(), EnPassantMode::Legal).to_string();
+        let default = GameVariant::of(pos).start_position(None)?;
+        if epd == Epd::from_position(default, EnPassantMode::Legal).to_string() {
+            return Ok(Vec::new());
+        }
+        if !self.setup_tokens {
+            return Err(anyhow!("Setup tokens are disabled, unable to encode the position {}", epd));
+        }
+
+        let base = self.setup_token_base();
+        let mut tokens = Vec::from([Token { value: base }]);
+        for c in epd.chars() {
+            match self.fen_char_token(c) {
+                Some(token) => tokens.push(token),
+                None => return Err(anyhow!("Unexpected FEN character {:?}", c)),
+            }
+        }
+        tokens.push(Token { value: base + 1 });
+        return Ok(tokens);
+    }
+
+    /// The tokens written before the first move of a game from `start`: the start token claiming `outcome`, the Elo
+    /// tokens of both players (when the vocabulary has Elo buckets, None is an unknown rating) and the setup prefix
+    pub fn game_prefix(&self, start: &VariantPosition, outcome: Option<Outcome>, white_elo: Option<u32>, black_elo: Option<u32>) -> Result<Vec<Token>, anyhow::Error> {
+        let mut tokens = vec![self.variant_start_token(GameVariant::of(start), outcome)?];
+        for (color, elo) in [(Color::White, white_elo), (Color::Black, black_elo)] {
+            if let Some(token) = self.elo_token(color, elo) {
+                tokens.push(token);
+            }
+        }
+        tokens.extend(self.setup_tokens(start)?);
+        return Ok(tokens);
+    }
+
+    /// The tokens of the game `moves` played from `start`, as written by the builder without <end>
+    pub fn game_tokens(&self, start: &VariantPosition, moves: &[Move], outcome: Option<Outcome>, white_elo: Option<u32>, black_elo: Option<u32>) -> Result<Vec<Token>, anyhow::Error> {
+        let mut tokens = self.game_prefix(start, outcome, white_elo, black_elo)?;
+        let mut pos = start.clone();
+        for _move in moves {
+            tokens.extend(self.encode_move(&pos, _move)?);
+            pos.play_unchecked(_move);
+        }
+        return Ok(tokens);
+    }
+
+    /// Decodes a setup prefix at the start of `tokens` for a game of `variant`, returning the position, its FEN and the
+    /// number of tokens used. Returns None when `tokens` do not start with <setup>
+    pub fn decode_setup(&self, tokens: &[Token], variant: GameVariant) -> Result<Option<(VariantPosition, String, usize)>, anyhow::Error> {
+        let base = self.setup_token_base();
+        if !self.setup_tokens || tokens.first() != Some(&Token { value: base }) {
+            return Ok(None);
+        }
+
+        let mut epd = String::new();
+        for (i, token) in tokens.iter().enumerate().skip(1) {
+            if *token == (Token { value: base + 1 }) {
+                let fen = format!("{} 0 1", epd);
+                let pos = match variant.start_position(Some(&fen)) {
+                    Result::Ok(pos) => pos,
+                    Err(e) => return Err(anyhow!("Invalid setup position {}: {}", epd, e)),
+                };
+                return Ok(Some((pos, fen, i + 1)));
+            }
+            match self.token_to_fen_char(token) {
+                Some(c) => epd.push(c),
+                None => return Err(anyhow!("Unexpected token {} in setup", token.value)),
+            }
+        }
+        return Err(anyhow!("Unterminated setup"));
+    }
+
+    /// Enables the start tokens of these variants (standard games always use the plain start tokens), and the
+    /// drop / king promotion move tokens when crazyhouse or antichess is among them
+    pub fn set_variants(&mut self, variants: &[GameVariant]) {
+        let mut variants: Vec<GameVariant> = variants.iter().copied().filter(|variant| *variant != GameVariant::Standard).collect();
+        variants.sort();
+        variants.dedup();
+        self.variant_move_tokens = match variants.iter().any(|variant| variant.has_variant_moves()) {
+            true => self.scheme.variant_vocabulary(),
+            false => Vec::new(),
+        };
+        self.variants = variants;
+    }
+
+    pub fn variants(&self) -> &[GameVariant] {
+        return &self.variants;
+    }
+
+    pub fn supports_variant(&self, variant: GameVariant) -> bool {
+        return variant == GameVariant::Standard || self.variants.contains(&variant);
+    }
+
+    fn start_tokens_per_variant(&self) -> usize {
+        return 3 + self.unknown_outcome as usize;
+    }
+
+    fn variant_token_base(&self) -> u16 {
+        return self.setup_token_base() + self.setup_token_count() as u16;
+    }
+
+    fn variant_move_base(&self) -> u16 {
+        return self.variant_token_base() + (self.variants.len() * self.start_tokens_per_variant()) as u16;
+    }
+
+    fn variant_fen_base(&self) -> u16 {
+        return self.variant_move_base() + self.variant_move_tokens.len() as u16;
+    }
+
+    fn variant_fen_count(&self) -> usize {
+        return match self.setup_tokens && !self.variants.is_empty() {
+            true => VARIANT_FEN_CHARS.len(),
+            false => 0,
+        };
+    }
+
+    // The variant index and the outcome offset (WHITE, BLACK, DRAW, UNKNOWN) of a variant start token
+    fn variant_start_offset(&self, token: &Token) -> Option<(usize, usize)> {
+        let base = self.variant_token_base() as usize;
+        let per_variant = self.start_tokens_per_variant();
+        let value = token.value as usize;
+        if value < base || value >= base + self.variants.len() * per_variant {
+            return None;
+        }
+        return Some(((value - base) / per_variant, (value - base) % per_variant));
+    }
+
+    /// The start token of a game of `variant`, game_start_token for standard games
+    pub fn variant_start_token(&self, variant: GameVariant, outcome: Option<Outcome>) -> Result<Token, anyhow::Error> {
+        if variant == GameVariant::Standard {
+            return self.game_start_token(outcome);
+        }
+        let idx = match self.variants.iter().position(|v| *v == variant) {
+            Some(idx) => idx,
+            None => return Err(anyhow!("Variant {} is not enabled", variant.name())),
+        };
+        let offset = match outcome {
+            Some(Outcome::Decisive { winner: Color::White }) => 0,
+            Some(Outcome::Decisive { winner: Color::Black }) => 1,
+            Some(Outcome::Draw) => 2,
+            None => match self.unknown_outcome {
+                true => 3,
+                false => return Err(anyhow!("Outcome not specified")),
+            },
+        };
+        return Ok(Token { value: self.variant_token_base() + (idx * self.start_tokens_per_variant() + offset) as u16 });
+    }
+
+    /// The variant of a game start token
+    pub fn token_to_variant(&self, token: &Token) -> Result<GameVariant, anyhow::Error> {
+        if let Some((idx, _)) = self.variant_start_offset(token) {
+            return Ok(self.variants[idx]);
+        }
+        return match self.is_game_start_token(token) {
+            true => Ok(GameVariant::Standard),
+            false => Err(anyhow!("Token {} is not a game start token", token.value)),
+        };
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        let variant_tokens = self.variants.len() * self.start_tokens_per_variant() + self.variant_move_tokens.len() + self.variant_fen_count();
+        return 4 + self.move_tokens.len() + self.unknown_outcome as usize + 2 * self.elo_tokens_per_side() + self.setup_token_count() + variant_tokens;
+    }
+
+    /// Every (name, id) pair of the vocabulary, ordered by id
+    pub fn vocabulary(&self) -> Vec<(String, u16)> {
+        let mut vocabulary = Vec::from([
+            (String::from("<start:WHITE>"), 0),
+            (String::from("<start:BLACK>"), 1),
+            (String::from("<start:DRAW>"), 2),
+            (String::from("<end>"), 3),
+        ]);
+        for (idx, name) in self.move_tokens.iter().enumerate() {
+            vocabulary.push((name.clone(), 4 + idx as u16));
+        }
+        if self.unknown_outcome {
+            vocabulary.push((String::from("<start:UNKNOWN>"), self.unknown_outcome_token().value));
+        }
+        for (color, side) in [(Color::White, "w"), (Color::Black, "b")] {
+            for idx in 0..self.elo_tokens_per_side() {
+                let label = match self.elo_bucket(idx) {
+                    Some(bucket) => bucket.to_string(),
+                    None => String::from("?"),
+                };
+                let elo = self.elo_bucket(idx).map(|bucket| bucket.min);
+                vocabulary.push((format!("<elo_{}:{}>", side, label), self.elo_token(color, elo).unwrap().value));
+            }
+        }
+        if self.setup_tokens {
+            let base = self.setup_token_base();
+            vocabulary.push((String::from("<setup>"), base));
+            vocabulary.push((String::from("<setup_end>"), base + 1));
+            for (idx, c) in FEN_CHARS.chars().enumerate() {
+                vocabulary.push((format!("<fen:{}>", c), base + 2 + idx as u16));
+            }
+        }
+        for (idx, variant) in self.variants.iter().enumerate() {
+            for (offset, outcome) in VARIANT_START_OUTCOMES[..self.start_tokens_per_variant()].iter().enumerate() {
+                let value = self.variant_token_base() + (idx * self.start_tokens_per_variant() + offset) as u16;
+                vocabulary.push((format!("<start:{}:{}>", variant.name(), outcome), value));
+            }
+        }
+        for (idx, name) in self.variant_move_tokens.iter().enumerate() {
+            vocabulary.push((name.clone(), self.variant_move_base() + idx as u16));
+        }
+        for (idx, c) in VARIANT_FEN_CHARS.chars().take(self.variant_fen_count()).enumerate() {
+            vocabulary.push((format!("<fen:{}>", c), self.variant_fen_base() + idx as u16));
+        }
+        vocabulary.sort_by_key(|(_, value)| *value);
+        return vocabulary;
+    }
+
+    /// Stable hash of the token map, used to tie a dataset to the vocabulary that produced it
+    pub fn fingerprint(&self) -> u64 {
+        let mut data = String::new();
+        for (name, value) in self.vocabulary() {
+            data.push_str(&format!("{}={}\n", name, value));
+        }
+        let digest = sha256::digest(data);
+        return u64::from_str_radix(&digest[..16], 16).expect("sha256 digest is not hex");
+    }
+
     pub fn save(&self, path: &str) {
         let mut data: serde_json::Map<String, Value> = serde_json::Map::new();
-        data.insert(String::from("<start:WHITE>"), json!(0));
-        data.insert(String::from("<start:BLACK>"), json!(1));
-        data.insert(String::from("<start:DRAW>"), json!(2));
-        data.insert(String::from("<end>"), json!(3));
-        for (uci, token) in &self.token_map {
-            data.insert(String::from(uci.to_string()), json!(token));
+        for (name, value) in self.vocabulary() {
+            data.insert(name, json!(value));
         }
         let data = Value::Object(data);
-        std::fs::write(path, data.to_string()).expect("Unexpected error occured");
+        std::fs::write(path, serde_json::to_string_pretty(&data).expect("Unexpected error occured")).expect("Unexpected error occured");
+    }
+
+    /// Loads an encoder json written by `save`
+    pub fn load(path: &str) -> Result<Tokenizer, anyhow::Error> {
+        let data: HashMap<String, u16> = match serde_json::from_str(&fs::read_to_string(path)?) {
+            Result::Ok(data) => data,
+            Err(e) => return Err(anyhow!("Invalid vocabulary file {}: {}", path, e)),
+        };
+        return Tokenizer::from_vocabulary(data.into_iter().collect());
+    }
+
+    /// Rebuilds a tokenizer from (name, id) pairs, they have to follow the token layout described at the top of this file
+    pub fn from_vocabulary(mut vocabulary: Vec<(String, u16)>) -> Result<Tokenizer, anyhow::Error> {
+        vocabulary.sort_by_key(|(_, value)| *value);
+        if vocabulary.iter().enumerate().any(|(idx, (_, value))| *value as usize != idx) {
+            return Err(anyhow!("Vocabulary ids are not contiguous"));
+        }
+
+        let mut unknown_outcome = false;
+        let mut setup_tokens = false;
+        let mut elo_buckets = Vec::new();
+        let mut variants = Vec::new();
+        let mut move_tokens = Vec::new();
+        for (name, _) in &vocabulary {
+            match name.as_str() {
+                "<start:WHITE>" | "<start:BLACK>" | "<start:DRAW>" | "<end>" | "<elo_w:?>" => {},
+                "<start:UNKNOWN>" => unknown_outcome = true,
+                // Variant start tokens, e.g. <start:chess960:WHITE>
+                name if name.starts_with("<start:") => match name.trim_start_matches("<start:").split_once(':') {
+                    Some((variant, _)) => variants.push(GameVariant::from_tag(Some(variant))?),
+                    None => return Err(anyhow!("Invalid start token {}", name)),
+                },
+                name if name.starts_with("<elo_w:") => {
+                    // Every bucket but the top one ends at the next edge, e.g. <elo_w:1800-2000>
+                    if let Some((_, max)) = name.trim_end_matches('>').split_once('-') {
+                        match max.parse::<u32>() {
+                            Result::Ok(max) => elo_buckets.push(max),
+                            Err(_) => return Err(anyhow!("Invalid Elo token {}", name)),
+                        }
+                    }
+                },
+                name if name.starts_with("<elo_b:") => {},
+                "<setup>" => setup_tokens = true,
+                name if name == "<setup_end>" || name.starts_with("<fen:") => {},
+                name => move_tokens.push(String::from(name)),
+            }
+        }
+
+        // The variant move tokens follow the move tokens of the scheme, they are checked against the rebuilt vocabulary below
+        let scheme = match SchemeKind::ALL.iter().map(|kind| kind.create()).find(|scheme| move_tokens.starts_with(&scheme.vocabulary())) {
+            Some(scheme) => scheme,
+            None => return Err(anyhow!("Move tokens do not match any tokenization scheme")),
+        };
+        let mut tokenizer = Tokenizer::with_scheme(scheme);
+        tokenizer.create_token_map();
+        tokenizer.set_unknown_outcome_token(unknown_outcome);
+        tokenizer.set_elo_buckets(&elo_buckets)?;
+        tokenizer.set_setup_tokens(setup_tokens);
+        tokenizer.set_variants(&variants);
+
+        if tokenizer.vocabulary() != vocabulary {
+            return Err(anyhow!("Vocabulary does not follow the expected token layout"));
+        }
+        return Ok(tokenizer);
+    }
+
+    /// Checks that both tokenizers assign the same ids to the start, end and move tokens
+    pub fn check_compatible(&self, other: &Tokenizer) -> Result<(), anyhow::Error> {
+        if self.scheme.name() != other.scheme.name() {
+            return Err(anyhow!("Tokenization schemes differ ({} != {})", self.scheme.name(), other.scheme.name()));
+        }
+
+        let mut mismatches: Vec<String> = Vec::new();
+        for (name, value) in &self.move_ids {
+            match other.move_ids.get(name) {
+                Some(other_value) if other_value == value => {},
+                Some(other_value) => mismatches.push(format!("{} ({} != {})", name, value, other_value)),
+                None => mismatches.push(format!("{} (missing)", name)),
+            }
+        }
+        for name in other.move_ids.keys() {
+            if !self.move_ids.contains_key(name) {
+                mismatches.push(format!("{} (unexpected)", name));
+            }
+        }
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        mismatches.sort();
+        return Err(anyhow!("{} move tokens differ, e.g. {}", mismatches.len(), mismatches[..mismatches.len().min(5)].join(", ")));
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{io::Read, str::FromStr};
+    use std::io::Read;
+
+    use shakmaty::Position;
+    use tempdir::TempDir;
 
     use super::*;
 
@@ -165,11 +782,33 @@ mod tests {
         let mut uci_strings = String::new();
         std::fs::File::read_to_string(&mut std::fs::File::open("assets/uci_strings.txt").unwrap(), &mut uci_strings).expect("Unexpected error occured");
 
-        assert_eq!(uci_strings.lines().count(), tokenizer.token_map.len());
+        assert_eq!(uci_strings.lines().count(), tokenizer.move_tokens.len());
         for uci in uci_strings.lines() {
             let uci = Uci::from_str(uci).unwrap();
-            assert!(tokenizer.token_map.contains_key(&uci));
+            assert!(tokenizer.uci_to_token(uci).is_ok());
+        }
+    }
+
+    #[test]
+    fn token_to_uci() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+
+        for (name, value) in &tokenizer.move_ids {
+            assert_eq!(tokenizer.token_to_uci(&Token { value: *value }).unwrap().to_string(), *name);
+        }
+        assert!(tokenizer.token_to_uci(&tokenizer.game_end_token()).is_err());
+    }
+
+    #[test]
+    fn token_to_outcome() {
+        let tokenizer = Tokenizer::new();
+        for outcome in [Outcome::Decisive { winner: Color::White }, Outcome::Decisive { winner: Color::Black }, Outcome::Draw] {
+            let token = tokenizer.game_start_token(Some(outcome)).unwrap();
+            assert!(tokenizer.is_game_start_token(&token));
+            assert_eq!(tokenizer.token_to_outcome(&token).unwrap(), outcome);
         }
+        assert!(tokenizer.token_to_outcome(&tokenizer.game_end_token()).is_err());
     }
 
     #[test]
@@ -178,10 +817,269 @@ mod tests {
         assert_eq!(tokenizer.game_end_token(), Token { value: 3 });
     }
 
+    #[test]
+    fn fingerprint() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        assert_eq!(tokenizer.vocab_size(), 1972);
+        assert_eq!(tokenizer.vocabulary().len(), tokenizer.vocab_size());
+
+        let mut other = Tokenizer::new();
+        other.create_token_map();
+        assert_eq!(tokenizer.fingerprint(), other.fingerprint());
+        assert_ne!(tokenizer.fingerprint(), Tokenizer::new().fingerprint());
+    }
+
+    #[test]
+    fn unknown_outcome_token() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        tokenizer.set_unknown_outcome_token(true);
+        tokenizer.set_elo_buckets(&[2000]).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 1972 + 1 + 6);
+
+        let token = tokenizer.game_start_token(None).unwrap();
+        assert_eq!(token, Token { value: 1972 });
+        assert!(tokenizer.is_game_start_token(&token));
+        assert_eq!(tokenizer.token_to_game_outcome(&token).unwrap(), None);
+        assert_eq!(tokenizer.token_to_game_outcome(&Token { value: 2 }).unwrap(), Some(Outcome::Draw));
+        assert!(tokenizer.token_to_outcome(&token).is_err());
+        assert_eq!(tokenizer.elo_token(Color::White, Some(1500)), Some(Token { value: 1973 }));
+        assert_eq!(tokenizer.vocabulary()[1972], (String::from("<start:UNKNOWN>"), 1972));
+    }
+
+    #[test]
+    fn elo_tokens() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        assert_eq!(tokenizer.elo_token(Color::White, Some(1900)), None);
+        let fingerprint = tokenizer.fingerprint();
+
+        tokenizer.set_elo_buckets(&[1800, 2000]).unwrap();
+        assert_eq!(tokenizer.vocab_size(), 1972 + 8);
+        assert_ne!(tokenizer.fingerprint(), fingerprint);
+
+        let vocabulary = tokenizer.vocabulary();
+        assert_eq!(vocabulary.len(), tokenizer.vocab_size());
+        let names: Vec<&str> = vocabulary[1972..].iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["<elo_w:0-1800>", "<elo_w:1800-2000>", "<elo_w:2000+>", "<elo_w:?>", "<elo_b:0-1800>", "<elo_b:1800-2000>", "<elo_b:2000+>", "<elo_b:?>"]);
+
+        let token = tokenizer.elo_token(Color::Black, Some(1800)).unwrap();
+        assert_eq!(token, Token { value: 1977 });
+        assert!(tokenizer.is_elo_token(&token));
+        assert!(!tokenizer.is_elo_token(&Token { value: 1980 }));
+        assert_eq!(tokenizer.token_to_elo(&token).unwrap(), (Color::Black, Some(EloBucket { min: 1800, max: Some(2000) })));
+        assert_eq!(tokenizer.token_to_elo(&tokenizer.elo_token(Color::White, Some(2500)).unwrap()).unwrap(), (Color::White, Some(EloBucket { min: 2000, max: None })));
+        assert_eq!(tokenizer.token_to_elo(&tokenizer.elo_token(Color::White, None).unwrap()).unwrap(), (Color::White, None));
+        assert!(tokenizer.token_to_elo(&tokenizer.game_end_token()).is_err());
+        assert!(tokenizer.set_elo_buckets(&[2000, 1800]).is_err());
+    }
+
+    #[test]
+    fn game_tokens() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        tokenizer.set_elo_buckets(&[1800, 2000]).unwrap();
+        let start = GameVariant::Standard.start_position(None).unwrap();
+        let e2e4 = Uci::from_str("e2e4").unwrap().to_move(&start).unwrap();
+
+        let tokens = tokenizer.game_tokens(&start, &[e2e4], Some(Outcome::Draw), Some(1900), None).unwrap();
+        assert_eq!(tokens, [
+            tokenizer.game_start_token(Some(Outcome::Draw)).unwrap(),
+            tokenizer.elo_token(Color::White, Some(1900)).unwrap(),
+            tokenizer.elo_token(Color::Black, None).unwrap(),
+            tokenizer.uci_to_token(Uci::from_str("e2e4").unwrap()).unwrap(),
+        ]);
+        assert_eq!(tokenizer.game_prefix(&start, Some(Outcome::Draw), Some(1900), None).unwrap(), tokens[..3]);
+    }
+
+    #[test]
+    fn setup_tokens() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        let fen = "4k3/8/8/8/8/8/4P3/4K3 b - - 0 1";
+        let pos = GameVariant::Standard.start_position(Some(fen)).unwrap();
+        assert!(tokenizer.setup_tokens(&GameVariant::Standard.start_position(None).unwrap()).unwrap().is_empty());
+        assert!(tokenizer.setup_tokens(&pos).is_err());
+
+        tokenizer.set_elo_buckets(&[2000]).unwrap();
+        tokenizer.set_setup_tokens(true);
+        assert_eq!(tokenizer.vocab_size(), 1972 + 6 + 2 + FEN_CHARS.len());
+        let tokens = tokenizer.setup_tokens(&pos).unwrap();
+        assert_eq!(tokens.len(), 2 + "4k3/8/8/8/8/8/4P3/4K3 b - -".len());
+        assert!(tokens.iter().all(|token| tokenizer.is_setup_token(token)));
+        assert_eq!(tokenizer.vocabulary()[tokens[0].value as usize].0, "<setup>");
+
+        let mut game = tokens.clone();
+        game.push(tokenizer.game_end_token());
+        let (decoded, decoded_fen, n_tokens) = tokenizer.decode_setup(&game, GameVariant::Standard).unwrap().unwrap();
+        assert_eq!(decoded_fen, fen);
+        assert_eq!(decoded.turn(), Color::Black);
+        assert_eq!(n_tokens, tokens.len());
+        assert!(tokenizer.decode_setup(&game[1..], GameVariant::Standard).unwrap().is_none());
+        assert!(tokenizer.decode_setup(&tokens[..tokens.len() - 1], GameVariant::Standard).is_err());
+    }
+
+    #[test]
+    fn encode_move() {
+        for kind in SchemeKind::ALL {
+            let mut tokenizer = Tokenizer::with_scheme(kind.create());
+            tokenizer.create_token_map();
+            tokenizer.set_unknown_outcome_token(true);
+
+            let pos = GameVariant::Standard.start_position(None).unwrap();
+            let _move = Uci::from_str("e2e4").unwrap().to_move(&pos).unwrap();
+            let mut tokens = tokenizer.encode_move(&pos, &_move).unwrap();
+            assert!(tokens.iter().all(|token| tokenizer.is_move_token(token)));
+            let n_tokens = tokens.len();
+            tokens.push(tokenizer.game_end_token());
+            assert_eq!(tokenizer.decode_move(&pos, &tokens).unwrap(), (_move, n_tokens));
+            assert!(tokenizer.decode_move(&pos, &tokens[n_tokens..]).is_err());
+            assert!(!tokenizer.is_move_token(&tokenizer.game_start_token(None).unwrap()));
+        }
+    }
+
+    #[test]
+    fn variant_tokens() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        tokenizer.set_unknown_outcome_token(true);
+        tokenizer.set_setup_tokens(true);
+        let vocabulary = tokenizer.vocabulary();
+
+        tokenizer.set_variants(&[GameVariant::Crazyhouse, GameVariant::Standard, GameVariant::Chess960]);
+        assert_eq!(tokenizer.variants(), [GameVariant::Chess960, GameVariant::Crazyhouse]);
+        assert!(tokenizer.supports_variant(GameVariant::Standard));
+        assert!(!tokenizer.supports_variant(GameVariant::Atomic));
+        // Variant tokens are appended, the ids of the other tokens do not change
+        assert_eq!(tokenizer.vocabulary()[..vocabulary.len()], vocabulary);
+        assert_eq!(tokenizer.vocab_size(), vocabulary.len() + 2 * 4 + tokenizer.scheme().variant_vocabulary().len() + VARIANT_FEN_CHARS.len());
+        assert_eq!(tokenizer.vocabulary().len(), tokenizer.vocab_size());
+
+        let token = tokenizer.variant_start_token(GameVariant::Crazyhouse, Some(Outcome::Draw)).unwrap();
+        assert_eq!(tokenizer.vocabulary()[token.value as usize].0, "<start:crazyhouse:DRAW>");
+        assert!(tokenizer.is_game_start_token(&token));
+        assert_eq!(tokenizer.token_to_variant(&token).unwrap(), GameVariant::Crazyhouse);
+        assert_eq!(tokenizer.token_to_outcome(&token).unwrap(), Outcome::Draw);
+        let token = tokenizer.variant_start_token(GameVariant::Chess960, None).unwrap();
+        assert_eq!(tokenizer.token_to_game_outcome(&token).unwrap(), None);
+        assert_eq!(tokenizer.token_to_variant(&Token { value: 0 }).unwrap(), GameVariant::Standard);
+        assert_eq!(tokenizer.variant_start_token(GameVariant::Standard, Some(Outcome::Draw)).unwrap(), Token { value: 2 });
+        assert!(tokenizer.variant_start_token(GameVariant::Atomic, Some(Outcome::Draw)).is_err());
+
+        let fen = "4k3/8/8/8/8/8/8/4K3[Nq] w - - 0 1";
+        let pos = GameVariant::Crazyhouse.start_position(Some(fen)).unwrap();
+        let _move = Uci::from_str("N@f3").unwrap().to_move(&pos).unwrap();
+        let tokens = tokenizer.encode_move(&pos, &_move).unwrap();
+        assert_eq!(tokens, [tokenizer.uci_to_token(Uci::from_str("N@f3").unwrap()).unwrap()]);
+        assert!(tokenizer.is_move_token(&tokens[0]));
+        assert_eq!(tokenizer.token_to_uci(&tokens[0]).unwrap().to_string(), "N@f3");
+        assert_eq!(tokenizer.decode_move(&pos, &tokens).unwrap(), (_move.clone(), 1));
+
+        let setup = tokenizer.setup_tokens(&pos).unwrap();
+        assert!(setup.iter().all(|token| tokenizer.is_setup_token(token)));
+        let (decoded, decoded_fen, _) = tokenizer.decode_setup(&setup, GameVariant::Crazyhouse).unwrap().unwrap();
+        assert_eq!(decoded_fen, "4k3/8/8/8/8/8/8/4K3[Nq] w - - 0 1");
+        assert_eq!(GameVariant::of(&decoded), GameVariant::Crazyhouse);
+        assert!(tokenizer.setup_tokens(&GameVariant::Crazyhouse.start_position(None).unwrap()).unwrap().is_empty());
+
+        // Without crazyhouse there are no drop tokens
+        let mut chess960 = Tokenizer::new();
+        chess960.create_token_map();
+        chess960.set_variants(&[GameVariant::Chess960]);
+        assert_eq!(chess960.vocab_size(), 1972 + 3);
+        assert!(chess960.encode_move(&pos, &_move).is_err());
+    }
+
+    #[test]
+    fn legal_move_tokens() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        let pos = GameVariant::Standard.start_position(None).unwrap();
+        let tokens = tokenizer.legal_move_tokens(&pos);
+        assert_eq!(tokens.len(), 20 + 1);
+        assert_eq!(tokens[0], tokenizer.game_end_token());
+        assert!(tokens.contains(&tokenizer.uci_to_token(Uci::from_str("g1f3").unwrap()).unwrap()));
+        assert!(!tokens.contains(&tokenizer.uci_to_token(Uci::from_str("e2e5").unwrap()).unwrap()));
+
+        let mask = tokenizer.legal_move_mask(&pos, &[]);
+        assert_eq!(mask.len(), tokenizer.vocab_size());
+        assert_eq!(mask.iter().filter(|legal| **legal).count(), tokens.len());
+        let packed = tokenizer.pack_mask(&tokens);
+        assert_eq!(packed.len(), 1972 / 8 + 1);
+        assert_eq!(packed.iter().map(|byte| byte.count_ones()).sum::<u32>(), 21);
+        assert_eq!(packed[0], 1 << 3);
+
+        // Multi token moves, the <to:..> tokens of the pieces that can move from g1
+        let mut factored = Tokenizer::with_scheme(SchemeKind::Factored.create());
+        factored.create_token_map();
+        assert_eq!(factored.legal_move_tokens(&pos).len(), 10 + 1);
+        let g1 = factored.legal_move_tokens(&pos).into_iter().find(|token| factored.vocabulary()[token.value as usize].0 == "<from:g1>").unwrap();
+        let names: Vec<String> = factored.legal_next_tokens(&pos, &[g1]).iter().map(|token| factored.vocabulary()[token.value as usize].0.clone()).collect();
+        assert_eq!(names, ["<to:f3>", "<to:h3>"]);
+        assert!(factored.legal_next_tokens(&pos, &[g1, g1]).is_empty());
+    }
+
     #[test]
     fn save() {
         let mut tokenizer = Tokenizer::new();
         tokenizer.create_token_map();
         tokenizer.save("assets/encoder_test.json");
     }
+
+    #[test]
+    fn frozen_vocab() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+
+        let frozen = Tokenizer::load("assets/vocab.json").unwrap();
+        assert!(tokenizer.check_compatible(&frozen).is_ok(), "The generated vocabulary no longer matches assets/vocab.json");
+        assert_eq!(tokenizer.vocabulary(), frozen.vocabulary());
+        assert_eq!(tokenizer.fingerprint(), frozen.fingerprint());
+
+        let mut other = Tokenizer::new();
+        other.create_token_map();
+        let (e2e4, e7e5) = (other.move_ids["e2e4"], other.move_ids["e7e5"]);
+        other.move_ids.insert(String::from("e2e4"), e7e5);
+        other.move_ids.insert(String::from("e7e5"), e2e4);
+        assert!(tokenizer.check_compatible(&other).unwrap_err().to_string().starts_with("2 move tokens differ"));
+
+        let mut factored = Tokenizer::with_scheme(SchemeKind::Factored.create());
+        factored.create_token_map();
+        assert!(tokenizer.check_compatible(&factored).is_err());
+    }
+
+    #[test]
+    fn load() {
+        let dir = TempDir::new("tokenizer").unwrap();
+        let path = dir.path().join("vocab.json");
+        let path = path.to_str().unwrap();
+
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.create_token_map();
+        tokenizer.set_unknown_outcome_token(true);
+        tokenizer.set_elo_buckets(&[1600, 2000, 2400]).unwrap();
+        tokenizer.set_setup_tokens(true);
+        tokenizer.set_variants(&[GameVariant::Chess960, GameVariant::Antichess]);
+        tokenizer.save(path);
+
+        let loaded = Tokenizer::load(path).unwrap();
+        assert!(loaded.has_unknown_outcome_token());
+        assert_eq!(loaded.variants(), [GameVariant::Chess960, GameVariant::Antichess]);
+        assert_eq!(loaded.elo_buckets(), [1600, 2000, 2400]);
+        assert!(loaded.has_setup_tokens());
+        assert_eq!(loaded.vocabulary(), tokenizer.vocabulary());
+
+        let mut san = Tokenizer::with_scheme(SchemeKind::San.create());
+        san.create_token_map();
+        san.save(path);
+        let loaded = Tokenizer::load(path).unwrap();
+        assert_eq!(loaded.scheme().name(), "san");
+        assert_eq!(loaded.fingerprint(), san.fingerprint());
+
+        std::fs::write(path, r#"{"<start:WHITE>": 0, "<start:BLACK>": 1, "<start:DRAW>": 2, "<end>": 3, "e2e4": 5}"#).unwrap();
+        assert!(Tokenizer::load(path).is_err());
+        std::fs::write(path, r#"{"<start:WHITE>": 0, "<start:BLACK>": 1, "<start:DRAW>": 2, "<end>": 3, "e2e9": 4}"#).unwrap();
+        assert!(Tokenizer::load(path).is_err());
+    }
 }
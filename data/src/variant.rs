@@ -0,0 +1,210 @@
+use anyhow::{anyhow, Error};
+use std::str::FromStr;
+
+use shakmaty::{fen::Fen, uci::Uci, variant::{Variant, VariantPosition}, CastlingMode, Move, Position, Setup};
+
+/*
+
+Chess variants, selected by the Variant tag of a game. Tag values are matched
+case insensitively and without spaces / dashes, so both the lichess names
+("Three-check", "King of the Hill") and the short names below are accepted.
+A game without a Variant tag, or with "Standard" / "From Position", is
+standard chess.
+
+Chess960 is played with the standard rules, only castling differs: it is
+written as the king taking its own rook (e1h1) rather than the king move to its
+target square (e1g1), see Move::to_uci.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, clap::ValueEnum)]
+pub enum GameVariant {
+    Standard,
+    Chess960,
+    ThreeCheck,
+    Crazyhouse,
+    Atomic,
+    Antichess,
+    KingOfTheHill,
+    RacingKings,
+    Horde,
+}
+
+impl GameVariant {
+    pub const ALL: [GameVariant; 9] = [
+        GameVariant::Standard,
+        GameVariant::Chess960,
+        GameVariant::ThreeCheck,
+        GameVariant::Crazyhouse,
+        GameVariant::Atomic,
+        GameVariant::Antichess,
+        GameVariant::KingOfTheHill,
+        GameVariant::RacingKings,
+        GameVariant::Horde,
+    ];
+
+    /// The variant of a Variant tag value, None (no tag) is standard chess
+    pub fn from_tag(tag: Option<&str>) -> Result<GameVariant, Error> {
+        let tag = match tag {
+            Some(tag) => tag,
+            None => return Ok(GameVariant::Standard),
+        };
+        let name: String = tag.chars().filter(|c| c.is_alphanumeric()).collect::<String>().to_lowercase();
+        return match name.as_str() {
+            "" | "standard" | "chess" | "fromposition" => Ok(GameVariant::Standard),
+            "chess960" | "fischerandom" | "fischerrandom" => Ok(GameVariant::Chess960),
+            "threecheck" | "3check" => Ok(GameVariant::ThreeCheck),
+            "crazyhouse" => Ok(GameVariant::Crazyhouse),
+            "atomic" => Ok(GameVariant::Atomic),
+            "antichess" | "giveaway" => Ok(GameVariant::Antichess),
+            "kingofthehill" | "koth" => Ok(GameVariant::KingOfTheHill),
+            "racingkings" => Ok(GameVariant::RacingKings),
+            "horde" => Ok(GameVariant::Horde),
+            _ => Err(anyhow!("Unsupported variant {}", tag)),
+        };
+    }
+
+    /// Short name, used in the token names
+    pub fn name(&self) -> &'static str {
+        return match self {
+            GameVariant::Standard => "standard",
+            GameVariant::Chess960 => "chess960",
+            GameVariant::ThreeCheck => "three-check",
+            GameVariant::Crazyhouse => "crazyhouse",
+            GameVariant::Atomic => "atomic",
+            GameVariant::Antichess => "antichess",
+            GameVariant::KingOfTheHill => "king-of-the-hill",
+            GameVariant::RacingKings => "racing-kings",
+            GameVariant::Horde => "horde",
+        };
+    }
+
+    /// Value of the Variant tag, as written by lichess
+    pub fn tag(&self) -> &'static str {
+        return match self {
+            GameVariant::Standard => "Standard",
+            GameVariant::Chess960 => "Chess960",
+            GameVariant::ThreeCheck => "Three-check",
+            GameVariant::Crazyhouse => "Crazyhouse",
+            GameVariant::Atomic => "Atomic",
+            GameVariant::Antichess => "Antichess",
+            GameVariant::KingOfTheHill => "King of the Hill",
+            GameVariant::RacingKings => "Racing Kings",
+            GameVariant::Horde => "Horde",
+        };
+    }
+
+    pub fn castling_mode(&self) -> CastlingMode {
+        return match self {
+            GameVariant::Chess960 => CastlingMode::Chess960,
+            _ => CastlingMode::Standard,
+        };
+    }
+
+    /// Whether games of this variant can contain drops (crazyhouse) or king promotions (antichess)
+    pub fn has_variant_moves(&self) -> bool {
+        return matches!(self, GameVariant::Crazyhouse | GameVariant::Antichess);
+    }
+
+    fn shakmaty_variant(&self) -> Variant {
+        return match self {
+            GameVariant::Standard | GameVariant::Chess960 => Variant::Chess,
+            GameVariant::ThreeCheck => Variant::ThreeCheck,
+            GameVariant::Crazyhouse => Variant::Crazyhouse,
+            GameVariant::Atomic => Variant::Atomic,
+            GameVariant::Antichess => Variant::Antichess,
+            GameVariant::KingOfTheHill => Variant::KingOfTheHill,
+            GameVariant::RacingKings => Variant::RacingKings,
+            GameVariant::Horde => Variant::Horde,
+        };
+    }
+
+    /// The variant a position was set up for by `start_position`
+    pub fn of(pos: &VariantPosition) -> GameVariant {
+        return match pos.variant() {
+            Variant::Chess => match pos.castles().mode() {
+                CastlingMode::Chess960 => GameVariant::Chess960,
+                CastlingMode::Standard => GameVariant::Standard,
+            },
+            Variant::ThreeCheck => GameVariant::ThreeCheck,
+            Variant::Crazyhouse => GameVariant::Crazyhouse,
+            Variant::Atomic => GameVariant::Atomic,
+            Variant::Antichess => GameVariant::Antichess,
+            Variant::KingOfTheHill => GameVariant::KingOfTheHill,
+            Variant::RacingKings => GameVariant::RacingKings,
+            Variant::Horde => GameVariant::Horde,
+        };
+    }
+
+    /// The start position of a game, from its FEN tag or the default position of the variant
+    pub fn start_position(&self, fen: Option<&str>) -> Result<VariantPosition, Error> {
+        let setup = match fen {
+            Some(fen) => match Fen::from_ascii(fen.trim().as_bytes()) {
+                Ok(fen) => fen.into_setup(),
+                Err(e) => return Err(anyhow!("Invalid FEN: {}", e)),
+            },
+            None => match self {
+                // The default chess960 position is the standard one, but castling is still written as king takes rook
+                GameVariant::Chess960 => Setup::initial(),
+                _ => return Ok(VariantPosition::new(self.shakmaty_variant())),
+            },
+        };
+        return match VariantPosition::from_setup(self.shakmaty_variant(), setup, self.castling_mode()) {
+            Ok(pos) => Ok(pos),
+            Err(e) => Err(anyhow!("Unsupported position: {}", e)),
+        };
+    }
+}
+
+/// Plays the uci `moves` on `pos`, returning the moves played
+pub fn play_uci_moves<'a>(pos: &mut VariantPosition, moves: impl IntoIterator<Item = &'a str>) -> Result<Vec<Move>, Error> {
+    let mut played = Vec::new();
+    for uci in moves {
+        let _move = match Uci::from_str(uci) {
+            Ok(uci) => match uci.to_move(pos) {
+                Ok(_move) => _move,
+                Err(e) => return Err(anyhow!("Illegal move {}: {}", uci, e)),
+            },
+            Err(e) => return Err(anyhow!("Invalid uci move {}: {}", uci, e)),
+        };
+        pos.play_unchecked(&_move);
+        played.push(_move);
+    }
+    return Ok(played);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_tag() {
+        assert_eq!(GameVariant::from_tag(None).unwrap(), GameVariant::Standard);
+        assert_eq!(GameVariant::from_tag(Some("From Position")).unwrap(), GameVariant::Standard);
+        assert_eq!(GameVariant::from_tag(Some("Three-check")).unwrap(), GameVariant::ThreeCheck);
+        assert_eq!(GameVariant::from_tag(Some("King of the Hill")).unwrap(), GameVariant::KingOfTheHill);
+        assert!(GameVariant::from_tag(Some("Bughouse")).is_err());
+        for variant in GameVariant::ALL {
+            assert_eq!(GameVariant::from_tag(Some(variant.tag())).unwrap(), variant);
+            assert_eq!(GameVariant::from_tag(Some(variant.name())).unwrap(), variant);
+            assert_eq!(GameVariant::of(&variant.start_position(None).unwrap()), variant);
+        }
+    }
+
+    #[test]
+    fn chess960_castling() {
+        let pos = GameVariant::Chess960.start_position(Some("bqnbrkrn/pppppppp/8/8/8/8/PPPPPPPP/BQNBRKRN w KQkq - 0 1")).unwrap();
+        assert_eq!(GameVariant::of(&pos), GameVariant::Chess960);
+        assert!(GameVariant::Standard.start_position(Some("8/8/8/8/8/8/8/8 w - - 0 1")).is_err());
+
+        // The standard start position still castles king takes rook
+        let mut pos = GameVariant::Chess960.start_position(None).unwrap();
+        for uci in ["e2e4", "e7e5", "g1f3", "b8c6", "f1c4", "g8f6"] {
+            let _move = Uci::from_str(uci).unwrap().to_move(&pos).unwrap();
+            pos.play_unchecked(&_move);
+        }
+        let castle = Uci::from_str("e1h1").unwrap().to_move(&pos).unwrap();
+        assert!(castle.is_castle());
+        assert_eq!(castle.to_uci(pos.castles().mode()).to_string(), "e1h1");
+    }
+}
@@ -0,0 +1,75 @@
+#![cfg(unix)]
+#![allow(clippy::needless_return)]
+
+use std::{fs, io::Write, path::Path, process::{Command, Stdio}, thread, time::{Duration, Instant}};
+
+use shakmaty::{san::San, Chess, Position};
+use tempdir::TempDir;
+
+// Every pair of first moves as a game of its own, 400 games
+fn games() -> Vec<u8> {
+    let mut pgn = String::new();
+    let pos = Chess::default();
+    for white in pos.legal_moves() {
+        let after = pos.clone().play(&white).unwrap();
+        for black in after.legal_moves() {
+            pgn.push_str(&format!("[Event \"Resume\"]\n[Result \"1-0\"]\n\n1. {} {} 1-0\n\n", San::from_move(&pos, &white), San::from_move(&after, &black)));
+        }
+    }
+    return pgn.into_bytes();
+}
+
+fn build(dir: &Path, source: &Path, resume: bool) -> Command {
+    fs::write(dir.join("input.json"), format!("[\"{}\"]", source.display())).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_data"));
+    command
+        .arg("--input").arg(dir.join("input.json"))
+        .arg("--output").arg(dir.join("out.games"))
+        .arg("--encoder-output").arg(dir.join("encoder.json"))
+        .arg("--index-output").arg(dir.join("out.index"))
+        .args(["--n-workers", "1", "--chunk-size", "512"])
+        .stdout(Stdio::null());
+    if resume {
+        command.arg("--resume");
+    }
+    return command;
+}
+
+#[test]
+fn resume_interrupted_first_source() {
+    let pgn = games();
+    let dir = TempDir::new("resume").unwrap();
+    let source = dir.path().join("source.pgn");
+
+    // The build reads its only source from a fifo and stalls once half of it is written
+    assert!(Command::new("mkfifo").arg(&source).status().unwrap().success());
+    let mut child = build(dir.path(), &source, false).spawn().unwrap();
+    let mut fifo = fs::OpenOptions::new().write(true).open(&source).unwrap();
+    fifo.write_all(&pgn[..pgn.len() / 2]).unwrap();
+
+    let started = Instant::now();
+    while fs::metadata(dir.path().join("out.games")).map_or(0, |metadata| metadata.len()) <= 32 {
+        assert!(started.elapsed() < Duration::from_secs(60), "No output was written");
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    drop(fifo);
+
+    let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.path().join("out.games.manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["sources"].as_array().unwrap().len(), 0);
+
+    fs::remove_file(&source).unwrap();
+    fs::write(&source, &pgn).unwrap();
+    assert!(build(dir.path(), &source, true).status().unwrap().success());
+
+    let clean = TempDir::new("clean").unwrap();
+    let clean_source = clean.path().join("source.pgn");
+    fs::write(&clean_source, &pgn).unwrap();
+    assert!(build(clean.path(), &clean_source, false).status().unwrap().success());
+
+    for name in ["out.games", "out.index"] {
+        let resumed = fs::read(dir.path().join(name)).unwrap();
+        assert_eq!(resumed, fs::read(clean.path().join(name)).unwrap(), "{} differs", name);
+    }
+}
@@ -0,0 +1,18 @@
+import sys
+import torch
+from safetensors.torch import save_file
+
+# Exports a model checkpoint (a state dict, or a training checkpoint from train.py)
+# as safetensors for the Rust inference in data/src/model.rs
+# usage: python export.py models/model.pth models/model.safetensors
+
+if len(sys.argv) != 3:
+    print("usage: python export.py <checkpoint.pth> <output.safetensors>")
+    sys.exit(1)
+
+checkpoint = torch.load(sys.argv[1], map_location=torch.device('cpu'))
+state_dict = checkpoint.get("model_state_dict", checkpoint)
+# The causal masks are rebuilt from the block size
+tensors = {name: tensor.float().contiguous() for name, tensor in state_dict.items() if not name.endswith(".tril")}
+save_file(tensors, sys.argv[2])
+print(f"Exported {len(tensors)} tensors to {sys.argv[2]}")
@@ -0,0 +1,85 @@
+import ctypes
+import os
+
+# ctypes bindings of the constrained move sampler in data/src/sampler.rs
+# Build the library with `cargo build --release` in data/
+
+DEFAULT_LIB_PATH = os.path.join(os.path.dirname(os.path.abspath(__file__)), "data", "target", "release", "libdata.so")
+
+
+class MoveSampler:
+    def __init__(self, vocab_path, seed=0, temperature=1.0, top_k=0, top_p=1.0, lib_path=DEFAULT_LIB_PATH):
+        self.lib = ctypes.CDLL(lib_path)
+        self.lib.chess_sampler_new.restype = ctypes.c_void_p
+        self.lib.chess_sampler_new.argtypes = [ctypes.c_char_p, ctypes.c_uint64, ctypes.c_float, ctypes.c_size_t, ctypes.c_float]
+        self.lib.chess_sampler_free.argtypes = [ctypes.c_void_p]
+        self.lib.chess_sampler_vocab_size.restype = ctypes.c_size_t
+        self.lib.chess_sampler_vocab_size.argtypes = [ctypes.c_void_p]
+        self.lib.chess_sampler_last_error.restype = ctypes.c_char_p
+        self.lib.chess_sampler_last_error.argtypes = [ctypes.c_void_p]
+        self.lib.chess_sampler_sample_move.restype = ctypes.c_int32
+        self.lib.chess_sampler_sample_move.argtypes = [
+            ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p, ctypes.c_char_p,
+            ctypes.POINTER(ctypes.c_float), ctypes.c_size_t,
+            ctypes.c_char_p, ctypes.c_size_t, ctypes.POINTER(ctypes.c_float),
+        ]
+        self.lib.chess_sampler_encode.restype = ctypes.c_int32
+        self.lib.chess_sampler_encode.argtypes = [
+            ctypes.c_void_p, ctypes.c_char_p, ctypes.c_char_p, ctypes.c_char_p, ctypes.c_char_p,
+            ctypes.c_uint32, ctypes.c_uint32, ctypes.POINTER(ctypes.c_uint16), ctypes.c_size_t,
+        ]
+
+        self.sampler = self.lib.chess_sampler_new(vocab_path.encode(), seed, temperature, top_k, top_p)
+        if not self.sampler:
+            error = self.lib.chess_sampler_last_error(None).decode()
+            raise ValueError(f"Unable to create a sampler from {vocab_path}: {error}")
+        self.n_vocab = self.lib.chess_sampler_vocab_size(self.sampler)
+
+    def encode(self, moves=(), result="*", fen=None, variant=None, white_elo=None, black_elo=None):
+        """Token ids of the game of uci `moves` played from `fen` (default start position), starting with the
+        start token claiming `result` ("1-0", "0-1", "1/2-1/2" or "*") and the Elo tokens of the players
+        (None is an unknown rating), in the vocabulary `sample` uses"""
+        args = (
+            self.sampler,
+            variant.encode() if variant else None,
+            fen.encode() if fen else None,
+            " ".join(moves).encode(),
+            result.encode() if result else None,
+            white_elo or 0,
+            black_elo or 0,
+        )
+        tokens = (ctypes.c_uint16 * (2 * len(moves) + 128))()
+        n_tokens = self.lib.chess_sampler_encode(*args, tokens, len(tokens))
+        if n_tokens > len(tokens):
+            tokens = (ctypes.c_uint16 * n_tokens)()
+            n_tokens = self.lib.chess_sampler_encode(*args, tokens, len(tokens))
+        if n_tokens < 0:
+            raise ValueError(self.lib.chess_sampler_last_error(self.sampler).decode())
+        return list(tokens[:n_tokens])
+
+    def sample(self, logits, moves=(), fen=None, variant=None):
+        """Samples a legal move after the uci `moves` played from `fen` (default start position).
+        Returns (uci, probability, token)"""
+        if hasattr(logits, "tolist"):
+            logits = logits.tolist()
+        if len(logits) != self.n_vocab:
+            raise ValueError(f"Expected {self.n_vocab} logits, got {len(logits)}")
+
+        logits = (ctypes.c_float * len(logits))(*logits)
+        uci = ctypes.create_string_buffer(16)
+        probability = ctypes.c_float()
+        token = self.lib.chess_sampler_sample_move(
+            self.sampler,
+            variant.encode() if variant else None,
+            fen.encode() if fen else None,
+            " ".join(moves).encode(),
+            logits, len(logits), uci, len(uci), ctypes.byref(probability),
+        )
+        if token < 0:
+            raise ValueError(self.lib.chess_sampler_last_error(self.sampler).decode())
+        return uci.value.decode(), probability.value, token
+
+    def __del__(self):
+        if getattr(self, "sampler", None):
+            self.lib.chess_sampler_free(self.sampler)
+            self.sampler = None
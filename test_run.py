@@ -1,10 +1,11 @@
 import torch
 from model import Transformer
-from data_utils import Encoding
+from sampler import MoveSampler
 
-enc = Encoding()
+# Only legal moves are sampled, see data/src/sampler.rs. The moves are encoded with the same vocabulary
+sampler = MoveSampler("data/assets/vocab.json", temperature=1.0, top_k=0, top_p=1.0)
 
-n_vocab = enc.n_vocab
+n_vocab = sampler.n_vocab
 
 # with open("models/model.config", "r") as f:
 #     args = json.load(f)
@@ -31,12 +32,22 @@ model.to(device)
 model.load_state_dict(torch.load("models/model-1705985287.1557353-1500.pth", map_location=torch.device('cpu')))
 model.eval()
 
-context = []
+# The user plays white, the model plays black and is asked for a win
+result = "0-1"
+
+moves = []
 while True:
     try:
-        context += [enc.encode(input("> "))]
-        x = model.generate(torch.tensor([context], dtype=torch.long, device=device), max_new_tokens=1)[0].tolist()
-        context += x
-        print([enc.decode(tok) for tok in x])
+        move = input("> ")
+        try:
+            context = sampler.encode(moves + [move], result)
+        except ValueError as e:
+            print(e)
+            continue
+        moves += [move]
+        logits, _ = model(torch.tensor([context[-block_size:]], dtype=torch.long, device=device))
+        uci, probability, token = sampler.sample(logits[0, -1, :], moves)
+        moves += [uci]
+        print(uci, f"{probability:.3f}")
     except KeyboardInterrupt as e:
         break
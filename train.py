@@ -1,4 +1,5 @@
 import os
+import json
 import time
 import torch
 import wandb
@@ -13,7 +14,8 @@ parser = argparse.ArgumentParser(
                     prog='Chess Transformer Trainer',
                     description='Train the transformer on a dataset')
 
-parser.add_argument('-i', '--input', help='Input data .npy', default='data.npy')
+parser.add_argument('-i', '--input', help='Input data .npy, or the .manifest.json of a sharded export', default='data.npy')
+parser.add_argument('--val_input', help='Validation data (.npy or .manifest.json) of a split build. The input is split 90/10 by position if not provided', default=None)
 parser.add_argument('-o', '--output', help='Output directory', required=True)
 parser.add_argument('--block_size', help='Block (context) size', type=int)
 parser.add_argument('--batch_size', help='Batch size', type=int, required=True)
@@ -88,20 +90,40 @@ wandb.init(
 if "checkpoint" in args and args.checkpoint is not None:
     wandb.restore(args.checkpoint)
 
-with open(args.input, 'rb') as f:
-    tokens = np.load(f)
-
-data = torch.from_numpy(tokens)
-data = data.type(torch.LongTensor)
-n = int(0.9*data.shape[0])
-train_data = data[:n]
-val_data = data[n:]
+def load_tokens(path):
+    """Memory mapped token arrays, one per shard of a sharded export"""
+    if path.endswith('.json'):
+        with open(path, 'r') as f:
+            manifest = json.load(f)
+        shards_dir = os.path.dirname(path)
+        return [np.load(os.path.join(shards_dir, shard['tokens']), mmap_mode='r') for shard in manifest['shards']]
+    return [np.load(path, mmap_mode='r')]
+
+def split_tokens(shards, fraction):
+    """The first `fraction` of the tokens of `shards` and the rest, as views of the memory maps"""
+    n = int(fraction*sum(len(shard) for shard in shards))
+    head, tail = [], []
+    for shard in shards:
+        take = min(len(shard), n)
+        head.append(shard[:take])
+        tail.append(shard[take:])
+        n -= take
+    return head, tail
+
+train_data = load_tokens(args.input)
+if args.val_input is not None:
+    val_data = load_tokens(args.val_input)
+else:
+    train_data, val_data = split_tokens(train_data, 0.9)
 
 def get_batch(split):
-    data = train_data if split == "train" else val_data
-    ix = torch.randint(len(data) - block_size, (batch_size,))
-    x = torch.stack([data[i:i+block_size] for i in ix])
-    y = torch.stack([data[i+1:i+block_size+1] for i in ix])
+    shards = train_data if split == "train" else val_data
+    # Blocks never cross shards, only the sampled blocks are read and cast to int64
+    starts = torch.tensor([max(len(shard) - block_size, 0) for shard in shards], dtype=torch.float)
+    shard_ix = torch.multinomial(starts, batch_size, replacement=True)
+    blocks = [(shards[s], torch.randint(len(shards[s]) - block_size, (1,)).item()) for s in shard_ix.tolist()]
+    x = torch.stack([torch.from_numpy(shard[i:i+block_size].astype(np.int64)) for shard, i in blocks])
+    y = torch.stack([torch.from_numpy(shard[i+1:i+block_size+1].astype(np.int64)) for shard, i in blocks])
     x, y = x.to(device), y.to(device)
     return x, y
 